@@ -12,6 +12,23 @@ pub struct AppArguments {
     /// Application config file path
     #[structopt(short, long, parse(from_os_str), env = "UPLOADER_CONFIG_FILE")]
     pub config: PathBuf,
+
+    /// Without command the server is started
+    #[structopt(subcommand)]
+    pub command: Option<AppCommand>,
+}
+
+/// Additional commands
+#[derive(StructOpt, Debug)]
+pub enum AppCommand {
+    /// Validate config and service account private keys
+    CheckConfig {
+        /// Also receive tokens and check bucket write permissions
+        #[structopt(long)]
+        online: bool,
+    },
+    /// Print effective config with secrets redacted
+    PrintConfig,
 }
 
 impl AppArguments {
@@ -1,63 +1,746 @@
-use serde::Deserialize;
-use std::{fs::File, io::BufReader, path::PathBuf};
+use crate::{config_interpolation, message_template::check_template};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+};
+
+/// Метка основного токена проекта api_token
+pub const DEFAULT_TOKEN_LABEL: &str = "default";
 
 /// Настройки для всего приложения
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct SettingsConfig {
     pub port: u16,
+    /// Адреса проксей (nginx и тд), которым доверяем заголовки X-Forwarded-For / X-Real-IP
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Каталог для сохранения состояния между перезапусками (счетчики квот и тд)
+    pub state_dir: Option<PathBuf>,
+    /// API администрирования проектов на отдельном порту
+    pub admin: Option<AdminConfig>,
+    /// Очередь выгрузок на диске на случай недоступности Google
+    pub spool: Option<SpoolConfig>,
+    /// Очередь уведомлений с повторами
+    #[serde(default)]
+    pub notification_outbox: NotificationOutboxConfig,
+    /// Внешний адрес прокси для ссылок на QR коды и страницы выгрузок
+    pub public_url: Option<String>,
+    /// Страницы выгрузок мобильных сборок
+    pub landing_pages: Option<LandingPagesConfig>,
+}
+
+fn default_spool_retry_delay_secs() -> u64 {
+    10
+}
+
+fn default_spool_max_retry_delay_secs() -> u64 {
+    600
+}
+
+fn default_spool_keep_finished_hours() -> u64 {
+    24
+}
+
+/// Настройки очереди выгрузок
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct SpoolConfig {
+    /// Каталог для данных и состояния задач
+    pub dir: PathBuf,
+    /// Задержка перед первым повтором, дальше удваивается
+    #[serde(default = "default_spool_retry_delay_secs")]
+    pub retry_delay_secs: u64,
+    /// Максимальная задержка между повторами
+    #[serde(default = "default_spool_max_retry_delay_secs")]
+    pub max_retry_delay_secs: u64,
+    /// Ограничение на количество попыток, по умолчанию повторяем пока не получится
+    pub max_attempts: Option<u32>,
+    /// Сколько хранить информацию о завершенных задачах
+    #[serde(default = "default_spool_keep_finished_hours")]
+    pub keep_finished_hours: u64,
+}
+
+fn default_outbox_max_attempts() -> u32 {
+    10
+}
+
+/// Настройки очереди уведомлений
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct NotificationOutboxConfig {
+    /// Каталог для неотправленных уведомлений, по умолчанию notification_outbox в state_dir.
+    /// Без обоих каталогов очередь живет только в памяти.
+    pub dir: Option<PathBuf>,
+    /// Задержка перед первым повтором, дальше удваивается
+    #[serde(default = "default_spool_retry_delay_secs")]
+    pub retry_delay_secs: u64,
+    /// Максимальная задержка между повторами
+    #[serde(default = "default_spool_max_retry_delay_secs")]
+    pub max_retry_delay_secs: u64,
+    /// Количество попыток, включая первую при ответе на запрос
+    #[serde(default = "default_outbox_max_attempts")]
+    pub max_attempts: u32,
+    /// Сколько хранить информацию о завершенных уведомлениях
+    #[serde(default = "default_spool_keep_finished_hours")]
+    pub keep_finished_hours: u64,
+}
+
+impl Default for NotificationOutboxConfig {
+    fn default() -> Self {
+        NotificationOutboxConfig {
+            dir: None,
+            retry_delay_secs: default_spool_retry_delay_secs(),
+            max_retry_delay_secs: default_spool_max_retry_delay_secs(),
+            max_attempts: default_outbox_max_attempts(),
+            keep_finished_hours: default_spool_keep_finished_hours(),
+        }
+    }
+}
+
+fn default_landing_pages_keep_days() -> u64 {
+    30
+}
+
+/// Настройки страниц выгрузок
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct LandingPagesConfig {
+    /// Каталог для описаний выгрузок
+    pub dir: PathBuf,
+    /// Сколько дней хранить страницы, потом ссылки на них перестают работать
+    #[serde(default = "default_landing_pages_keep_days")]
+    pub keep_days: u64,
+}
+
+/// Настройки API администрирования
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct AdminConfig {
+    pub port: u16,
+    /// Токен в заголовке X-Admin-Token
+    pub token: String,
+    /// Сохранять ли изменения обратно в файл конфига
+    #[serde(default)]
+    pub persist_changes: bool,
 }
 
 /// Настройки для проекта и выгрузки в CloudStorage
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct GoogleStorageConfig {
-    pub credentials_file: PathBuf,
+    /// Краткая запись для credentials с типом file
+    pub credentials_file: Option<PathBuf>,
+    /// Источник токенов, если задан, то credentials_file не указывается
+    pub credentials: Option<GoogleCredentialsConfig>,
+    /// Выгружаем от имени этого сервисного аккаунта через IAM generateAccessToken
+    pub impersonate_service_account: Option<String>,
+    /// Использовать подписанный ключом JWT как токен без похода на token_uri
+    #[serde(default)]
+    pub self_signed_jwt: bool,
     pub bucket_name: String,
 }
 
+/// Откуда берем токены для Google API
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GoogleCredentialsConfig {
+    /// JSON файл сервисного аккаунта или пользователя (authorized_user)
+    File { path: PathBuf },
+    /// Метадата сервер GCE/GKE, в том числе workload identity
+    MetadataServer {
+        /// По умолчанию GCE_METADATA_HOST или http://169.254.169.254
+        endpoint: Option<String>,
+        /// По умолчанию default
+        service_account: Option<String>,
+    },
+    /// Поиск как у библиотек Google: GOOGLE_APPLICATION_CREDENTIALS, файл gcloud, метадата сервер
+    ApplicationDefault,
+}
+
+impl GoogleStorageConfig {
+    /// Итоговый источник токенов с учетом краткой записи
+    pub fn resolved_credentials(&self) -> Result<GoogleCredentialsConfig, eyre::Error> {
+        match (&self.credentials_file, &self.credentials) {
+            (Some(path), None) => Ok(GoogleCredentialsConfig::File { path: path.clone() }),
+            (None, Some(credentials)) => Ok(credentials.clone()),
+            (Some(_), Some(_)) => Err(eyre::eyre!("credentials_file and credentials can't be used together")),
+            (None, None) => Err(eyre::eyre!("credentials_file or credentials is required")),
+        }
+    }
+}
+
 /// Настройки для проекта и выгрузки в Slack
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct SlackConfig {
     pub token: String,
     pub targets: Vec<String>,
     pub qr_code: bool,
     pub default_text_before: Option<String>,
+    /// Каналы, которые можно указать в slack_channels запроса
+    #[serde(default)]
+    pub allowed_channels: Vec<String>,
+    /// Пользователи для личных сообщений из slack_users запроса: id, email или *@domain
+    #[serde(default)]
+    pub allowed_users: Vec<String>,
+    /// Сообщение из блоков Block Kit с кнопкой скачивания и контекстом вместо простого текста
+    #[serde(default)]
+    pub block_kit: bool,
+    /// Файлы меньше этого размера выкладываются в тред рядом со ссылкой.
+    /// Файл должен уместиться в буфер памяти из retry.memory_buffer_bytes.
+    pub upload_files_below_bytes: Option<u64>,
+}
+
+fn default_telegram_base_url() -> String {
+    "https://api.telegram.org".to_owned()
+}
+
+/// Отправка ссылки ботом Telegram
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct TelegramConfig {
+    pub bot_token: String,
+    pub chat_ids: Vec<String>,
+    /// Отправлять ссылку вместе с картинкой QR кода
+    #[serde(default)]
+    pub qr_code: bool,
+    pub default_text_before: Option<String>,
+    /// Адрес Bot API, можно заменить на локальный сервер
+    #[serde(default = "default_telegram_base_url")]
+    pub base_url: String,
+}
+
+/// Отправка ссылки через входящие вебхуки Discord или Microsoft Teams.
+/// Адрес вебхука задается целиком, поэтому его можно направить на локальный сервер.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct IncomingWebhookConfig {
+    pub webhook_urls: Vec<String>,
+    pub default_text_before: Option<String>,
+}
+
+/// Шифрование соединения с SMTP сервером
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpTls {
+    /// Без шифрования, только для локальных серверов
+    None,
+    /// Обычное соединение с переходом на TLS командой STARTTLS, порт 587
+    #[default]
+    Starttls,
+    /// TLS сразу при подключении, порт 465
+    Implicit,
+}
+
+fn default_email_subject() -> String {
+    "File uploaded: {{ file_name }}".to_owned()
+}
+
+fn default_email_body() -> String {
+    "{{ text }}\n\nProject: {{ project }}\nFile: {{ file_name }}\nSize: {{ size }}\nRequest: {{ request_id }}".to_owned()
+}
+
+/// Отправка ссылки письмом через SMTP сервер.
+/// Тема и текст - шаблоны MiniJinja, в {{ text }} текст сообщения по шаблону проекта.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct EmailConfig {
+    pub smtp_host: String,
+    /// По умолчанию зависит от режима шифрования
+    pub smtp_port: Option<u16>,
+    #[serde(default)]
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    /// Разрешить замену получателей параметром email_to запроса
+    #[serde(default)]
+    pub allow_recipient_override: bool,
+    /// Картинка QR кода внутри письма
+    #[serde(default)]
+    pub qr_code: bool,
+    #[serde(default = "default_email_subject")]
+    pub subject: String,
+    #[serde(default = "default_email_body")]
+    pub body: String,
+}
+
+/// Дополнительные получатели ссылки на выгрузку
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum NotifierConfig {
+    Slack(SlackConfig),
+    Telegram(TelegramConfig),
+    Discord(IncomingWebhookConfig),
+    Teams(IncomingWebhookConfig),
+    Email(EmailConfig),
+}
+
+impl NotifierConfig {
+    /// Имя типа для логов и ошибок
+    pub fn kind(&self) -> &'static str {
+        match self {
+            NotifierConfig::Slack(_) => "slack",
+            NotifierConfig::Telegram(_) => "telegram",
+            NotifierConfig::Discord(_) => "discord",
+            NotifierConfig::Teams(_) => "teams",
+            NotifierConfig::Email(_) => "email",
+        }
+    }
+
+    fn validate(&self) -> Result<(), eyre::Error> {
+        use eyre::{ensure, WrapErr};
+
+        match self {
+            NotifierConfig::Slack(slack) => {
+                ensure!(!slack.token.is_empty(), "token: empty slack token");
+                ensure!(!slack.targets.is_empty(), "targets: empty slack targets");
+            }
+            NotifierConfig::Telegram(telegram) => {
+                ensure!(!telegram.bot_token.is_empty(), "bot_token: empty bot token");
+                ensure!(!telegram.chat_ids.is_empty(), "chat_ids: empty chat list");
+                validate_webhook_url(&telegram.base_url).wrap_err("base_url")?;
+            }
+            NotifierConfig::Discord(webhooks) | NotifierConfig::Teams(webhooks) => {
+                ensure!(!webhooks.webhook_urls.is_empty(), "webhook_urls: empty webhook list");
+                for (index, url) in webhooks.webhook_urls.iter().enumerate() {
+                    validate_webhook_url(url).wrap_err_with(|| format!("webhook_urls[{}]", index))?;
+                }
+            }
+            NotifierConfig::Email(email) => {
+                ensure!(!email.smtp_host.is_empty(), "smtp_host: empty smtp host");
+                ensure!(!email.to.is_empty(), "to: empty recipients list");
+                ensure!(
+                    email.username.is_some() == email.password.is_some(),
+                    "username: username and password must be set together"
+                );
+                check_template(&email.subject).wrap_err("subject")?;
+                check_template(&email.body).wrap_err("body")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Убираем токены и адреса вебхуков, в которых тоже есть секрет
+    fn redact(&mut self, redacted: &str) {
+        match self {
+            NotifierConfig::Slack(slack) => slack.token = redacted.to_owned(),
+            NotifierConfig::Telegram(telegram) => telegram.bot_token = redacted.to_owned(),
+            NotifierConfig::Discord(webhooks) | NotifierConfig::Teams(webhooks) => {
+                webhooks.webhook_urls.iter_mut().for_each(|url| *url = redacted.to_owned())
+            }
+            NotifierConfig::Email(email) => {
+                if let Some(password) = email.password.as_mut() {
+                    *password = redacted.to_owned();
+                }
+            }
+        }
+    }
+}
+
+/// Ограничения частоты и количества одновременных выгрузок
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct LimitsConfig {
+    /// Допустимое количество запросов в секунду
+    pub requests_per_second: Option<f64>,
+    /// Сколько запросов можно сделать разом сверх частоты, по-умолчанию равно частоте
+    pub burst: Option<u32>,
+    /// Максимум одновременных выгрузок
+    pub max_concurrent_uploads: Option<usize>,
+}
+
+/// Квоты на объем выгружаемых данных
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct QuotaConfig {
+    /// Байт за скользящие сутки
+    pub bytes_per_day: Option<u64>,
+    /// Байт за скользящие 30 дней
+    pub bytes_per_month: Option<u64>,
+    /// Общее количество объектов
+    pub max_objects: Option<u64>,
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    500
+}
+
+fn default_retry_max_delay_ms() -> u64 {
+    30_000
+}
+
+fn default_retry_jitter() -> f64 {
+    0.5
+}
+
+fn default_retry_memory_buffer_bytes() -> u64 {
+    8 * 1024 * 1024
+}
+
+/// Повторы выгрузки при временных ошибках Google: 429, 5xx, обрывы соединения
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct RetryConfig {
+    /// Общее количество попыток вместе с первой
+    pub max_attempts: u32,
+    /// Задержка перед первым повтором, дальше удваивается
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub base_delay_ms: u64,
+    /// Максимальная задержка между попытками
+    #[serde(default = "default_retry_max_delay_ms")]
+    pub max_delay_ms: u64,
+    /// Доля случайного разброса задержки от 0 до 1
+    #[serde(default = "default_retry_jitter")]
+    pub jitter: f64,
+    /// Общее время на все попытки
+    pub deadline_secs: Option<u64>,
+    /// Тела до этого размера держим в памяти для повторов, большие пишем во временный файл
+    #[serde(default = "default_retry_memory_buffer_bytes")]
+    pub memory_buffer_bytes: u64,
+    /// Каталог временных файлов, по умолчанию системный
+    pub spool_dir: Option<PathBuf>,
+}
+
+impl RetryConfig {
+    fn validate(&self) -> Result<(), eyre::Error> {
+        use eyre::ensure;
+
+        ensure!(self.max_attempts > 0, "max attempts must be positive");
+        ensure!(
+            self.jitter.is_finite() && (0.0..=1.0).contains(&self.jitter),
+            "jitter must be in range 0..1"
+        );
+        ensure!(self.base_delay_ms <= self.max_delay_ms, "base delay must not exceed max delay");
+        if let Some(deadline) = self.deadline_secs {
+            ensure!(deadline > 0, "deadline must be positive");
+        }
+
+        Ok(())
+    }
+}
+
+fn default_webhook_timeout_secs() -> u64 {
+    10
+}
+
+/// Уведомления внешних систем о завершенных выгрузках.
+/// Доставка и повторы идут через settings.notification_outbox.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct WebhooksConfig {
+    /// Адреса, которые получают уведомление о каждой выгрузке
+    #[serde(default)]
+    pub urls: Vec<String>,
+    /// Ключ подписи тела запроса HMAC-SHA256
+    pub secret: String,
+    /// Разрешить дополнительный адрес из параметра callback_url запроса
+    #[serde(default)]
+    pub allow_callback_url: bool,
+    /// Ограничение времени на одну попытку
+    #[serde(default = "default_webhook_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Файл для уведомлений, не доставленных за все попытки очереди уведомлений, по строке JSON на уведомление
+    pub dead_letter_file: Option<PathBuf>,
+}
+
+/// Проверка адреса уведомления
+pub fn validate_webhook_url(url: &str) -> Result<(), eyre::Error> {
+    let uri: hyper::Uri = url.parse()?;
+    eyre::ensure!(
+        matches!(uri.scheme_str(), Some("http") | Some("https")) && uri.host().is_some(),
+        "absolute http(s) url expected"
+    );
+    Ok(())
+}
+
+impl WebhooksConfig {
+    fn validate(&self) -> Result<(), eyre::Error> {
+        use eyre::{ensure, WrapErr};
+
+        ensure!(!self.secret.is_empty(), "secret: empty webhook secret");
+        ensure!(self.timeout_secs > 0, "timeout_secs: timeout must be positive");
+        for (index, url) in self.urls.iter().enumerate() {
+            validate_webhook_url(url).wrap_err_with(|| format!("urls[{}]", index))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn default_qr_module_size() -> u32 {
+    8
+}
+
+fn default_qr_quiet_zone() -> u32 {
+    4
+}
+
+fn default_qr_dark_color() -> String {
+    "#000000".to_owned()
+}
+
+fn default_qr_light_color() -> String {
+    "#ffffff".to_owned()
+}
+
+/// Уровень коррекции ошибок QR кода
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum QrErrorCorrection {
+    /// Восстанавливается 7% данных
+    L,
+    /// 15%
+    #[default]
+    M,
+    /// 25%
+    Q,
+    /// 30%, нужен для логотипа поверх кода
+    H,
+}
+
+/// Формат картинки QR кода
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum QrFormat {
+    #[default]
+    Png,
+    /// Только для Slack, в Telegram и почту всегда уходит PNG
+    Svg,
+}
+
+/// Внешний вид QR кода
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct QrConfig {
+    /// Размер модуля в пикселях
+    #[serde(default = "default_qr_module_size")]
+    pub module_size: u32,
+    /// Отступ вокруг кода в модулях
+    #[serde(default = "default_qr_quiet_zone")]
+    pub quiet_zone: u32,
+    #[serde(default)]
+    pub error_correction: QrErrorCorrection,
+    #[serde(default)]
+    pub format: QrFormat,
+    /// Цвет модулей в виде #rrggbb
+    #[serde(default = "default_qr_dark_color")]
+    pub dark_color: String,
+    /// Цвет фона в виде #rrggbb
+    #[serde(default = "default_qr_light_color")]
+    pub light_color: String,
+    /// PNG картинка в центре кода
+    pub logo: Option<PathBuf>,
+    /// Подпись с именем файла под кодом
+    #[serde(default)]
+    pub caption: bool,
+    /// Шрифт TTF/OTF для подписи в PNG.
+    /// Для SVG не нужен, но без него PNG для Telegram и почты будут без подписи.
+    pub caption_font: Option<PathBuf>,
+}
+
+impl Default for QrConfig {
+    fn default() -> Self {
+        QrConfig {
+            module_size: default_qr_module_size(),
+            quiet_zone: default_qr_quiet_zone(),
+            error_correction: Default::default(),
+            format: Default::default(),
+            dark_color: default_qr_dark_color(),
+            light_color: default_qr_light_color(),
+            logo: None,
+            caption: false,
+            caption_font: None,
+        }
+    }
+}
+
+/// Цвет в виде #rrggbb
+pub fn parse_color(text: &str) -> Result<[u8; 3], eyre::Error> {
+    let hex = text
+        .strip_prefix('#')
+        // Срезы по байтам ниже допустимы только для ASCII
+        .filter(|hex| hex.len() == 6 && hex.bytes().all(|v| v.is_ascii_hexdigit()))
+        .ok_or_else(|| eyre::eyre!("color {} is not in #rrggbb format", text))?;
+    let mut color = [0; 3];
+    for (index, value) in color.iter_mut().enumerate() {
+        *value = u8::from_str_radix(&hex[index * 2..index * 2 + 2], 16).expect("Hex digits checked above");
+    }
+    Ok(color)
+}
+
+impl QrConfig {
+    fn validate(&self) -> Result<(), eyre::Error> {
+        use eyre::{ensure, WrapErr};
+
+        ensure!((1..=64).contains(&self.module_size), "module_size: must be in range 1..64");
+        ensure!(self.quiet_zone <= 16, "quiet_zone: must not exceed 16");
+        parse_color(&self.dark_color).wrap_err("dark_color")?;
+        parse_color(&self.light_color).wrap_err("light_color")?;
+        if self.logo.is_some() {
+            ensure!(
+                matches!(self.error_correction, QrErrorCorrection::Q | QrErrorCorrection::H),
+                "logo: logo requires error_correction q or h"
+            );
+        }
+        if self.caption && self.format == QrFormat::Png {
+            ensure!(self.caption_font.is_some(), "caption_font: caption in PNG requires a font file");
+        }
+
+        Ok(())
+    }
+}
+
+/// Дополнительный токен доступа к проекту
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ApiTokenConfig {
+    pub token: String,
+    /// Метка токена для логов и метрик
+    pub label: String,
+    /// Собственные ограничения токена вместо token_limits проекта
+    pub limits: Option<LimitsConfig>,
+    #[serde(default)]
+    pub disabled: bool,
 }
 
 /// Описание для отдельного проекта
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct ProjectConfig {
+    /// Имя проекта для логов, метрик и файлов состояния.
+    /// Задается явно: по нему находятся очередь, уведомления, страницы и квоты после перезапуска.
+    pub name: String,
+    /// Выключенный проект не принимает выгрузки
+    #[serde(default)]
+    pub disabled: bool,
     pub api_token: String,
+    /// Основной токен выключен, например, после ротации
+    #[serde(default)]
+    pub api_token_disabled: bool,
+    /// Дополнительные токены, например, отдельный для каждого CI
+    #[serde(default)]
+    pub extra_api_tokens: Vec<ApiTokenConfig>,
     pub google_storage_target: GoogleStorageConfig,
     pub slack_link_dub: Option<SlackConfig>,
+    /// Мессенджеры, куда кроме slack_link_dub отправляется ссылка
+    #[serde(default)]
+    pub notifiers: Vec<NotifierConfig>,
+    /// Если список не пустой, то разрешены запросы только из указанных подсетей
+    #[serde(default)]
+    pub ip_allowlist: Vec<IpNet>,
+    /// Запрещенные подсети, проверяются раньше разрешенных
+    #[serde(default)]
+    pub ip_denylist: Vec<IpNet>,
+    /// Ограничения на проект целиком
+    pub limits: Option<LimitsConfig>,
+    /// Ограничения на каждый отдельный токен проекта
+    pub token_limits: Option<LimitsConfig>,
+    /// Квоты на выгружаемые данные
+    pub quota: Option<QuotaConfig>,
+    /// Повторы при временных ошибках, без настройки делается одна попытка
+    pub retry: Option<RetryConfig>,
+    /// Выгрузки сначала пишутся в очередь на диске, клиент сразу получает 202 с id задачи
+    #[serde(default)]
+    pub spool_uploads: bool,
+    /// Уведомления о завершенных выгрузках
+    pub webhooks: Option<WebhooksConfig>,
+    /// Шаблон MiniJinja текста сообщений в мессенджеры вместо префикса и ссылки.
+    /// Доступны project, link, file_name, size, token_label, request_id и metadata из запроса.
+    pub message_template: Option<String>,
+    /// Внешний вид QR кодов со ссылкой
+    pub qr: Option<QrConfig>,
+    /// Для APK и IPA создается страница с QR кодом и установкой, мессенджеры получают ссылку на нее
+    #[serde(default)]
+    pub landing_page: bool,
 }
 
 /// Конфиг нашего приложения
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct Config {
     pub settings: SettingsConfig,
     pub projects: Vec<ProjectConfig>,
 }
 
+impl LimitsConfig {
+    fn validate(&self) -> Result<(), eyre::Error> {
+        use eyre::ensure;
+
+        if let Some(rps) = self.requests_per_second {
+            ensure!(rps.is_finite() && rps > 0.0, "requests per second must be positive");
+        }
+        if let Some(burst) = self.burst {
+            ensure!(burst > 0, "burst must be positive");
+        }
+        if let Some(max) = self.max_concurrent_uploads {
+            ensure!(max > 0, "max concurrent uploads must be positive");
+        }
+
+        Ok(())
+    }
+}
+
+/// Поддерживаемые форматы файла конфига
+#[derive(Debug, Clone, Copy)]
+enum ConfigFormat {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl ConfigFormat {
+    fn from_path(path: &Path) -> Result<ConfigFormat, eyre::Error> {
+        match path.extension().and_then(|v| v.to_str()).map(str::to_lowercase).as_deref() {
+            Some("yml") | Some("yaml") => Ok(ConfigFormat::Yaml),
+            Some("json") => Ok(ConfigFormat::Json),
+            Some("toml") => Ok(ConfigFormat::Toml),
+            _ => Err(eyre::eyre!(
+                "Unsupported config file extention {}. Only yml/yaml/json/toml are supported",
+                path.display()
+            )),
+        }
+    }
+
+    /// Читаем файл в виде дерева значений без подстановок
+    fn read_raw(self, path: &Path) -> Result<serde_json::Value, eyre::Error> {
+        let value = match self {
+            ConfigFormat::Yaml => serde_yaml::from_reader(BufReader::new(File::open(path)?))?,
+            ConfigFormat::Json => serde_json::from_reader(BufReader::new(File::open(path)?))?,
+            ConfigFormat::Toml => toml::from_str(&std::fs::read_to_string(path)?)?,
+        };
+        Ok(value)
+    }
+
+    fn serialize(self, mut value: serde_json::Value) -> Result<String, eyre::Error> {
+        let text = match self {
+            ConfigFormat::Yaml => serde_yaml::to_string(&value)?,
+            ConfigFormat::Json => serde_json::to_string_pretty(&value)?,
+            ConfigFormat::Toml => {
+                // В TOML нет null, пустые опциональные поля просто пропускаем
+                remove_nulls(&mut value);
+                toml::to_string_pretty(&toml::Value::try_from(value)?)?
+            }
+        };
+        Ok(text)
+    }
+}
+
+fn remove_nulls(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            *map = std::mem::take(map).into_iter().filter(|(_, v)| !v.is_null()).collect();
+            map.values_mut().for_each(remove_nulls);
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(remove_nulls),
+        _ => {}
+    }
+}
+
 impl Config {
     /// Пытаемся распасить конфиг из файлика
-    pub fn parse_from_file(path: PathBuf) -> Result<Config, eyre::Error> {
+    pub fn parse_from_file(path: &Path) -> Result<Config, eyre::Error> {
+        use eyre::WrapErr;
+
         // Пробуем загрузить конфиг из файлика в зависимости от расширения
-        let config: Config = match path.extension().and_then(|v| v.to_str()).map(str::to_lowercase).as_deref() {
-            Some("yml") | Some("yaml") => {
-                let r = BufReader::new(File::open(path)?);
-                serde_yaml::from_reader(r)?
-            }
-            Some("json") => {
-                let r = BufReader::new(File::open(path)?);
-                serde_json::from_reader(r)?
-            }
-            _ => {
-                return Err(eyre::eyre!(
-                    "Unsupported config file extention {}. Only yml/yaml/json/toml are supported",
-                    path.display()
-                ));
-            }
-        };
+        let format = ConfigFormat::from_path(path)?;
+        let mut value = format
+            .read_raw(path)
+            .wrap_err_with(|| format!("Config file {} parsing failed", path.display()))?;
+
+        // Подставляем переменные окружения и секреты из файлов
+        config_interpolation::interpolate(&mut value)?;
+
+        // Ошибка содержит путь к неправильному значению
+        let config: Config = serde_path_to_error::deserialize(value).map_err(|err| eyre::eyre!("{}: {}", err.path(), err.inner()))?;
 
         // Отвалидируем данные конфига после загрузки
         config.validate_config()?;
@@ -65,42 +748,259 @@ impl Config {
         Ok(config)
     }
 
-    fn validate_config(&self) -> Result<(), eyre::Error> {
-        use eyre::ensure;
+    /// Сохраняем конфиг обратно в файл в том же формате, комментарии при этом теряются.
+    /// Неизмененные значения из ${...} пишутся обратно плейсхолдерами.
+    pub fn save_to_file(&self, path: &Path) -> Result<(), eyre::Error> {
+        use eyre::WrapErr;
+
+        let format = ConfigFormat::from_path(path)?;
+        let mut value = serde_json::to_value(self).wrap_err("Config serialize failed")?;
+        if path.exists() {
+            let raw = format.read_raw(path).wrap_err("Current config read failed")?;
+            config_interpolation::restore_placeholders(&mut value, &raw);
+        }
+        let data = format.serialize(value).wrap_err("Config serialize failed")?;
+
+        // Пишем через временный файл, чтобы не оставить обрезанный конфиг
+        let tmp_path = path.with_extension("tmp");
+        std::fs::write(&tmp_path, data).wrap_err("Config write failed")?;
+        std::fs::rename(&tmp_path, path).wrap_err("Config rename failed")?;
+
+        Ok(())
+    }
+
+    /// Копия конфига без секретов для вывода на экран.
+    /// Новые секретные поля обязательно нужно добавлять сюда.
+    pub fn redacted(&self) -> Config {
+        const REDACTED: &str = "<redacted>";
+
+        let mut config = self.clone();
+        if let Some(admin) = config.settings.admin.as_mut() {
+            admin.token = REDACTED.to_owned();
+        }
+        for proj in config.projects.iter_mut() {
+            proj.api_token = REDACTED.to_owned();
+            for extra in proj.extra_api_tokens.iter_mut() {
+                extra.token = REDACTED.to_owned();
+            }
+            if let Some(slack) = proj.slack_link_dub.as_mut() {
+                slack.token = REDACTED.to_owned();
+            }
+            for notifier in proj.notifiers.iter_mut() {
+                notifier.redact(REDACTED);
+            }
+            if let Some(webhooks) = proj.webhooks.as_mut() {
+                webhooks.secret = REDACTED.to_owned();
+            }
+        }
+        config
+    }
+
+    /// Текст конфига в формате YAML
+    pub fn to_yaml_string(&self) -> Result<String, eyre::Error> {
+        Ok(serde_yaml::to_string(self)?)
+    }
+
+    /// Проверка конфига, используется и при изменениях через API администрирования.
+    /// В ошибках указываем путь к проблемному значению.
+    pub fn validate_config(&self) -> Result<(), eyre::Error> {
+        use eyre::{ensure, WrapErr};
 
         // Есть вообще проекты?
-        ensure!(!self.projects.is_empty(), "Empty projects list");
+        ensure!(!self.projects.is_empty(), "projects: empty projects list");
+
+        // API администрирования
+        if let Some(admin) = &self.settings.admin {
+            ensure!(!admin.token.is_empty(), "settings.admin.token: empty admin token");
+            ensure!(
+                admin.port != self.settings.port,
+                "settings.admin.port: admin port must differ from main port"
+            );
+        }
+
+        // Очередь выгрузок
+        if let Some(spool) = &self.settings.spool {
+            ensure!(
+                spool.retry_delay_secs > 0 && spool.retry_delay_secs <= spool.max_retry_delay_secs,
+                "settings.spool.retry_delay_secs: must be positive and not exceed max_retry_delay_secs"
+            );
+            ensure!(spool.max_attempts != Some(0), "settings.spool.max_attempts: must be positive");
+        }
+
+        // Внешний адрес
+        if let Some(public_url) = &self.settings.public_url {
+            validate_webhook_url(public_url).wrap_err("settings.public_url")?;
+        }
 
         // Проверим каждый проект
         for (key, proj) in self.projects.iter().enumerate() {
+            let path = format!("projects[{}]", key);
+
             // Токен
-            ensure!(!proj.api_token.is_empty(), "Project {}: empty token", key);
+            ensure!(!proj.api_token.is_empty(), "{}.api_token: empty token", path);
 
-            // Корзина выгрузки
+            // Дополнительные токены
+            for (index, extra) in proj.extra_api_tokens.iter().enumerate() {
+                let token_path = format!("{}.extra_api_tokens[{}]", path, index);
+                ensure!(!extra.token.is_empty(), "{}.token: empty extra token", token_path);
+                ensure!(!extra.label.is_empty(), "{}.label: empty extra token label", token_path);
+                ensure!(
+                    extra.label != DEFAULT_TOKEN_LABEL,
+                    "{}.label: token label {} is reserved for api_token",
+                    token_path,
+                    DEFAULT_TOKEN_LABEL
+                );
+                ensure!(
+                    proj.extra_api_tokens.iter().filter(|other| other.label == extra.label).count() == 1,
+                    "{}.label: duplicated token label {}",
+                    token_path,
+                    extra.label
+                );
+                if let Some(limits) = &extra.limits {
+                    limits.validate().wrap_err_with(|| format!("{}.limits", token_path))?;
+                }
+            }
+
+            // Ограничения
+            if let Some(limits) = &proj.limits {
+                limits.validate().wrap_err_with(|| format!("{}.limits", path))?;
+            }
+            if let Some(limits) = &proj.token_limits {
+                limits.validate().wrap_err_with(|| format!("{}.token_limits", path))?;
+            }
+
+            // Повторы
+            if let Some(retry) = &proj.retry {
+                retry.validate().wrap_err_with(|| format!("{}.retry", path))?;
+            }
+
+            // Уведомления о выгрузках
+            if let Some(webhooks) = &proj.webhooks {
+                webhooks.validate().wrap_err_with(|| format!("{}.webhooks", path))?;
+            }
+
+            // QR коды
+            if let Some(qr) = &proj.qr {
+                qr.validate().wrap_err_with(|| format!("{}.qr", path))?;
+            }
+
+            // Страницы выгрузок
+            if proj.landing_page {
+                ensure!(
+                    self.settings.landing_pages.is_some() && self.settings.public_url.is_some(),
+                    "{}.landing_page: settings.landing_pages and settings.public_url must be configured",
+                    path
+                );
+            }
+
+            // Очередь выгрузок
+            if proj.spool_uploads {
+                ensure!(
+                    self.settings.spool.is_some(),
+                    "{}.spool_uploads: settings.spool must be configured",
+                    path
+                );
+            }
+
+            // Квоты хранятся на диске, поэтому нужен каталог состояния
+            if proj.quota.is_some() {
+                ensure!(
+                    self.settings.state_dir.is_some(),
+                    "{}.quota: quota requires settings.state_dir",
+                    path
+                );
+            }
+
+            // Имя проекта используется в именах файлов состояния
+            ensure!(!proj.name.is_empty(), "{}.name: empty name", path);
             ensure!(
-                !proj.google_storage_target.bucket_name.is_empty(),
-                "Project {}: empty google storage bucket",
-                key
+                proj.name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+                    && !proj.name.starts_with('.'),
+                "{}.name: project name {} may contain only latin letters, digits, '_', '-' and '.'",
+                path,
+                proj.name
             );
-
-            // Файлик креденшиалов выгрузки
             ensure!(
-                proj.google_storage_target.credentials_file.exists(),
-                "Project {}: google storage credential file does not exist",
-                key
+                self.projects.iter().filter(|other| other.name == proj.name).count() == 1,
+                "{}.name: duplicated project name {}",
+                path,
+                proj.name
             );
+
+            // Корзина выгрузки
             ensure!(
-                proj.google_storage_target.credentials_file.is_file(),
-                "Project {}: google storage credential file is NOT a file",
-                key
+                !proj.google_storage_target.bucket_name.is_empty(),
+                "{}.google_storage_target.bucket_name: empty google storage bucket",
+                path
             );
 
+            // Файлик креденшиалов выгрузки
+            let credentials = proj
+                .google_storage_target
+                .resolved_credentials()
+                .map_err(|err| eyre::eyre!("{}.google_storage_target: {}", path, err))?;
+            if let GoogleCredentialsConfig::File { path: file_path } = &credentials {
+                let key = if proj.google_storage_target.credentials_file.is_some() {
+                    "credentials_file"
+                } else {
+                    "credentials.path"
+                };
+                ensure!(
+                    file_path.exists(),
+                    "{}.google_storage_target.{}: google storage credential file does not exist",
+                    path,
+                    key
+                );
+                ensure!(
+                    file_path.is_file(),
+                    "{}.google_storage_target.{}: google storage credential file is NOT a file",
+                    path,
+                    key
+                );
+            }
+            if let Some(account) = &proj.google_storage_target.impersonate_service_account {
+                ensure!(
+                    account.contains('@'),
+                    "{}.google_storage_target.impersonate_service_account: service account email expected",
+                    path
+                );
+                ensure!(
+                    !proj.google_storage_target.self_signed_jwt,
+                    "{}.google_storage_target.self_signed_jwt: can't be used with impersonate_service_account",
+                    path
+                );
+            }
+
             // Данные слака
             if let Some(slack) = &proj.slack_link_dub {
                 // Токен
-                ensure!(!slack.token.is_empty(), "Project {}: empty slack token", key);
+                ensure!(!slack.token.is_empty(), "{}.slack_link_dub.token: empty slack token", path);
                 // Токен
-                ensure!(!slack.targets.is_empty(), "Project {}: slack targets", key);
+                ensure!(!slack.targets.is_empty(), "{}.slack_link_dub.targets: empty slack targets", path);
+            }
+            if let Some(template) = &proj.message_template {
+                check_template(template).wrap_err_with(|| format!("{}.message_template", path))?;
+            }
+            for (index, notifier) in proj.notifiers.iter().enumerate() {
+                notifier
+                    .validate()
+                    .wrap_err_with(|| format!("{}.notifiers[{}]({})", path, index, notifier.kind()))?;
+            }
+        }
+
+        // Токены не должны пересекаться между проектами
+        let mut tokens = std::collections::HashSet::new();
+        for (key, proj) in self.projects.iter().enumerate() {
+            ensure!(tokens.insert(&proj.api_token), "projects[{}].api_token: duplicated api token", key);
+            for (index, extra) in proj.extra_api_tokens.iter().enumerate() {
+                ensure!(
+                    tokens.insert(&extra.token),
+                    "projects[{}].extra_api_tokens[{}].token: duplicated api token",
+                    key,
+                    index
+                );
             }
         }
 
@@ -115,14 +1015,39 @@ mod tests {
 
     fn test_results(config: Config) {
         assert_eq!(config.settings.port, 8080);
+        assert_eq!(config.settings.trusted_proxies, vec!["10.0.0.0/8".parse::<IpNet>().unwrap()]);
 
         let project_config = config.projects.get(0).unwrap();
         assert_eq!(project_config.api_token, "TOKEN_VALUE");
 
         let google_storage_info = &project_config.google_storage_target;
-        assert_eq!(google_storage_info.credentials_file, Path::new("/TEST/CREDENTIALS_FILE.json"));
+        assert_eq!(
+            google_storage_info.credentials_file.as_deref(),
+            Some(Path::new("/TEST/CREDENTIALS_FILE.json"))
+        );
+        assert_eq!(
+            google_storage_info.resolved_credentials().unwrap(),
+            GoogleCredentialsConfig::File {
+                path: "/TEST/CREDENTIALS_FILE.json".into()
+            }
+        );
         assert_eq!(google_storage_info.bucket_name, "PI2_BUCKET_NAME");
 
+        assert_eq!(project_config.ip_allowlist, vec!["192.168.0.0/16".parse::<IpNet>().unwrap()]);
+        assert!(project_config.ip_denylist.is_empty());
+
+        let limits = project_config.token_limits.as_ref().unwrap();
+        assert_eq!(limits.requests_per_second, Some(2.0));
+        assert_eq!(limits.max_concurrent_uploads, Some(4));
+
+        let quota = project_config.quota.as_ref().unwrap();
+        assert_eq!(quota.bytes_per_day, Some(1000));
+        assert_eq!(quota.max_objects, None);
+
+        let extra_token = project_config.extra_api_tokens.first().unwrap();
+        assert_eq!(extra_token.token, "EXTRA_TOKEN");
+        assert_eq!(extra_token.label, "ci");
+
         // TODO: Add new tests
     }
 
@@ -132,17 +1057,29 @@ mod tests {
         let config: Config = serde_yaml::from_str(r#"
             settings:
               port: 8080
+              trusted_proxies: ["10.0.0.0/8"]
             projects:
-                  - api_token: "TOKEN_VALUE"
+                  - name: "first"
+                    api_token: "TOKEN_VALUE"
                     google_storage_target:
                         credentials_file: "/TEST/CREDENTIALS_FILE.json"
                         bucket_name: "PI2_BUCKET_NAME"
+                    ip_allowlist: ["192.168.0.0/16"]
+                    token_limits:
+                        requests_per_second: 2
+                        max_concurrent_uploads: 4
+                    extra_api_tokens:
+                        - token: "EXTRA_TOKEN"
+                          label: "ci"
+                    quota:
+                        bytes_per_day: 1000
                     slack_link_dub:
                         token: "asdasd"
                         targets: ["asdasd", "asdads", "asdasd"]
                         qr_code: true
                         default_text_before: "qweqwe"
-                  - api_token: "asddasd"
+                  - name: "second"
+                    api_token: "asddasd"
                     google_storage_target:
                         credentials_file: "/asd/asdasd.json"
                         bucket_name: "dfgdfg"
@@ -163,15 +1100,28 @@ mod tests {
         let config: Config = serde_json::from_str(r#"
             {
                 "settings": {
-                    "port": 8080
+                    "port": 8080,
+                    "trusted_proxies": ["10.0.0.0/8"]
                 },
                 "projects": [
                     {
+                        "name": "first",
                         "api_token": "TOKEN_VALUE",
                         "google_storage_target": {
                             "credentials_file": "/TEST/CREDENTIALS_FILE.json",
                             "bucket_name": "PI2_BUCKET_NAME"
                         },
+                        "ip_allowlist": ["192.168.0.0/16"],
+                        "token_limits": {
+                            "requests_per_second": 2.0,
+                            "max_concurrent_uploads": 4
+                        },
+                        "extra_api_tokens": [
+                            {"token": "EXTRA_TOKEN", "label": "ci"}
+                        ],
+                        "quota": {
+                            "bytes_per_day": 1000
+                        },
                         "slack_link_dub": {
                             "token": "asdasd",
                             "targets": ["qweasd", "asdasdas"],
@@ -180,6 +1130,7 @@ mod tests {
                         }                        
                     },
                     {
+                        "name": "second",
                         "api_token": "fgdfg",
                         "google_storage_target": {
                             "credentials_file": "test/test.json",
@@ -198,4 +1149,157 @@ mod tests {
 
         test_results(config);
     }
+
+    #[test]
+    fn test_toml_config_parsing() {
+        #[rustfmt::skip]
+        let config: Config = toml::from_str(r#"
+            [settings]
+            port = 8080
+            trusted_proxies = ["10.0.0.0/8"]
+
+            [[projects]]
+            name = "first"
+            api_token = "TOKEN_VALUE"
+            ip_allowlist = ["192.168.0.0/16"]
+
+            [projects.google_storage_target]
+            credentials_file = "/TEST/CREDENTIALS_FILE.json"
+            bucket_name = "PI2_BUCKET_NAME"
+
+            [projects.token_limits]
+            requests_per_second = 2.0
+            max_concurrent_uploads = 4
+
+            [[projects.extra_api_tokens]]
+            token = "EXTRA_TOKEN"
+            label = "ci"
+
+            [projects.quota]
+            bytes_per_day = 1000
+
+            [projects.slack_link_dub]
+            token = "asdasd"
+            targets = ["asdasd"]
+            qr_code = true
+        "#)
+        .expect("Toml config parsing failed");
+
+        test_results(config);
+    }
+
+    #[test]
+    fn test_error_key_path() {
+        let value = serde_json::json!({
+            "settings": {"port": 8080},
+            "projects": [{"name": "first", "api_token": "TOKEN", "google_storage_target": {"credentials_file": "/a.json", "bucket_name": 5}}]
+        });
+        let err = serde_path_to_error::deserialize::<_, Config>(value).unwrap_err();
+        assert_eq!(err.path().to_string(), "projects[0].google_storage_target.bucket_name");
+    }
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(parse_color("#1a2B3c").unwrap(), [0x1a, 0x2b, 0x3c]);
+        assert!(parse_color("1a2b3c").is_err());
+        assert!(parse_color("#1a2b3g").is_err());
+        // 6 байт, но не 6 символов
+        assert!(parse_color("#ééé").is_err());
+        assert!(parse_color("#a\u{e9}bcd").is_err());
+    }
+
+    #[test]
+    fn test_project_name_required() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.yaml");
+        #[rustfmt::skip]
+        std::fs::write(&path, r#"
+            settings:
+                port: 8080
+            projects:
+                - api_token: "TOKEN_VALUE"
+                  google_storage_target:
+                      credentials_file: "/TEST/CREDENTIALS_FILE.json"
+                      bucket_name: "bucket"
+        "#).unwrap();
+
+        let err = Config::parse_from_file(&path).unwrap_err();
+        assert_eq!(err.to_string(), "projects[0]: missing field `name`");
+    }
+
+    #[test]
+    fn test_saved_config_parses_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let credentials_path = dir.path().join("credentials.json");
+        std::fs::write(&credentials_path, "{}").unwrap();
+        let path = dir.path().join("config.yaml");
+        #[rustfmt::skip]
+        std::fs::write(&path, format!(r#"
+            settings:
+                port: 8080
+            projects:
+                - name: "first"
+                  api_token: "TOKEN_VALUE"
+                  google_storage_target:
+                      credentials_file: "{}"
+                      bucket_name: "bucket"
+                  limits:
+                      requests_per_second: 0.5
+        "#, credentials_path.display())).unwrap();
+
+        // Перезагрузка после записи через API администрирования сравнивает конфиги
+        let config = Config::parse_from_file(&path).unwrap();
+        config.save_to_file(&path).unwrap();
+        assert_eq!(Config::parse_from_file(&path).unwrap(), config);
+    }
+
+    #[test]
+    fn test_credentials_config() {
+        #[rustfmt::skip]
+        let config: GoogleStorageConfig = serde_yaml::from_str(r#"
+            credentials:
+                type: metadata_server
+                endpoint: "http://127.0.0.1:8085"
+            bucket_name: "bucket"
+        "#)
+        .unwrap();
+        assert_eq!(
+            config.resolved_credentials().unwrap(),
+            GoogleCredentialsConfig::MetadataServer {
+                endpoint: Some("http://127.0.0.1:8085".to_owned()),
+                service_account: None
+            }
+        );
+
+        let config: GoogleStorageConfig = serde_yaml::from_str("{credentials: {type: application_default}, bucket_name: bucket}").unwrap();
+        assert_eq!(config.resolved_credentials().unwrap(), GoogleCredentialsConfig::ApplicationDefault);
+
+        let config: GoogleStorageConfig =
+            serde_yaml::from_str("{credentials_file: /a.json, credentials: {type: application_default}, bucket_name: bucket}").unwrap();
+        assert!(config.resolved_credentials().is_err());
+    }
+
+    #[test]
+    fn test_redacted_config() {
+        let value = serde_json::json!({
+            "settings": {"port": 8080, "admin": {"port": 8081, "token": "ADMIN_SECRET"}},
+            "projects": [{
+                "name": "first",
+                "api_token": "TOKEN_SECRET",
+                "extra_api_tokens": [{"token": "EXTRA_SECRET", "label": "ci"}],
+                "google_storage_target": {"credentials_file": "/a.json", "bucket_name": "bucket"},
+                "slack_link_dub": {"token": "SLACK_SECRET", "targets": ["channel"], "qr_code": false},
+                "notifiers": [
+                    {"type": "telegram", "bot_token": "TELEGRAM_SECRET", "chat_ids": ["123"]},
+                    {"type": "discord", "webhook_urls": ["https://discord.com/api/webhooks/1/DISCORD_SECRET"]}
+                ],
+                "webhooks": {"urls": ["https://ci.example.com/hook"], "secret": "HMAC_SECRET"}
+            }]
+        });
+        let config: Config = serde_json::from_value(value).unwrap();
+
+        let text = config.redacted().to_yaml_string().unwrap();
+        assert!(!text.contains("SECRET"));
+        assert!(text.contains("bucket"));
+    }
 }
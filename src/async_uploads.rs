@@ -0,0 +1,197 @@
+use crate::{
+    error::ErrorWithStatusAndDesc,
+    notification_outbox::{all_sent, NotificationOutbox, NotificationReport},
+    project::{Project, UploadData, UploadParams},
+    upload_queue::{JobState, UploadJob},
+};
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tracing::{error, info, Instrument};
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Сколько храним завершенные задачи для запросов статуса
+const KEEP_FINISHED_SECS: i64 = 60 * 60;
+
+/// Состояние задачи выгрузки для клиента
+#[derive(Debug, Clone, Serialize)]
+pub struct JobStatus {
+    pub job_id: String,
+    pub request_id: String,
+    pub project: String,
+    pub state: JobState,
+    /// Объем принятых от клиента данных
+    pub total_bytes: u64,
+    /// Объем отправленных в Storage данных
+    pub bytes_transferred: u64,
+    pub link: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_link: Option<String>,
+    pub slack_sent: bool,
+    /// Состояние уведомлений по получателям
+    pub notification_status: NotificationReport,
+    pub error: Option<String>,
+    /// Unix timestamp создания
+    pub created_at: i64,
+    /// Unix timestamp последнего изменения
+    pub updated_at: i64,
+}
+
+impl JobStatus {
+    fn is_finished(&self) -> bool {
+        matches!(self.state, JobState::Done | JobState::Failed)
+    }
+}
+
+impl From<&UploadJob> for JobStatus {
+    fn from(job: &UploadJob) -> Self {
+        JobStatus {
+            job_id: job.id.clone(),
+            request_id: job.params.request_id.clone(),
+            project: job.project.clone(),
+            state: job.state,
+            total_bytes: job.spooled_bytes,
+            bytes_transferred: job.uploaded_bytes.unwrap_or(0),
+            link: job.link.clone(),
+            page_link: job.page_link.clone(),
+            slack_sent: job.slack_sent,
+            notification_status: job.notification_status.clone(),
+            error: job.error.clone(),
+            created_at: job.created_at,
+            updated_at: job.updated_at,
+        }
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+struct AsyncJob {
+    status: JobStatus,
+    /// Счетчик отправленных байт текущей попытки
+    progress: Arc<AtomicU64>,
+}
+
+/// Выгрузки, которые выполняются после ответа клиенту.
+/// В отличие от очереди на диске задачи живут только в памяти процесса.
+#[derive(Default)]
+pub struct AsyncUploads {
+    jobs: Mutex<HashMap<String, AsyncJob>>,
+}
+
+impl AsyncUploads {
+    /// Запускаем выгрузку уже принятых данных в фоне.
+    /// Задача продолжает span запроса, guard удерживается до ее завершения.
+    pub fn start<G>(
+        self: &Arc<Self>,
+        project: Arc<Project>,
+        outbox: Arc<NotificationOutbox>,
+        params: UploadParams,
+        data: UploadData,
+        guard: G,
+    ) -> JobStatus
+    where
+        G: Send + 'static,
+    {
+        self.cleanup_finished();
+
+        let now = chrono::Utc::now().timestamp();
+        let status = JobStatus {
+            job_id: uuid::Uuid::new_v4().to_simple().to_string(),
+            request_id: params.request_id.clone(),
+            project: project.name().to_owned(),
+            state: JobState::Queued,
+            total_bytes: data.buffered_len().unwrap_or(params.data_length),
+            bytes_transferred: 0,
+            link: None,
+            page_link: None,
+            slack_sent: false,
+            notification_status: Default::default(),
+            error: None,
+            created_at: now,
+            updated_at: now,
+        };
+        let progress = Arc::new(AtomicU64::new(0));
+        self.jobs.lock().expect("Async uploads lock poisoned").insert(
+            status.job_id.clone(),
+            AsyncJob {
+                status: status.clone(),
+                progress: progress.clone(),
+            },
+        );
+
+        let jobs = self.clone();
+        let id = status.job_id.clone();
+        let task = async move {
+            let _guard = guard;
+
+            jobs.update(&id, |status| status.state = JobState::Uploading);
+            let attachment = project.attachment(&params, &data);
+            let result = async {
+                let upload_result = project.store(&params, data, Some(progress), &outbox).in_current_span().await?;
+                jobs.update(&id, |status| {
+                    status.link = Some(upload_result.download_link.clone());
+                    status.page_link = upload_result.page_link.clone();
+                });
+                let notification_status = outbox
+                    .send(&project, &params, upload_result.notification_link(), attachment.as_ref())
+                    .in_current_span()
+                    .await;
+                Ok::<_, ErrorWithStatusAndDesc>(notification_status)
+            }
+            .await;
+
+            match result {
+                Ok(notification_status) => {
+                    info!(job_id = %id, "Async upload finished");
+                    jobs.update(&id, |status| {
+                        status.state = JobState::Done;
+                        status.slack_sent = all_sent(&notification_status);
+                        status.notification_status = notification_status;
+                    });
+                }
+                Err(err) => {
+                    error!(job_id = %id, "Async upload failed: {}", err);
+                    jobs.update(&id, |status| {
+                        status.state = JobState::Failed;
+                        status.error = Some(err.to_string());
+                    });
+                }
+            }
+        };
+        tokio::spawn(task.in_current_span());
+
+        status
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut JobStatus)) {
+        if let Some(job) = self.jobs.lock().expect("Async uploads lock poisoned").get_mut(id) {
+            f(&mut job.status);
+            job.status.updated_at = chrono::Utc::now().timestamp();
+        }
+    }
+
+    /// Текущее состояние задачи
+    pub fn get(&self, id: &str) -> Option<JobStatus> {
+        let jobs = self.jobs.lock().expect("Async uploads lock poisoned");
+        jobs.get(id).map(|job| {
+            let mut status = job.status.clone();
+            status.bytes_transferred = job.progress.load(Ordering::Relaxed);
+            status
+        })
+    }
+
+    /// Удаляем давно завершенные задачи
+    fn cleanup_finished(&self) {
+        let expire_time = chrono::Utc::now().timestamp() - KEEP_FINISHED_SECS;
+        self.jobs
+            .lock()
+            .expect("Async uploads lock poisoned")
+            .retain(|_, job| !(job.status.is_finished() && job.status.updated_at < expire_time));
+    }
+}
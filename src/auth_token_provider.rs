@@ -1,39 +1,75 @@
 use crate::{
-    oauth2::{get_token_data, ServiceAccountData, TokenData},
+    oauth2::{Credentials, TokenData},
+    prometheus::{count_google_token_failure, remove_google_token_metrics, set_google_token_age},
     types::HttpClient,
 };
-use chrono::Duration as ChronoDuration;
+use arc_swap::ArcSwapOption;
 use eyre::{Context, ContextCompat};
+use lazy_static::lazy_static;
+use rand::Rng;
+use serde::Serialize;
 use std::{
-    path::Path,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     time::{Duration as StdDuration, Instant},
 };
-use tokio::sync::Mutex;
-use tracing::{debug, Instrument};
-use tracing_log::log::warn;
+use tokio::{
+    sync::{watch, Notify},
+    task::JoinHandle,
+    time::timeout,
+};
+use tracing::{debug, warn, Instrument};
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
+/// Обновляем токен заранее, за столько времени до истечения
+const REFRESH_BEFORE_EXPIRE: StdDuration = StdDuration::from_secs(5 * 60);
+/// Токен с меньшим оставшимся временем жизни уже не отдаем
+const MIN_TOKEN_LIFE: StdDuration = StdDuration::from_secs(30);
+/// Задержки между повторами при ошибках получения токена
+const RETRY_BASE_DELAY: StdDuration = StdDuration::from_millis(500);
+const RETRY_MAX_DELAY: StdDuration = StdDuration::from_secs(60);
+/// Как долго запрос ждет первого токена или внепланового обновления
+const WAIT_TOKEN_TIMEOUT: StdDuration = StdDuration::from_secs(30);
+/// Период обновления метрики возраста токена
+const METRICS_UPDATE_PERIOD: StdDuration = StdDuration::from_secs(15);
+
+lazy_static! {
+    /// Количество живых провайдеров с одинаковыми аккаунтом и scope.
+    /// Метки метрик у них общие, например, при ротации ключа старый провайдер живет вместе с новым.
+    static ref LIVE_PROVIDERS: Mutex<HashMap<(String, &'static str), usize>> = Mutex::new(HashMap::new());
+}
+
 #[derive(Debug)]
 struct ReceivedTokenInfo {
     data: TokenData,
+    received_time: Instant,
     expire_time: Instant,
 }
 
 impl ReceivedTokenInfo {
-    async fn request(http_client: &HttpClient, account_data: &ServiceAccountData, scopes: &str) -> Result<ReceivedTokenInfo, eyre::Error> {
+    async fn request(http_client: &HttpClient, credentials: &Credentials, scopes: &str) -> Result<ReceivedTokenInfo, eyre::Error> {
         // Получаем токен на основе данных
-        let data = get_token_data(http_client, account_data, scopes, ChronoDuration::minutes(60))
+        let data = credentials
+            .request_token(http_client, scopes)
             .in_current_span()
             .await
             .wrap_err("Token receive")?;
 
         // Вычисляем время завершения
-        let expire_time = Instant::now()
+        let received_time = Instant::now();
+        let expire_time = received_time
             .checked_add(StdDuration::from_secs(data.expires_in))
             .wrap_err("Invalid token expire time")?;
 
-        Ok(ReceivedTokenInfo { data, expire_time })
+        Ok(ReceivedTokenInfo {
+            data,
+            received_time,
+            expire_time,
+        })
     }
 
     fn life_duration_left(&self) -> StdDuration {
@@ -44,82 +80,243 @@ impl ReceivedTokenInfo {
 
 ////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
+/// Общее состояние провайдера и фоновой задачи обновления
 #[derive(Debug)]
-pub struct AuthTokenProvider {
+struct SharedState {
     http_client: HttpClient,
-    account_data: ServiceAccountData,
+    credentials: Credentials,
+    /// Имя аккаунта для метрик и статуса
+    account: String,
     scopes: &'static str,
-    token_info: Mutex<Option<ReceivedTokenInfo>>,
+    /// Текущий токен, читается без блокировок
+    token: ArcSwapOption<ReceivedTokenInfo>,
+    /// Текст последней ошибки получения токена
+    last_error: Mutex<Option<String>>,
+    /// Количество ошибок подряд
+    consecutive_failures: AtomicU64,
+    /// Просьба обновить токен вне расписания
+    refresh_requested: Notify,
+    /// Уведомление о завершении очередной попытки получения токена
+    attempt_finished_tx: watch::Sender<()>,
 }
 
-impl AuthTokenProvider {
-    pub fn new(http_client: HttpClient, service_account_json_path: &Path, scopes: &'static str) -> Result<AuthTokenProvider, eyre::Error> {
-        // Прочитаем креденшиалы для гугла
-        let service_acc_data = ServiceAccountData::new_from_file(service_account_json_path).wrap_err("Service account file read err")?;
-        debug!("Service account data: {:?}", service_acc_data);
+impl SharedState {
+    /// Токен, который еще можно отдавать
+    fn valid_token(&self) -> Option<String> {
+        let info = self.token.load();
+        info.as_ref()
+            .filter(|info| info.life_duration_left() >= MIN_TOKEN_LIFE)
+            .map(|info| info.data.access_token.clone())
+    }
 
-        Ok(AuthTokenProvider {
-            http_client,
-            account_data: service_acc_data,
-            scopes,
-            token_info: Mutex::new(None),
-        })
+    fn update_age_metric(&self) {
+        if let Some(info) = self.token.load().as_ref() {
+            set_google_token_age(&self.account, self.scopes, info.received_time.elapsed().as_secs());
+        }
     }
 
-    pub async fn get_token(&self) -> Result<String, eyre::Error> {
-        macro_rules! update_token_or_warning {
-            ($load_result: expr, $token_lock: expr, $iteration_num: expr) => {
-                match $load_result {
-                    Ok(new_info) => {
-                        $token_lock.replace(new_info);
-                    }
-                    Err(err) => {
-                        // Пока ограничиваемся выкидываем предупреждения, может быть повезет на новой итерации
-                        warn!("Token receive iteration number {} failed with err: {}", $iteration_num, err);
-                    }
+    /// Задержка перед повтором с экспоненциальным ростом и случайным разбросом
+    fn retry_delay(failures: u64) -> StdDuration {
+        let exp = RETRY_BASE_DELAY.saturating_mul(1 << failures.min(16) as u32).min(RETRY_MAX_DELAY);
+        exp.mul_f64(rand::thread_rng().gen_range(0.5..1.0))
+    }
+
+    /// Фоновое обновление токена до истечения его времени жизни
+    async fn refresh_loop(self: Arc<Self>) {
+        loop {
+            let result = ReceivedTokenInfo::request(&self.http_client, &self.credentials, self.scopes)
+                .in_current_span()
+                .await;
+
+            let next_refresh = match result {
+                Ok(info) => {
+                    debug!("Token received, life time: {:?}", info.life_duration_left());
+                    let refresh_in = info
+                        .life_duration_left()
+                        .saturating_sub(REFRESH_BEFORE_EXPIRE)
+                        .max(StdDuration::from_secs(1));
+                    self.token.store(Some(Arc::new(info)));
+                    self.consecutive_failures.store(0, Ordering::Relaxed);
+                    self.last_error.lock().expect("Token error lock poisoned").take();
+                    self.update_age_metric();
+                    Some(refresh_in)
+                }
+                Err(err) => {
+                    let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+                    warn!("Token receive failed {} times in a row: {:#}", failures, err);
+                    count_google_token_failure(&self.account, self.scopes);
+                    self.last_error
+                        .lock()
+                        .expect("Token error lock poisoned")
+                        .replace(format!("{:#}", err));
+                    None
                 }
             };
+
+            // Будим тех, кто ждет токен
+            self.attempt_finished_tx.send(()).ok();
+
+            match next_refresh {
+                Some(refresh_in) => self.wait_refresh_time(refresh_in).await,
+                // При ошибке повторяем с задержкой, внеплановые просьбы не ускоряют повтор
+                None => tokio::time::sleep(Self::retry_delay(self.consecutive_failures.load(Ordering::Relaxed))).await,
+            }
         }
+    }
 
-        // Блокируемся, тем самым не даем другим клиентам тоже получать токены
-        let mut token_lock = self.token_info.lock().in_current_span().await;
+    /// Ждем планового времени обновления или внеплановой просьбы
+    async fn wait_refresh_time(&self, refresh_in: StdDuration) {
+        let refresh_at = tokio::time::Instant::now() + refresh_in;
+        let mut metrics_interval = tokio::time::interval(METRICS_UPDATE_PERIOD);
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep_until(refresh_at) => return,
+                _ = self.refresh_requested.notified() => {
+                    // Просьба могла остаться от уже выполненного обновления
+                    if self.valid_token().is_none() {
+                        return;
+                    }
+                }
+                _ = metrics_interval.tick() => self.update_age_metric(),
+            }
+        }
+    }
+}
 
-        // Ограничиваемся количеством итераций, вдруг время жизни токена будет кривое приходить
-        for request_iteration in 0..10 {
-            // Если токен есть и не протух
-            if let Some(info) = token_lock.as_ref() {
-                debug!("Token info: {:?}, life time left: {:?}", info, info.life_duration_left());
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
-                // Если осталось уже меньше 10 секунд
-                if info.life_duration_left() < StdDuration::from_secs(30) {
-                    debug!("Token will expire after 30 seconds, request new");
+/// Состояние провайдера для проверки работоспособности
+#[derive(Debug, Serialize)]
+pub struct TokenProviderStatus {
+    pub account: String,
+    pub scope: &'static str,
+    /// ok, pending или failing
+    pub state: &'static str,
+    pub token_age_seconds: Option<u64>,
+    pub consecutive_failures: u64,
+    pub last_error: Option<String>,
+}
 
-                    // Иначе запрашиваем токен и обновляем значение локально
-                    let load_res = ReceivedTokenInfo::request(&self.http_client, &self.account_data, self.scopes)
-                        .in_current_span()
-                        .await;
+/// Провайдер токенов с фоновым обновлением до истечения времени жизни.
+/// Фоновая задача завершается при уничтожении провайдера.
+#[derive(Debug)]
+pub struct AuthTokenProvider {
+    shared: Arc<SharedState>,
+    refresh_task: JoinHandle<()>,
+}
 
-                    // Обновляем значение или идем на новую итерацию при ошибке
-                    update_token_or_warning!(load_res, token_lock, request_iteration);
-                }
-                // Пока возвращаем старый статус
-                else {
-                    return Ok(info.data.access_token.clone());
-                }
-            } else {
-                // Иначе запрашиваем токен и обновляем значение локально
-                let load_res = ReceivedTokenInfo::request(&self.http_client, &self.account_data, self.scopes)
-                    .in_current_span()
-                    .await;
-                // Обновляем значение или идем на новую итерацию при ошибке
-                update_token_or_warning!(load_res, token_lock, request_iteration);
+impl AuthTokenProvider {
+    /// Создание провайдера сразу запускает получение токена, поэтому вызывать нужно внутри рантайма tokio
+    pub fn new(http_client: HttpClient, credentials: Credentials, scopes: &'static str) -> AuthTokenProvider {
+        let (attempt_finished_tx, _) = watch::channel(());
+        let shared = Arc::new(SharedState {
+            http_client,
+            account: credentials.identity(),
+            credentials,
+            scopes,
+            token: ArcSwapOption::empty(),
+            last_error: Mutex::new(None),
+            consecutive_failures: AtomicU64::new(0),
+            refresh_requested: Notify::new(),
+            attempt_finished_tx,
+        });
+        *LIVE_PROVIDERS
+            .lock()
+            .expect("Live providers lock poisoned")
+            .entry((shared.account.clone(), scopes))
+            .or_default() += 1;
+
+        let span = tracing::info_span!("token_refresh", account = %shared.account);
+        let refresh_task = tokio::spawn(shared.clone().refresh_loop().instrument(span));
+
+        AuthTokenProvider { shared, refresh_task }
+    }
+
+    /// Источник токенов
+    pub fn credentials(&self) -> &Credentials {
+        &self.shared.credentials
+    }
+
+    /// Текущее состояние получения токенов
+    pub fn status(&self) -> TokenProviderStatus {
+        let token = self.shared.token.load();
+        let consecutive_failures = self.shared.consecutive_failures.load(Ordering::Relaxed);
+        let state = if consecutive_failures > 0 {
+            "failing"
+        } else if self.shared.valid_token().is_some() {
+            "ok"
+        } else {
+            "pending"
+        };
+        TokenProviderStatus {
+            account: self.shared.account.clone(),
+            scope: self.shared.scopes,
+            state,
+            token_age_seconds: token.as_ref().map(|info| info.received_time.elapsed().as_secs()),
+            consecutive_failures,
+            last_error: self.shared.last_error.lock().expect("Token error lock poisoned").clone(),
+        }
+    }
+
+    pub async fn get_token(&self) -> Result<String, eyre::Error> {
+        // Подписываемся заранее, чтобы не пропустить завершение попытки между проверкой и ожиданием.
+        // Новая подписка считает прошлые попытки уже увиденными.
+        let mut attempt_finished = self.shared.attempt_finished_tx.subscribe();
+
+        // Обычный случай - токен уже есть
+        if let Some(token) = self.shared.valid_token() {
+            return Ok(token);
+        }
+
+        // Токена нет или он истекает, просим обновить и ждем результата попытки
+        self.shared.refresh_requested.notify_one();
+        timeout(WAIT_TOKEN_TIMEOUT, attempt_finished.changed())
+            .in_current_span()
+            .await
+            .wrap_err("Token wait timeout")?
+            .wrap_err("Token refresh task finished")?;
+
+        match self.shared.valid_token() {
+            Some(token) => Ok(token),
+            None => {
+                let last_error = self.shared.last_error.lock().expect("Token error lock poisoned").clone();
+                Err(eyre::eyre!("Token is not available: {}", last_error.unwrap_or_default()))
             }
         }
+    }
+}
+
+impl Drop for AuthTokenProvider {
+    fn drop(&mut self) {
+        self.refresh_task.abort();
+
+        // Метрики убираем только вместе с последним провайдером с такими метками
+        let mut live_providers = LIVE_PROVIDERS.lock().expect("Live providers lock poisoned");
+        let key = (self.shared.account.clone(), self.shared.scopes);
+        let count = live_providers.get_mut(&key).map(|count| {
+            *count -= 1;
+            *count
+        });
+        if count == Some(0) {
+            live_providers.remove(&key);
+            remove_google_token_metrics(&self.shared.account, self.shared.scopes);
+        }
+    }
+}
+
+////////////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
-        // Делаем токен None
-        token_lock.take();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        // За 10 итерациий не смогли получить токен
-        Err(eyre::eyre!("Invalid tokens received more than 10 times"))
+    #[test]
+    fn test_retry_delay() {
+        for failures in 0..100 {
+            let delay = SharedState::retry_delay(failures);
+            assert!(delay <= RETRY_MAX_DELAY);
+            assert!(delay >= RETRY_BASE_DELAY / 2);
+        }
+        assert!(SharedState::retry_delay(1) < SharedState::retry_delay(10));
     }
 }
@@ -0,0 +1,143 @@
+use hyper::http::HeaderMap;
+use ipnet::IpNet;
+use std::net::IpAddr;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+fn is_trusted(ip: &IpAddr, trusted_proxies: &[IpNet]) -> bool {
+    trusted_proxies.iter().any(|net| net.contains(ip))
+}
+
+/// Определяем реальный адрес клиента.
+/// Заголовкам X-Forwarded-For / X-Real-IP доверяем только если запрос пришел от доверенного прокси,
+/// X-Forwarded-For разбираем справа налево до первого недоверенного адреса.
+pub fn resolve_client_ip(remote_addr: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    // Непосредственный отправитель не прокси - заголовки может подделать кто угодно
+    if !is_trusted(&remote_addr, trusted_proxies) {
+        return remote_addr;
+    }
+
+    // Все значения X-Forwarded-For по порядку добавления
+    let forwarded: Vec<&str> = headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .collect();
+
+    if !forwarded.is_empty() {
+        let mut client_ip = remote_addr;
+        for value in forwarded.iter().rev() {
+            match value.parse::<IpAddr>() {
+                Ok(ip) => {
+                    client_ip = ip;
+                    if !is_trusted(&ip, trusted_proxies) {
+                        break;
+                    }
+                }
+                // Мусор в цепочке - дальше верить нельзя, остаемся на последнем доверенном адресе
+                Err(_) => break,
+            }
+        }
+        return client_ip;
+    }
+
+    // Заголовок от nginx с одним адресом
+    headers
+        .get("X-Real-IP")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<IpAddr>().ok())
+        .unwrap_or(remote_addr)
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Списки разрешенных и запрещенных подсетей
+#[derive(Debug, Default)]
+pub struct IpAccessList {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl IpAccessList {
+    pub fn new(allow: Vec<IpNet>, deny: Vec<IpNet>) -> IpAccessList {
+        IpAccessList { allow, deny }
+    }
+
+    /// Запрещенные подсети имеют приоритет, пустой список разрешенных - разрешены все
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        if self.deny.iter().any(|net| net.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip))
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::http::HeaderValue;
+
+    fn nets(list: &[&str]) -> Vec<IpNet> {
+        list.iter().map(|v| v.parse().unwrap()).collect()
+    }
+
+    fn ip(v: &str) -> IpAddr {
+        v.parse().unwrap()
+    }
+
+    #[test]
+    fn test_untrusted_remote_ignores_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-For", HeaderValue::from_static("1.1.1.1"));
+        headers.insert("X-Real-IP", HeaderValue::from_static("2.2.2.2"));
+
+        let res = resolve_client_ip(ip("8.8.8.8"), &headers, &nets(&["10.0.0.0/8"]));
+        assert_eq!(res, ip("8.8.8.8"));
+    }
+
+    #[test]
+    fn test_forwarded_chain() {
+        let mut headers = HeaderMap::new();
+        headers.append("X-Forwarded-For", HeaderValue::from_static("6.6.6.6, 1.1.1.1"));
+        headers.append("X-Forwarded-For", HeaderValue::from_static("10.0.0.2"));
+
+        // Подделанный клиентом первый адрес пропускаем, берем первый недоверенный справа
+        let res = resolve_client_ip(ip("10.0.0.1"), &headers, &nets(&["10.0.0.0/8"]));
+        assert_eq!(res, ip("1.1.1.1"));
+    }
+
+    #[test]
+    fn test_forwarded_garbage() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Forwarded-For", HeaderValue::from_static("1.1.1.1, unknown, 10.0.0.5"));
+
+        let res = resolve_client_ip(ip("10.0.0.1"), &headers, &nets(&["10.0.0.0/8"]));
+        assert_eq!(res, ip("10.0.0.5"));
+    }
+
+    #[test]
+    fn test_real_ip() {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Real-IP", HeaderValue::from_static("1.1.1.1"));
+
+        let res = resolve_client_ip(ip("127.0.0.1"), &headers, &nets(&["127.0.0.1/32"]));
+        assert_eq!(res, ip("1.1.1.1"));
+    }
+
+    #[test]
+    fn test_access_list() {
+        let list = IpAccessList::new(nets(&["192.168.0.0/16"]), nets(&["192.168.1.0/24"]));
+        assert!(list.is_allowed(&ip("192.168.2.10")));
+        assert!(!list.is_allowed(&ip("192.168.1.10")));
+        assert!(!list.is_allowed(&ip("10.0.0.1")));
+
+        let list = IpAccessList::new(vec![], nets(&["10.0.0.0/8"]));
+        assert!(list.is_allowed(&ip("1.1.1.1")));
+        assert!(!list.is_allowed(&ip("10.1.1.1")));
+    }
+}
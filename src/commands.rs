@@ -0,0 +1,83 @@
+use crate::{
+    app_config::Config,
+    oauth2::{parse_private_key, Credentials},
+    project::{GoogleUploader, STORAGE_AUDIENCE},
+    token_provider_registry::{load_credentials, TokenProviderRegistry},
+    types::HttpClient,
+};
+use eyre::WrapErr;
+use std::path::Path;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Проверка конфига без запуска сервера.
+/// Возвращает false если хотя бы одна проверка не прошла.
+pub async fn check_config(config_path: &Path, online: bool, http_client: HttpClient) -> bool {
+    // Базовые проверки выполняются при загрузке
+    let config = match Config::parse_from_file(config_path) {
+        Ok(config) => config,
+        Err(err) => {
+            println!("Config is invalid: {:#}", err);
+            return false;
+        }
+    };
+    println!("Config structure is valid");
+
+    let token_providers = TokenProviderRegistry::default();
+    let mut success = true;
+    for project in config.projects.iter() {
+        // Приватный ключ должен читаться, иначе ошибка будет только при первой выгрузке.
+        // Метадата сервер и refresh токены проверяются только в режиме online.
+        let key_check = load_credentials(&project.google_storage_target, STORAGE_AUDIENCE)
+            .and_then(|credentials| check_credentials_offline(&credentials));
+        if let Err(err) = key_check {
+            println!("Project {}: {:#}", project.name, err);
+            success = false;
+            continue;
+        }
+
+        if online {
+            let uploader_check = match GoogleUploader::new(
+                http_client.clone(),
+                project.google_storage_target.clone(),
+                project.retry.clone(),
+                &token_providers,
+            ) {
+                Ok(uploader) => uploader.check_write_permission().await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = uploader_check {
+                println!("Project {}: {:#}", project.name, err);
+                success = false;
+                continue;
+            }
+        }
+
+        println!("Project {}: ok", project.name);
+    }
+
+    success
+}
+
+/// Проверки креденшиалов без сетевых запросов
+fn check_credentials_offline(credentials: &Credentials) -> Result<(), eyre::Error> {
+    match credentials {
+        Credentials::ServiceAccount(data) | Credentials::SelfSignedJwt { data, .. } => parse_private_key(data).map(|_| ()),
+        Credentials::Impersonated { source, .. } => check_credentials_offline(source),
+        Credentials::ExternalAccount(data) => match &data.credential_source.file {
+            Some(path) => {
+                eyre::ensure!(path.is_file(), "Subject token file {} does not exist", path.display());
+                Ok(())
+            }
+            None => Ok(()),
+        },
+        Credentials::AuthorizedUser(_) | Credentials::MetadataServer(_) => Ok(()),
+    }
+}
+
+/// Выводим итоговый конфиг после подстановок без секретов
+pub fn print_config(config_path: &Path) -> Result<(), eyre::Error> {
+    let config = Config::parse_from_file(config_path).wrap_err("Config load failed")?;
+    print!("{}", config.redacted().to_yaml_string()?);
+    Ok(())
+}
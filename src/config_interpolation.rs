@@ -0,0 +1,182 @@
+use serde_json::Value;
+use std::fmt::Write;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Путь к значению в конфиге в виде projects[0].slack_link_dub.token
+#[derive(Debug, Default, Clone)]
+struct KeyPath(String);
+
+impl KeyPath {
+    fn key(&self, key: &str) -> KeyPath {
+        if self.0.is_empty() {
+            KeyPath(key.to_owned())
+        } else {
+            KeyPath(format!("{}.{}", self.0, key))
+        }
+    }
+
+    fn index(&self, index: usize) -> KeyPath {
+        let mut path = self.0.clone();
+        write!(path, "[{}]", index).expect("String write failed");
+        KeyPath(path)
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Подставляем значения в строку.
+/// ${NAME} - переменная окружения, ${file:/path} - содержимое файла (например, Docker secrets),
+/// $${ - экранирование для строки "${".
+fn interpolate_str<F>(text: &str, lookup_env: &F) -> Result<String, String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(pos) = rest.find('$') {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+
+        if let Some(stripped) = rest.strip_prefix("$${") {
+            result.push_str("${");
+            rest = stripped;
+        } else if let Some(stripped) = rest.strip_prefix("${") {
+            let end = stripped.find('}').ok_or_else(|| "unclosed ${ placeholder".to_owned())?;
+            let name = &stripped[..end];
+            let value = match name.strip_prefix("file:") {
+                Some(path) => std::fs::read_to_string(path)
+                    .map(|v| v.trim_end_matches(&['\r', '\n'][..]).to_owned())
+                    .map_err(|err| format!("secret file {} read failed: {}", path, err))?,
+                None if name.is_empty() => return Err("empty ${} placeholder".to_owned()),
+                None => lookup_env(name).ok_or_else(|| format!("environment variable {} is not set", name))?,
+            };
+            result.push_str(&value);
+            rest = &stripped[end + 1..];
+        } else {
+            result.push('$');
+            rest = &rest[1..];
+        }
+    }
+    result.push_str(rest);
+    Ok(result)
+}
+
+fn interpolate_value<F>(value: &mut Value, path: KeyPath, lookup_env: &F) -> Result<(), eyre::Error>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    match value {
+        Value::String(text) => {
+            if text.contains('$') {
+                *text = interpolate_str(text, lookup_env).map_err(|err| eyre::eyre!("{}: {}", path.0, err))?;
+            }
+        }
+        Value::Array(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                interpolate_value(item, path.index(index), lookup_env)?;
+            }
+        }
+        Value::Object(map) => {
+            for (key, item) in map.iter_mut() {
+                interpolate_value(item, path.key(key), lookup_env)?;
+            }
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+    Ok(())
+}
+
+/// Подставляем переменные окружения и секреты из файлов во все строки конфига
+pub fn interpolate(value: &mut Value) -> Result<(), eyre::Error> {
+    interpolate_value(value, KeyPath::default(), &|name: &str| std::env::var(name).ok())
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// При сохранении конфига возвращаем обратно плейсхолдеры из исходного файла,
+/// если подставленное значение не изменилось. Так секреты не попадают в файл.
+pub fn restore_placeholders(value: &mut Value, raw: &Value) {
+    restore_with(value, raw, &|name: &str| std::env::var(name).ok())
+}
+
+fn restore_with<F>(value: &mut Value, raw: &Value, lookup_env: &F)
+where
+    F: Fn(&str) -> Option<String>,
+{
+    match (value, raw) {
+        (Value::String(text), Value::String(raw_text))
+            if raw_text.contains('$') && interpolate_str(raw_text, lookup_env).ok().as_ref() == Some(text) =>
+        {
+            *text = raw_text.clone();
+        }
+        (Value::Array(items), Value::Array(raw_items)) => {
+            for (item, raw_item) in items.iter_mut().zip(raw_items.iter()) {
+                restore_with(item, raw_item, lookup_env);
+            }
+        }
+        (Value::Object(map), Value::Object(raw_map)) => {
+            for (key, item) in map.iter_mut() {
+                if let Some(raw_item) = raw_map.get(key) {
+                    restore_with(item, raw_item, lookup_env);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "SLACK_TOKEN" => Some("xoxb-secret".to_owned()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_interpolate_str() {
+        assert_eq!(interpolate_str("${SLACK_TOKEN}", &lookup).unwrap(), "xoxb-secret");
+        assert_eq!(interpolate_str("a-${SLACK_TOKEN}-b", &lookup).unwrap(), "a-xoxb-secret-b");
+        assert_eq!(interpolate_str("$${X} 5$", &lookup).unwrap(), "${X} 5$");
+        assert!(interpolate_str("${MISSING}", &lookup).is_err());
+        assert!(interpolate_str("${SLACK_TOKEN", &lookup).is_err());
+
+        // Секрет из файла без завершающего перевода строки
+        let dir = std::env::temp_dir().join(format!("config_interpolation_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let secret_path = dir.join("secret");
+        std::fs::write(&secret_path, "file-secret\n").unwrap();
+        let text = format!("${{file:{}}}", secret_path.display());
+        assert_eq!(interpolate_str(&text, &lookup).unwrap(), "file-secret");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_interpolate_tree() {
+        let mut value = json!({
+            "projects": [
+                {"api_token": "plain"},
+                {"slack_link_dub": {"token": "${MISSING}"}}
+            ]
+        });
+        let err = interpolate_value(&mut value, KeyPath::default(), &lookup).unwrap_err();
+        assert!(err.to_string().starts_with("projects[1].slack_link_dub.token: "));
+
+        let raw = json!({"token": "${SLACK_TOKEN}", "port": 80, "targets": ["${SLACK_TOKEN}"]});
+        let mut value = raw.clone();
+        interpolate_value(&mut value, KeyPath::default(), &lookup).unwrap();
+        assert_eq!(value, json!({"token": "xoxb-secret", "port": 80, "targets": ["xoxb-secret"]}));
+
+        // Измененные значения пишутся как есть, неизмененные - обратно плейсхолдером
+        value["targets"][0] = json!("changed");
+        restore_with(&mut value, &raw, &lookup);
+        assert_eq!(value, json!({"token": "${SLACK_TOKEN}", "port": 80, "targets": ["changed"]}));
+    }
+}
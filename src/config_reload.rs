@@ -0,0 +1,152 @@
+use crate::{app_config::Config, prometheus::count_config_reload, types::App};
+use eyre::{ContextCompat, WrapErr};
+use notify::{watcher, DebouncedEvent, RecursiveMode, Watcher};
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::mpsc::{unbounded_channel, UnboundedReceiver},
+};
+use tracing::{debug, error, info, warn, Instrument};
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Перечитываем файл конфига и пересоздаем все проекты.
+/// Выгрузки в процессе держат ссылки на старые проекты и завершаются со старыми настройками.
+/// С `skip_unchanged` конфиг, совпадающий с текущим, не применяется, возвращается false.
+/// Так запись файла через API администрирования не пересоздает все проекты повторно.
+pub async fn reload_config(app: &App, skip_unchanged: bool) -> Result<bool, eyre::Error> {
+    let _update_lock = app.state_update_lock.lock().in_current_span().await;
+
+    let config = Config::parse_from_file(&app.config_path).wrap_err("Config load failed")?;
+
+    let current = app.state.load_full();
+    if skip_unchanged && config == current.config {
+        return Ok(false);
+    }
+
+    // Эти настройки применяются только при старте
+    let (old_settings, new_settings) = (&current.config.settings, &config.settings);
+    if old_settings.port != new_settings.port
+        || old_settings.state_dir != new_settings.state_dir
+        || old_settings.spool != new_settings.spool
+        || old_settings.admin.as_ref().map(|v| v.port) != new_settings.admin.as_ref().map(|v| v.port)
+    {
+        warn!("Port, admin port, state and spool directory changes require restart");
+    }
+
+    let new_state = app.factory.reload(config, &current).wrap_err("Projects create failed")?;
+    app.state.store(Arc::new(new_state));
+
+    Ok(true)
+}
+
+/// Изменения файла конфига.
+/// Следим за директорией, так как редакторы и Kubernetes заменяют файл целиком.
+fn watch_config_file(config_path: &Path) -> Result<UnboundedReceiver<()>, eyre::Error> {
+    let config_path = config_path.canonicalize().wrap_err("Config path canonicalize failed")?;
+    let dir = config_path
+        .parent()
+        .map(Path::to_path_buf)
+        .wrap_err("Config directory is missing")?;
+    let file_name = config_path
+        .file_name()
+        .map(OsStr::to_os_string)
+        .wrap_err("Config file name is missing")?;
+
+    let (events_tx, events_rx) = std::sync::mpsc::channel();
+    let mut file_watcher = watcher(events_tx, Duration::from_secs(1)).wrap_err("File watcher create failed")?;
+    file_watcher
+        .watch(&dir, RecursiveMode::NonRecursive)
+        .wrap_err("Config directory watch failed")?;
+
+    // Наш ли это файл, в Kubernetes ConfigMap обновляется через подмену директории ..data
+    let is_config_path = move |path: &PathBuf| match path.file_name() {
+        Some(name) => name == file_name || name.to_string_lossy().starts_with("..data"),
+        None => false,
+    };
+
+    let (changes_tx, changes_rx) = unbounded_channel();
+    std::thread::Builder::new()
+        .name("config_watcher".to_owned())
+        .spawn(move || {
+            // Watcher должен жить пока работает поток
+            let _file_watcher = file_watcher;
+            for event in events_rx {
+                let changed = match event {
+                    DebouncedEvent::Create(path) | DebouncedEvent::Write(path) | DebouncedEvent::Chmod(path) => is_config_path(&path),
+                    DebouncedEvent::Rename(_, path) => is_config_path(&path),
+                    DebouncedEvent::Rescan => true,
+                    DebouncedEvent::Error(err, _) => {
+                        warn!("Config watch error: {}", err);
+                        false
+                    }
+                    _ => false,
+                };
+                if changed && changes_tx.send(()).is_err() {
+                    break;
+                }
+            }
+        })
+        .wrap_err("Config watcher thread spawn failed")?;
+
+    Ok(changes_rx)
+}
+
+async fn next_file_change(changes: &mut Option<UnboundedReceiver<()>>) -> Option<()> {
+    match changes {
+        Some(changes) => changes.recv().await,
+        None => futures::future::pending().await,
+    }
+}
+
+/// Перезагружаем конфиг по SIGHUP и при изменении файла
+pub async fn watch_config_changes(app: Arc<App>) -> Result<(), eyre::Error> {
+    let mut sighup = signal(SignalKind::hangup()).wrap_err("SIGHUP handler setup failed")?;
+
+    // Без отслеживания файла остается перезагрузка по сигналу
+    let mut file_changes = match watch_config_file(&app.config_path) {
+        Ok(changes) => Some(changes),
+        Err(err) => {
+            warn!("Config file watching is disabled: {:#}", err);
+            None
+        }
+    };
+
+    loop {
+        let trigger = tokio::select! {
+            Some(_) = sighup.recv() => "sighup",
+            Some(_) = next_file_change(&mut file_changes) => "file",
+            else => break,
+        };
+
+        // Несколько изменений подряд обрабатываем одной перезагрузкой
+        if let Some(changes) = file_changes.as_mut() {
+            while changes.try_recv().is_ok() {}
+        }
+
+        // По сигналу пересоздаем проекты всегда, чтобы перечитать ключи,
+        // а изменение файла могло быть нашей же записью через API администрирования
+        let span = tracing::info_span!("config_reload", trigger);
+        match reload_config(&app, trigger == "file").instrument(span).await {
+            Ok(true) => {
+                info!(trigger, "Config reloaded");
+                count_config_reload(trigger, true);
+            }
+            Ok(false) => {
+                debug!(trigger, "Config file is not changed, reload skipped");
+            }
+            Err(err) => {
+                // Продолжаем работать на прошлом конфиге
+                error!(trigger, "Config reload failed: {:#}", err);
+                count_config_reload(trigger, false);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -171,6 +171,14 @@ impl<T> WrapErrorWithStatusAndDesc<T> for Option<T> {
 
 /////////////////////////////////////////////////////////////////////////////////////////////////////////
 
+/// Вид ошибки, когда одного статуса недостаточно
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    Other,
+    /// Исчерпана квота проекта, повтор выгрузки не поможет
+    QuotaExceeded,
+}
+
 #[derive(Debug)]
 pub struct ErrorWithStatusAndDesc {
     // Время жизни распространяется лишь на ссылки в подтипе, они должны иметь время жизни 'static
@@ -178,6 +186,9 @@ pub struct ErrorWithStatusAndDesc {
     source: Option<eyre::Error>,
     pub status: StatusCode,
     pub desc: Cow<'static, str>,
+    /// Через сколько секунд клиенту стоит повторить запрос
+    pub retry_after: Option<u64>,
+    pub kind: ErrorKind,
 }
 impl ErrorWithStatusAndDesc {
     pub fn from_error_with_status_desc(e: eyre::Error, status: StatusCode, desc: Cow<'static, str>) -> Self {
@@ -185,6 +196,8 @@ impl ErrorWithStatusAndDesc {
             source: Some(e),
             status,
             desc,
+            retry_after: None,
+            kind: ErrorKind::Other,
         }
     }
 
@@ -193,8 +206,22 @@ impl ErrorWithStatusAndDesc {
             source: None,
             status,
             desc,
+            retry_after: None,
+            kind: ErrorKind::Other,
         }
     }
+
+    /// Добавляем к ошибке значение для заголовка Retry-After
+    pub fn with_retry_after(mut self, secs: u64) -> Self {
+        self.retry_after = Some(secs);
+        self
+    }
+
+    /// Уточняем вид ошибки
+    pub fn with_kind(mut self, kind: ErrorKind) -> Self {
+        self.kind = kind;
+        self
+    }
 }
 impl Display for ErrorWithStatusAndDesc {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
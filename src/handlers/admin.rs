@@ -0,0 +1,263 @@
+use crate::{
+    app_config::{ApiTokenConfig, Config, LimitsConfig, ProjectConfig, DEFAULT_TOKEN_LABEL},
+    error::{ErrorWithStatusAndDesc, WrapErrorWithStatusAndDesc},
+    helpers::{constant_time_eq, get_required_str_header},
+    types::App,
+};
+use hyper::{
+    body::{to_bytes, Body as BodyStruct},
+    http::{header, method::Method, status::StatusCode},
+    Request, Response,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{net::IpAddr, sync::Arc};
+use tracing::{info, Instrument};
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+fn json_response<T: Serialize>(status: StatusCode, data: &T) -> Result<Response<BodyStruct>, ErrorWithStatusAndDesc> {
+    let json_text = serde_json::to_string(data).wrap_err_with_500()?;
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.essence_str())
+        .header(header::CONTENT_LENGTH, json_text.len())
+        .body(BodyStruct::from(json_text))
+        .wrap_err_with_500()
+}
+
+async fn parse_json_body<T: DeserializeOwned>(req: Request<BodyStruct>) -> Result<T, ErrorWithStatusAndDesc> {
+    let body = to_bytes(req.into_body()).in_current_span().await.wrap_err_with_400()?;
+    serde_json::from_slice(&body).map_err(|err| {
+        ErrorWithStatusAndDesc::new_with_status_desc(StatusCode::BAD_REQUEST, format!("Json body parsing failed: {}", err).into())
+    })
+}
+
+fn find_project<'a>(config: &'a mut Config, name: &str) -> Result<&'a mut ProjectConfig, ErrorWithStatusAndDesc> {
+    config
+        .projects
+        .iter_mut()
+        .find(|v| v.name == name)
+        .wrap_err_with_status_fn_desc(StatusCode::NOT_FOUND, || format!("Project {} is missing", name).into())
+}
+
+/// Изменяем копию текущего конфига, проверяем ее, собираем новые проекты и атомарно подменяем состояние
+async fn update_config<F>(app: &App, modify: F) -> Result<(), ErrorWithStatusAndDesc>
+where
+    F: FnOnce(&mut Config) -> Result<(), ErrorWithStatusAndDesc>,
+{
+    let _update_lock = app.state_update_lock.lock().in_current_span().await;
+
+    let current = app.state.load_full();
+    let mut config = current.config.clone();
+    modify(&mut config)?;
+
+    // Те же самые проверки, что и при загрузке из файла
+    config.validate_config().map_err(|err| {
+        ErrorWithStatusAndDesc::new_with_status_desc(StatusCode::BAD_REQUEST, format!("Invalid config: {:#}", err).into())
+    })?;
+
+    let new_state = app.factory.rebuild(config, &current).map_err(|err| {
+        ErrorWithStatusAndDesc::new_with_status_desc(StatusCode::BAD_REQUEST, format!("Projects create failed: {:#}", err).into())
+    })?;
+
+    // Сначала сохраняем на диск, чтобы не получить расхождение при ошибке записи
+    let persist = new_state.config.settings.admin.as_ref().map(|v| v.persist_changes).unwrap_or(false);
+    if persist {
+        new_state
+            .config
+            .save_to_file(&app.config_path)
+            .wrap_err_with_500_desc("Config save failed".into())?;
+    }
+
+    app.state.store(Arc::new(new_state));
+
+    Ok(())
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+async fn list_projects(app: &App) -> Result<Response<BodyStruct>, ErrorWithStatusAndDesc> {
+    #[derive(Serialize)]
+    struct TokenInfo<'a> {
+        label: &'a str,
+        disabled: bool,
+        limits: Option<&'a LimitsConfig>,
+    }
+    #[derive(Serialize)]
+    struct ProjectInfo<'a> {
+        name: &'a str,
+        disabled: bool,
+        bucket: &'a str,
+        tokens: Vec<TokenInfo<'a>>,
+    }
+    #[derive(Serialize)]
+    struct ProjectsList<'a> {
+        projects: Vec<ProjectInfo<'a>>,
+    }
+
+    // Значения токенов не отдаем, только метки
+    let state = app.state.load();
+    let projects = state
+        .config
+        .projects
+        .iter()
+        .map(|proj| {
+            let mut tokens = vec![TokenInfo {
+                label: DEFAULT_TOKEN_LABEL,
+                disabled: proj.api_token_disabled,
+                limits: proj.token_limits.as_ref(),
+            }];
+            tokens.extend(proj.extra_api_tokens.iter().map(|extra| TokenInfo {
+                label: &extra.label,
+                disabled: extra.disabled,
+                limits: extra.limits.as_ref().or(proj.token_limits.as_ref()),
+            }));
+            ProjectInfo {
+                name: &proj.name,
+                disabled: proj.disabled,
+                bucket: &proj.google_storage_target.bucket_name,
+                tokens,
+            }
+        })
+        .collect();
+
+    json_response(StatusCode::OK, &ProjectsList { projects })
+}
+
+async fn create_project(app: &App, req: Request<BodyStruct>) -> Result<Response<BodyStruct>, ErrorWithStatusAndDesc> {
+    let project: ProjectConfig = parse_json_body(req).in_current_span().await?;
+    let name = project.name.clone();
+
+    update_config(app, move |config| {
+        if config.projects.iter().any(|v| v.name == project.name) {
+            return Err(ErrorWithStatusAndDesc::new_with_status_desc(
+                StatusCode::CONFLICT,
+                "Project already exists".into(),
+            ));
+        }
+        config.projects.push(project);
+        Ok(())
+    })
+    .in_current_span()
+    .await?;
+
+    info!(target: "audit", project = %name, "Project created");
+
+    json_response(StatusCode::CREATED, &serde_json::json!({ "name": name }))
+}
+
+async fn disable_project(app: &App, name: &str) -> Result<Response<BodyStruct>, ErrorWithStatusAndDesc> {
+    update_config(app, |config| {
+        find_project(config, name)?.disabled = true;
+        Ok(())
+    })
+    .in_current_span()
+    .await?;
+
+    info!(target: "audit", project = %name, "Project disabled");
+
+    json_response(StatusCode::OK, &serde_json::json!({ "name": name, "disabled": true }))
+}
+
+async fn create_token(app: &App, name: &str, req: Request<BodyStruct>) -> Result<Response<BodyStruct>, ErrorWithStatusAndDesc> {
+    #[derive(Deserialize)]
+    struct CreateTokenRequest {
+        label: String,
+        /// Если не передан - генерируем случайный
+        token: Option<String>,
+        limits: Option<LimitsConfig>,
+    }
+    let request: CreateTokenRequest = parse_json_body(req).in_current_span().await?;
+
+    let token = request
+        .token
+        .unwrap_or_else(|| format!("{:x}{:x}", uuid::Uuid::new_v4().to_simple(), uuid::Uuid::new_v4().to_simple()));
+    let label = request.label;
+
+    update_config(app, |config| {
+        find_project(config, name)?.extra_api_tokens.push(ApiTokenConfig {
+            token: token.clone(),
+            label: label.clone(),
+            limits: request.limits,
+            disabled: false,
+        });
+        Ok(())
+    })
+    .in_current_span()
+    .await?;
+
+    info!(target: "audit", project = %name, token = %label, "Api token created");
+
+    // Значение токена отдаем только один раз при создании
+    json_response(StatusCode::CREATED, &serde_json::json!({ "label": label, "token": token }))
+}
+
+async fn disable_token(app: &App, name: &str, label: &str) -> Result<Response<BodyStruct>, ErrorWithStatusAndDesc> {
+    update_config(app, |config| {
+        let project = find_project(config, name)?;
+        if label == DEFAULT_TOKEN_LABEL {
+            project.api_token_disabled = true;
+        } else {
+            project
+                .extra_api_tokens
+                .iter_mut()
+                .find(|v| v.label == label)
+                .wrap_err_with_status_fn_desc(StatusCode::NOT_FOUND, || format!("Token {} is missing", label).into())?
+                .disabled = true;
+        }
+        Ok(())
+    })
+    .in_current_span()
+    .await?;
+
+    info!(target: "audit", project = %name, token = %label, "Api token disabled");
+
+    json_response(StatusCode::OK, &serde_json::json!({ "label": label, "disabled": true }))
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Обработчик запросов API администрирования на отдельном порту
+pub async fn handle_admin_request(
+    app: &App,
+    req: Request<BodyStruct>,
+    client_ip: IpAddr,
+) -> Result<Response<BodyStruct>, ErrorWithStatusAndDesc> {
+    // Проверяем токен администратора
+    {
+        let state = app.state.load();
+        let admin_config = state
+            .config
+            .settings
+            .admin
+            .as_ref()
+            .wrap_err_with_status_desc(StatusCode::NOT_FOUND, "Admin API is disabled".into())?;
+        let token = get_required_str_header(req.headers(), "X-Admin-Token")
+            .wrap_err_with_status_desc(StatusCode::UNAUTHORIZED, "Admin token parsing failed".into())?;
+        if !constant_time_eq(token.as_bytes(), admin_config.token.as_bytes()) {
+            info!(target: "audit", client_ip = %client_ip, "Admin request with invalid token rejected");
+            return Err(ErrorWithStatusAndDesc::new_with_status_desc(
+                StatusCode::UNAUTHORIZED,
+                "Invalid admin token".into(),
+            ));
+        }
+    }
+
+    info!(target: "audit", client_ip = %client_ip, method = %req.method(), path = %req.uri().path(), "Admin request");
+
+    let method = req.method().clone();
+    let path = req.uri().path().trim_matches('/').to_owned();
+    let segments: Vec<&str> = path.split('/').collect();
+
+    match (&method, segments.as_slice()) {
+        (&Method::GET, ["projects"]) => list_projects(app).in_current_span().await,
+        (&Method::POST, ["projects"]) => create_project(app, req).in_current_span().await,
+        (&Method::POST, ["projects", name, "disable"]) => disable_project(app, name).in_current_span().await,
+        (&Method::POST, ["projects", name, "tokens"]) => create_token(app, name, req).in_current_span().await,
+        (&Method::POST, ["projects", name, "tokens", label, "disable"]) => disable_token(app, name, label).in_current_span().await,
+        _ => Err(ErrorWithStatusAndDesc::new_with_status_desc(
+            StatusCode::BAD_REQUEST,
+            "Wrong path or method".into(),
+        )),
+    }
+}
@@ -0,0 +1,39 @@
+use crate::{
+    error::{ErrorWithStatusAndDesc, WrapErrorWithStatusAndDesc},
+    helpers::get_required_str_header,
+    types::{ApiToken, App},
+};
+use hyper::http::{HeaderMap, StatusCode};
+use std::{net::IpAddr, sync::Arc};
+use tracing::warn;
+
+/// Ищем токен проекта из запроса и проверяем адрес клиента
+/// до любой работы с токенами Google и телом запроса
+pub fn find_api_token(app: &App, headers: &HeaderMap, client_ip: IpAddr) -> Result<Arc<ApiToken>, ErrorWithStatusAndDesc> {
+    let state = app.state.load();
+
+    // Адрес проверяем до токена, чтобы с запрещенного адреса нельзя было отличить правильный токен от неправильного
+    if !state.tokens.values().any(|api_token| api_token.project.is_ip_allowed(&client_ip)) {
+        warn!(target: "audit", client_ip = %client_ip, "Request from forbidden address rejected");
+        return Err(ErrorWithStatusAndDesc::new_with_status_desc(
+            StatusCode::FORBIDDEN,
+            "Address is not allowed".into(),
+        ));
+    }
+
+    // Получаем токен из запроса и проверяем
+    let token = get_required_str_header(headers, "X-Api-Token")
+        .wrap_err_with_status_desc(StatusCode::UNAUTHORIZED, "Api token parsing failed".into())?;
+
+    // Ищем необходимый нам проект в зависимости от переданных данных.
+    // Токен проекта, которому адрес запрещен, отвечает так же, как неизвестный токен.
+    let api_token = state.tokens.get(token);
+    if let Some(api_token) = api_token.filter(|api_token| !api_token.project.is_ip_allowed(&client_ip)) {
+        let project = &api_token.project;
+        warn!(target: "audit", project = %project.name(), token = %api_token.label, client_ip = %client_ip, "Request from forbidden address rejected");
+    }
+    api_token
+        .filter(|api_token| api_token.project.is_ip_allowed(&client_ip))
+        .cloned()
+        .wrap_err_with_400_desc("Requested project is missing".into())
+}
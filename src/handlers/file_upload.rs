@@ -1,14 +1,27 @@
+use super::{
+    api_token::find_api_token,
+    qr::{qr_code_value, QrResponseMode},
+};
 use crate::{
+    async_uploads::JobStatus,
     error::{ErrorWithStatusAndDesc, WrapErrorWithStatusAndDesc},
-    helpers::{get_content_length, get_content_type, get_required_str_header},
+    helpers::{get_content_length, get_content_type},
+    project::UploadParams,
+    prometheus::count_rate_limit_rejection,
+    rate_limit::{LimitPermit, LimitRejection, Limiter},
     types::App,
 };
 use async_compression::tokio::bufread::GzipEncoder;
 use futures::StreamExt;
-use hyper::{body::Body as BodyStruct, http::status::StatusCode, Request, Response};
+use hyper::{
+    body::Body as BodyStruct,
+    http::{header, status::StatusCode},
+    Request, Response,
+};
 use serde::Deserialize;
+use std::{collections::BTreeMap, net::IpAddr};
 use tokio_util::io::{ReaderStream, StreamReader};
-use tracing::{debug, info, Instrument};
+use tracing::{debug, info, warn, Instrument};
 
 /////////////////////////////////////////////////////////////////////////////////////////////////////////
 
@@ -55,6 +68,16 @@ fn gzip_body(body: BodyStruct) -> BodyStruct {
     BodyStruct::wrap_stream(out_stream)
 }
 
+/// Получаем разрешение на выгрузку у ограничителя или формируем ответ 429
+fn acquire_limit(limiter: &Limiter, project_name: &str, token_label: &str, scope: &str) -> Result<LimitPermit, ErrorWithStatusAndDesc> {
+    limiter.try_acquire().map_err(|rejection: LimitRejection| {
+        warn!(project = %project_name, token = %token_label, scope, reason = rejection.reason(), "Upload rejected by limits");
+        count_rate_limit_rejection(project_name, scope, rejection.reason());
+        ErrorWithStatusAndDesc::new_with_status_desc(StatusCode::TOO_MANY_REQUESTS, format!("Too many {} uploads", scope).into())
+            .with_retry_after(rejection.retry_after_secs())
+    })
+}
+
 fn build_name_and_body(req: Request<BodyStruct>, input_filename: Option<String>) -> Result<(String, BodyStruct), ErrorWithStatusAndDesc> {
     // Макрос форматирования имени
     macro_rules! format_name {
@@ -107,26 +130,52 @@ fn build_name_and_body(req: Request<BodyStruct>, input_filename: Option<String>)
     Ok((name, body))
 }
 
+/// Список значений через запятую из query строки
+fn split_list(value: Option<String>) -> Vec<String> {
+    value
+        .map(|v| v.split(',').map(str::trim).filter(|v| !v.is_empty()).map(str::to_owned).collect())
+        .unwrap_or_default()
+}
+
+/// Ответ 202 для выгрузки, поставленной в очередь или запущенной в фоне
+fn job_accepted_response(job: &JobStatus) -> Result<Response<BodyStruct>, ErrorWithStatusAndDesc> {
+    let json_text = serde_json::json!({
+        "job_id": job.job_id,
+        "request_id": job.request_id,
+        "state": job.state,
+    })
+    .to_string();
+    Response::builder()
+        .status(StatusCode::ACCEPTED)
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.essence_str())
+        .header(header::CONTENT_LENGTH, json_text.len())
+        .body(BodyStruct::from(json_text))
+        .wrap_err_with_500()
+}
+
 // Пока достаточно самого верхнего контекста трассировки чтобы не захламлять вывод логов
 // #[instrument(level = "error", skip(app, req))]
-pub async fn file_upload(app: &App, req: Request<BodyStruct>, request_id: &str) -> Result<Response<BodyStruct>, ErrorWithStatusAndDesc> {
+pub async fn file_upload(
+    app: &App,
+    req: Request<BodyStruct>,
+    request_id: &str,
+    client_ip: IpAddr,
+) -> Result<Response<BodyStruct>, ErrorWithStatusAndDesc> {
     info!("File uploading");
 
-    // NGINX сейчас может добавлять заголовки при проксировании
-    // X-Real-IP
-    // X-Forwarded-For
-
-    // Ищем проект
-    let project = {
-        // Получаем токен из запроса и проверяем
-        let token = get_required_str_header(req.headers(), "X-Api-Token")
-            .wrap_err_with_status_desc(StatusCode::UNAUTHORIZED, "Api token parsing failed".into())?;
-
-        // Ищем необходимый нам проект в зависимости от переданных данных
-        app
-            .projects
-            .get(token)
-            .wrap_err_with_400_desc("Requested project is missing".into())?
+    // Ищем проект с проверкой адреса клиента
+    let api_token = find_api_token(app, req.headers(), client_ip)?;
+    let project = &api_token.project;
+
+    // Ограничения частоты и параллельности, разрешения держим до конца выгрузки.
+    // Если проект отклоняет запрос, токен частоты возвращаем в лимит токена.
+    let token_permit = acquire_limit(&api_token.limiter, project.name(), &api_token.label, "token")?;
+    let project_permit = match acquire_limit(project.limiter(), project.name(), &api_token.label, "project") {
+        Ok(permit) => permit,
+        Err(err) => {
+            token_permit.refund();
+            return Err(err);
+        }
     };
 
     // Один раз распарсим query строку
@@ -135,11 +184,36 @@ pub async fn file_upload(app: &App, req: Request<BodyStruct>, request_id: &str)
         filename: Option<String>,
         slack_send: Option<bool>,
         slack_text_prefix: Option<String>,
+        /// Ответить сразу после приема данных, выгрузка продолжится в фоне
+        #[serde(rename = "async")]
+        async_mode: Option<bool>,
+        /// Дополнительный адрес уведомления о завершении выгрузки
+        callback_url: Option<String>,
+        /// Каналы и пользователи Slack через запятую вместо каналов из настроек
+        slack_channels: Option<String>,
+        slack_users: Option<String>,
+        /// Ответ в существующий тред, вместе с единственным каналом в slack_channels
+        slack_thread_ts: Option<String>,
+        /// Получатели письма через запятую вместо адресов из настроек
+        email_to: Option<String>,
+        /// QR код ссылки в ответе: base64 или url
+        qr: Option<QrResponseMode>,
+        /// Метаданные для шаблонов сообщений: metadata[build]=42&metadata[branch]=main
+        #[serde(default)]
+        metadata: BTreeMap<String, String>,
     }
     let Query {
         filename,
         slack_send,
         slack_text_prefix,
+        async_mode,
+        callback_url,
+        slack_channels,
+        slack_users,
+        slack_thread_ts,
+        email_to,
+        qr,
+        metadata,
     } = if let Some(query_text) = req.uri().query() {
         serde_qs::from_str::<Query>(query_text).wrap_err_with_400_desc("Query parsing error".into())?
     } else {
@@ -156,16 +230,77 @@ pub async fn file_upload(app: &App, req: Request<BodyStruct>, request_id: &str)
     let (result_file_name, result_body) = build_name_and_body(req, filename)?;
 
     // Выполняем выгрузку c помощью указанного проекта
-    project
-        .upload(
-            result_file_name,
-            result_body,
-            slack_send.unwrap_or(false),
-            slack_text_prefix,
-            request_id,
-        )
+    let params = UploadParams {
+        file_name: result_file_name,
+        data_length: data_length as u64,
+        link_to_slack: slack_send.unwrap_or(false),
+        slack_text_prefix,
+        request_id: request_id.to_owned(),
+        client_ip,
+        callback_url,
+        slack_channels: split_list(slack_channels),
+        slack_users: split_list(slack_users),
+        slack_thread_ts,
+        email_to: split_list(email_to),
+        token_label: api_token.label.clone(),
+        metadata,
+    };
+
+    // Ссылка появится только после выгрузки
+    if qr.is_some() && (project.spool_uploads() || async_mode.unwrap_or(false)) {
+        return Err(ErrorWithStatusAndDesc::new_with_status_desc(
+            StatusCode::BAD_REQUEST,
+            "QR code in response requires synchronous upload".into(),
+        ));
+    }
+
+    // Выгрузка через очередь на диске, данные не потеряются при недоступности Google
+    if project.spool_uploads() {
+        let upload_queue = app
+            .upload_queue
+            .as_ref()
+            .wrap_err_with_status_desc(StatusCode::SERVICE_UNAVAILABLE, "Upload queue is not configured".into())?;
+        project.check_params(&params)?;
+        let job = upload_queue
+            .enqueue(project.name(), params, result_body)
+            .in_current_span()
+            .await
+            .wrap_err_with_500_desc("Upload spooling failed".into())?;
+        info!(job_id = %job.id, "Upload queued");
+        return job_accepted_response(&JobStatus::from(&job));
+    }
+
+    // Принимаем данные целиком и отвечаем, не дожидаясь выгрузки
+    if async_mode.unwrap_or(false) {
+        project.check_params(&params)?;
+        let data = project.buffer_body(result_body).in_current_span().await?;
+        let job = app.async_uploads.start(
+            project.clone(),
+            app.notification_outbox.clone(),
+            params,
+            data,
+            (token_permit, project_permit),
+        );
+        info!(job_id = %job.job_id, "Async upload started");
+        return job_accepted_response(&job);
+    }
+
+    let mut upload_response = project
+        .upload(params, result_body, &app.notification_outbox)
         .in_current_span()
-        .await
+        .await?;
+    if let Some(mode) = qr {
+        let public_url = app.state.load().config.settings.public_url.clone();
+        upload_response.qr_code = Some(qr_code_value(project, public_url.as_deref(), &upload_response.link, mode)?);
+    }
+
+    let json_text = serde_json::to_string(&upload_response).wrap_err_with_500()?;
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.essence_str())
+        .header(header::CONTENT_LENGTH, json_text.len())
+        .body(BodyStruct::from(json_text))
+        .wrap_err_with_500()
 }
 
 #[cfg(test)]
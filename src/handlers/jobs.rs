@@ -0,0 +1,45 @@
+use super::api_token::find_api_token;
+use crate::{
+    error::{ErrorWithStatusAndDesc, WrapErrorWithStatusAndDesc},
+    types::App,
+};
+use hyper::{
+    body::Body as BodyStruct,
+    http::{header, StatusCode},
+    Request, Response,
+};
+use std::net::IpAddr;
+
+/// Отдаем состояние фоновой выгрузки или задачи из очереди
+pub async fn job_status(
+    app: &App,
+    req: Request<BodyStruct>,
+    job_id: &str,
+    client_ip: IpAddr,
+) -> Result<Response<BodyStruct>, ErrorWithStatusAndDesc> {
+    let api_token = find_api_token(app, req.headers(), client_ip)?;
+    let project = &api_token.project;
+
+    // Задачи других проектов не показываем
+    let mut status = app
+        .async_uploads
+        .get(job_id)
+        .or_else(|| app.upload_queue.as_ref().and_then(|queue| queue.get(job_id)))
+        .filter(|status| status.project == project.name())
+        .wrap_err_with_status_desc(StatusCode::NOT_FOUND, "Job is not found".into())?;
+
+    // Уведомления могли дойти после завершения выгрузки
+    let report = app.notification_outbox.report(&status.project, &status.request_id);
+    status.notification_status.extend(report);
+
+    let json_text = serde_json::to_string(&status).wrap_err_with_500()?;
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.essence_str())
+        .header(header::CONTENT_LENGTH, json_text.len())
+        .body(BodyStruct::from(json_text))
+        .wrap_err_with_500()?;
+
+    Ok(response)
+}
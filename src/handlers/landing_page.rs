@@ -0,0 +1,60 @@
+use crate::{
+    error::{ErrorWithStatusAndDesc, WrapErrorWithStatusAndDesc},
+    project::QrRenderer,
+    types::App,
+};
+use hyper::{
+    body::Body as BodyStruct,
+    http::{header, StatusCode},
+    Response,
+};
+
+/// Отдаем страницу выгрузки или манифест установки IPA.
+/// Токен не нужен, адрес страницы знают только получатели ссылки.
+pub async fn landing_page(app: &App, page_path: &str) -> Result<Response<BodyStruct>, ErrorWithStatusAndDesc> {
+    let landing_pages = app
+        .landing_pages
+        .as_ref()
+        .wrap_err_with_status_desc(StatusCode::NOT_FOUND, "Landing pages are not configured".into())?;
+
+    let (id, manifest) = match page_path.strip_suffix("/manifest.plist") {
+        Some(id) => (id, true),
+        None => (page_path, false),
+    };
+    let page = landing_pages
+        .get(id)
+        .await
+        .wrap_err_with_500_desc("Landing page load failed".into())?
+        .wrap_err_with_status_desc(StatusCode::NOT_FOUND, "Page is not found".into())?;
+
+    let (content_type, text) = if manifest {
+        let manifest = page
+            .render_manifest()
+            .wrap_err_with_status_desc(StatusCode::NOT_FOUND, "Install manifest is not available".into())?;
+        ("application/xml", manifest)
+    } else {
+        // Проект могли удалить, тогда рисуем QR код с настройками по умолчанию
+        let project = app.state.load().projects.get(&page.project).cloned();
+        let default_qr;
+        let qr = match &project {
+            Some(project) => project.qr(),
+            None => {
+                default_qr = QrRenderer::new(None).wrap_err_with_500()?;
+                &default_qr
+            }
+        };
+        let html = page
+            .render_html(&landing_pages.page_url(&page.id), qr)
+            .wrap_err_with_500_desc("Landing page render failed".into())?;
+        ("text/html; charset=utf-8", html)
+    };
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::CONTENT_LENGTH, text.len())
+        .body(BodyStruct::from(text))
+        .wrap_err_with_500()?;
+
+    Ok(response)
+}
@@ -1,4 +1,10 @@
-mod router;
+mod admin;
+mod api_token;
 mod file_upload;
+mod jobs;
+mod landing_page;
+mod qr;
+mod quota;
+mod router;
 
-pub use self::router::handle_request;
+pub use self::{admin::handle_admin_request, router::handle_request};
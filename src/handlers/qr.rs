@@ -0,0 +1,83 @@
+use crate::{
+    error::{ErrorWithStatusAndDesc, WrapErrorWithStatusAndDesc},
+    project::Project,
+    types::App,
+};
+use hyper::{
+    body::Body as BodyStruct,
+    http::{header, StatusCode},
+    Request, Response,
+};
+use serde::Deserialize;
+
+/// Вид QR кода в ответе на выгрузку
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QrResponseMode {
+    /// Картинка в виде data: URI
+    Base64,
+    /// Адрес картинки на эндпоинте /qr
+    Url,
+}
+
+/// Имя файла из ссылки для подписи под кодом
+fn link_file_name(link: &str) -> &str {
+    link.rsplit('/').next().unwrap_or_default()
+}
+
+/// Значение поля qr_code ответа
+pub fn qr_code_value(
+    project: &Project,
+    public_url: Option<&str>,
+    link: &str,
+    mode: QrResponseMode,
+) -> Result<String, ErrorWithStatusAndDesc> {
+    match mode {
+        QrResponseMode::Base64 => {
+            let image = project
+                .qr()
+                .render(link, link_file_name(link))
+                .wrap_err_with_500_desc("QR code create failed".into())?;
+            Ok(format!("data:{};base64,{}", image.mime.essence_str(), base64::encode(image.data)))
+        }
+        // Без внешнего адреса отдаем путь относительно прокси
+        QrResponseMode::Url => Ok(format!(
+            "{}/qr?link={}",
+            public_url.unwrap_or_default().trim_end_matches('/'),
+            urlencoding::encode(link)
+        )),
+    }
+}
+
+/// Отдаем QR код для ссылки на файл, выгруженный одним из проектов.
+/// Токен не нужен, чтобы картинку можно было вставить прямо в страницу.
+pub async fn qr_code(app: &App, req: Request<BodyStruct>) -> Result<Response<BodyStruct>, ErrorWithStatusAndDesc> {
+    #[derive(Debug, Deserialize)]
+    struct Query {
+        link: String,
+    }
+    let query: Query = serde_qs::from_str(req.uri().query().unwrap_or_default()).wrap_err_with_400_desc("Query parsing error".into())?;
+
+    // Чужие ссылки не рисуем
+    let state = app.state.load();
+    let project = state
+        .projects
+        .values()
+        .find(|project| project.owns_link(&query.link))
+        .wrap_err_with_status_desc(StatusCode::NOT_FOUND, "Link is not produced by this proxy".into())?;
+
+    let image = project
+        .qr()
+        .render(&query.link, link_file_name(&query.link))
+        .wrap_err_with_500_desc("QR code create failed".into())?;
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, image.mime.essence_str())
+        .header(header::CONTENT_LENGTH, image.data.len())
+        .header(header::CACHE_CONTROL, "public, max-age=86400")
+        .body(BodyStruct::from(image.data))
+        .wrap_err_with_500()?;
+
+    Ok(response)
+}
@@ -0,0 +1,44 @@
+use super::api_token::find_api_token;
+use crate::{
+    error::{ErrorWithStatusAndDesc, WrapErrorWithStatusAndDesc},
+    quota::QuotaUsage,
+    types::App,
+};
+use hyper::{
+    body::Body as BodyStruct,
+    http::{header, StatusCode},
+    Request, Response,
+};
+use serde::Serialize;
+use std::net::IpAddr;
+
+/// Отдаем текущее использование квот проекта
+pub async fn quota_usage(app: &App, req: Request<BodyStruct>, client_ip: IpAddr) -> Result<Response<BodyStruct>, ErrorWithStatusAndDesc> {
+    let api_token = find_api_token(app, req.headers(), client_ip)?;
+    let project = &api_token.project;
+
+    let usage = project
+        .quota_usage()
+        .wrap_err_with_status_desc(StatusCode::NOT_FOUND, "Quota is not configured for this project".into())?;
+
+    #[derive(Serialize)]
+    struct QuotaResponse<'a> {
+        project: &'a str,
+        #[serde(flatten)]
+        usage: QuotaUsage,
+    }
+    let json_text = serde_json::to_string(&QuotaResponse {
+        project: project.name(),
+        usage,
+    })
+    .wrap_err_with_500()?;
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.essence_str())
+        .header(header::CONTENT_LENGTH, json_text.len())
+        .body(BodyStruct::from(json_text))
+        .wrap_err_with_500()?;
+
+    Ok(response)
+}
@@ -1,10 +1,11 @@
-use super::file_upload::file_upload;
+use super::{file_upload::file_upload, jobs::job_status, landing_page::landing_page, qr::qr_code, quota::quota_usage};
 use crate::{error::ErrorWithStatusAndDesc, types::App};
 use hyper::{
     body::Body as BodyStruct,
     http::{method::Method, status::StatusCode},
     Request, Response,
 };
+use std::net::IpAddr;
 use tracing::{error, info, Instrument};
 
 // Специальная обертка чтобы сообщить на уровень выше нужно ли подсчитывать данный запрос
@@ -26,6 +27,7 @@ pub async fn handle_request(
     method: &Method,
     req: Request<BodyStruct>,
     request_id: &str,
+    client_ip: IpAddr,
 ) -> Result<Response<BodyStruct>, ErrorWithStatusAndDesc> {
     // debug!("Request processing begin");
     info!("Full request info: {:?}", req);
@@ -33,7 +35,25 @@ pub async fn handle_request(
     // Обрабатываем путь и метод
     match (method, path) {
         // Выгружаем данные в Cloud
-        (&Method::POST, "/upload_file") => file_upload(app, req, request_id).in_current_span().await.map(Into::into),
+        (&Method::POST, "/upload_file") => file_upload(app, req, request_id, client_ip).in_current_span().await.map(Into::into),
+
+        // Текущее использование квот проекта
+        (&Method::GET, "/quota") => quota_usage(app, req, client_ip).in_current_span().await,
+
+        // QR код ссылки на выгруженный файл
+        (&Method::GET, "/qr") => qr_code(app, req).in_current_span().await,
+
+        // Страница выгрузки и манифест установки IPA
+        (&Method::GET, path) if path.starts_with("/pages/") => {
+            let page_path = path.trim_start_matches("/pages/");
+            landing_page(app, page_path).in_current_span().await
+        }
+
+        // Состояние фоновой выгрузки
+        (&Method::GET, path) if path.starts_with("/jobs/") => {
+            let job_id = path.trim_start_matches("/jobs/");
+            job_status(app, req, job_id, client_ip).in_current_span().await
+        }
 
         // Любой другой запрос
         _ => {
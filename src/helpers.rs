@@ -59,6 +59,14 @@ pub fn get_required_str_header<'a>(headers: &'a HeaderMap, key: &str) -> Result<
     Ok(val)
 }
 
+/// Сравнение секретов за время, не зависящее от позиции первого отличия
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 /*pub fn response_with_status_and_empty_body(status: StatusCode) -> Response<BodyStruct> {
     Response::builder()
         .status(status)
@@ -87,3 +95,11 @@ pub fn response_with_status_desc_and_trace_id(status: StatusCode, err_desc: &str
         .body(BodyStruct::from(error_json))
         .expect("Static fail response create failed") // Статически создаем ответ, здесь не критично
 }
+
+/// Экранирование текста для вставки в HTML и XML, в том числе в значения атрибутов
+pub fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,351 @@
+use crate::{app_config::LandingPagesConfig, helpers::escape_markup, project::QrRenderer};
+use chrono::TimeZone;
+use eyre::WrapErr;
+use minijinja::Environment;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tracing::{debug, warn};
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Шаблон страницы, экранирование HTML включается по расширению имени
+const PAGE_TEMPLATE_NAME: &str = "landing_page.html";
+const PAGE_TEMPLATE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{{ file_name }}</title>
+<style>
+body { font-family: -apple-system, Roboto, sans-serif; max-width: 480px; margin: 0 auto; padding: 16px; color: #222; }
+h1 { font-size: 20px; word-break: break-all; }
+.button { display: block; margin: 12px 0; padding: 14px; border-radius: 8px; background: #1a73e8; color: #fff; text-align: center; text-decoration: none; font-weight: bold; }
+.qr { display: block; margin: 16px auto; max-width: 100%; }
+table { border-collapse: collapse; width: 100%; }
+td { padding: 6px 8px; border-bottom: 1px solid #eee; word-break: break-all; }
+td:first-child { color: #666; white-space: nowrap; }
+</style>
+</head>
+<body>
+<h1>{{ file_name }}</h1>
+{% if install_link %}<a class="button" href="{{ install_link }}">Install</a>
+{% endif %}<a class="button" href="{{ download_link }}">Download</a>
+<img class="qr" src="{{ qr_code }}" alt="QR code">
+<table>
+<tr><td>Project</td><td>{{ project }}</td></tr>
+<tr><td>Size</td><td>{{ size }}</td></tr>
+<tr><td>Uploaded</td><td>{{ uploaded_at }}</td></tr>
+{% for key, value in metadata|items %}<tr><td>{{ key }}</td><td>{{ value }}</td></tr>
+{% endfor %}</table>
+</body>
+</html>
+"#;
+
+/// Как часто удаляем устаревшие страницы
+const CLEANUP_PERIOD: Duration = Duration::from_secs(60 * 60);
+
+/// Ключи метаданных запроса для манифеста установки IPA
+const BUNDLE_ID_KEY: &str = "bundle_id";
+const BUNDLE_VERSION_KEY: &str = "bundle_version";
+
+/// Тип мобильной сборки по расширению файла
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BuildKind {
+    Apk,
+    Ipa,
+}
+
+impl BuildKind {
+    fn from_file_name(file_name: &str) -> Option<BuildKind> {
+        let file_name = file_name.to_ascii_lowercase();
+        if file_name.ends_with(".apk") {
+            Some(BuildKind::Apk)
+        } else if file_name.ends_with(".ipa") {
+            Some(BuildKind::Ipa)
+        } else {
+            None
+        }
+    }
+}
+
+/// Размер для людей
+fn format_size(size: u64) -> String {
+    const MIB: u64 = 1024 * 1024;
+    if size >= MIB {
+        format!("{:.1} MiB", size as f64 / MIB as f64)
+    } else if size >= 1024 {
+        format!("{:.1} KiB", size as f64 / 1024.0)
+    } else {
+        format!("{} B", size)
+    }
+}
+
+/// Id страницы используется в имени файла, поэтому принимаем только uuid
+fn is_valid_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|v| v.is_ascii_hexdigit())
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Описание выгрузки для страницы, сохраняется на диск
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LandingPage {
+    pub id: String,
+    pub project: String,
+    pub request_id: String,
+    pub file_name: String,
+    pub size: u64,
+    /// Unix timestamp выгрузки
+    pub uploaded_at: i64,
+    pub metadata: BTreeMap<String, String>,
+    pub download_link: String,
+}
+
+impl LandingPage {
+    /// Манифест установки по воздуху есть только у IPA с известным bundle id
+    pub fn has_manifest(&self) -> bool {
+        BuildKind::from_file_name(&self.file_name) == Some(BuildKind::Ipa) && self.metadata.contains_key(BUNDLE_ID_KEY)
+    }
+
+    /// Манифест для itms-services://, ссылка на IPA должна открываться без авторизации
+    pub fn render_manifest(&self) -> Option<String> {
+        if !self.has_manifest() {
+            return None;
+        }
+        let bundle_id = self.metadata.get(BUNDLE_ID_KEY)?;
+        let bundle_version = self.metadata.get(BUNDLE_VERSION_KEY).map(String::as_str).unwrap_or("1.0");
+        Some(format!(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>items</key>
+    <array>
+        <dict>
+            <key>assets</key>
+            <array>
+                <dict>
+                    <key>kind</key>
+                    <string>software-package</string>
+                    <key>url</key>
+                    <string>{}</string>
+                </dict>
+            </array>
+            <key>metadata</key>
+            <dict>
+                <key>bundle-identifier</key>
+                <string>{}</string>
+                <key>bundle-version</key>
+                <string>{}</string>
+                <key>kind</key>
+                <string>software</string>
+                <key>title</key>
+                <string>{}</string>
+            </dict>
+        </dict>
+    </array>
+</dict>
+</plist>
+"#,
+            escape_markup(&self.download_link),
+            escape_markup(bundle_id),
+            escape_markup(bundle_version),
+            escape_markup(&self.file_name)
+        ))
+    }
+
+    /// HTML страница с QR кодом адреса самой страницы
+    pub fn render_html(&self, page_url: &str, qr: &QrRenderer) -> Result<String, eyre::Error> {
+        let qr_image = qr.render(page_url, &self.file_name).wrap_err("QR code create failed")?;
+        let install_link = self.has_manifest().then(|| {
+            format!(
+                "itms-services://?action=download-manifest&url={}",
+                urlencoding::encode(&format!("{}/manifest.plist", page_url))
+            )
+        });
+        let uploaded_at = chrono::Utc
+            .timestamp_opt(self.uploaded_at, 0)
+            .single()
+            .map(|time| time.format("%Y-%m-%d %H:%M UTC").to_string())
+            .unwrap_or_default();
+
+        let mut env = Environment::new();
+        env.add_template(PAGE_TEMPLATE_NAME, PAGE_TEMPLATE)
+            .wrap_err("Page template parsing failed")?;
+        env.get_template(PAGE_TEMPLATE_NAME)
+            .and_then(|template| {
+                template.render(minijinja::context! {
+                    project => &self.project,
+                    file_name => &self.file_name,
+                    size => format_size(self.size),
+                    uploaded_at => uploaded_at,
+                    metadata => &self.metadata,
+                    download_link => &self.download_link,
+                    install_link => install_link,
+                    qr_code => format!("data:{};base64,{}", qr_image.mime.essence_str(), base64::encode(qr_image.data)),
+                })
+            })
+            .wrap_err("Page template render failed")
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Страницы выгрузок мобильных сборок.
+/// Описания лежат на диске, чтобы ссылки из мессенджеров работали после перезапуска.
+pub struct LandingPages {
+    dir: PathBuf,
+    public_url: String,
+    keep_days: u64,
+}
+
+impl LandingPages {
+    pub fn open(config: LandingPagesConfig, public_url: &str) -> Result<LandingPages, eyre::Error> {
+        std::fs::create_dir_all(&config.dir).wrap_err_with(|| format!("Landing pages dir {} create failed", config.dir.display()))?;
+        Ok(LandingPages {
+            dir: config.dir,
+            public_url: public_url.trim_end_matches('/').to_owned(),
+            keep_days: config.keep_days,
+        })
+    }
+
+    fn page_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", id))
+    }
+
+    /// Внешний адрес страницы
+    pub fn page_url(&self, id: &str) -> String {
+        format!("{}/pages/{}", self.public_url, id)
+    }
+
+    /// Создаем страницу для APK и IPA, возвращаем ее адрес
+    pub async fn create(
+        &self,
+        project: &str,
+        request_id: &str,
+        file_name: &str,
+        size: u64,
+        metadata: &BTreeMap<String, String>,
+        download_link: &str,
+    ) -> Result<Option<String>, eyre::Error> {
+        if BuildKind::from_file_name(file_name).is_none() {
+            return Ok(None);
+        }
+
+        let page = LandingPage {
+            id: uuid::Uuid::new_v4().to_simple().to_string(),
+            project: project.to_owned(),
+            request_id: request_id.to_owned(),
+            file_name: file_name.to_owned(),
+            size,
+            uploaded_at: chrono::Utc::now().timestamp(),
+            metadata: metadata.clone(),
+            download_link: download_link.to_owned(),
+        };
+        let data = serde_json::to_vec(&page).wrap_err("Landing page serialize failed")?;
+        let path = self.page_path(&page.id);
+        let tmp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, data).await.wrap_err("Landing page write failed")?;
+        tokio::fs::rename(&tmp_path, &path).await.wrap_err("Landing page rename failed")?;
+
+        Ok(Some(self.page_url(&page.id)))
+    }
+
+    /// Описание страницы, если она есть
+    pub async fn get(&self, id: &str) -> Result<Option<LandingPage>, eyre::Error> {
+        if !is_valid_id(id) {
+            return Ok(None);
+        }
+        let data = match tokio::fs::read(self.page_path(id)).await {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err).wrap_err("Landing page read failed"),
+        };
+        let page = serde_json::from_slice(&data).wrap_err("Landing page parse failed")?;
+        Ok(Some(page))
+    }
+
+    /// Удаляем страницы старше keep_days, файлы страниц после создания не меняются
+    async fn cleanup_expired(&self) -> Result<usize, eyre::Error> {
+        let keep = Duration::from_secs(self.keep_days * 24 * 60 * 60);
+        let expire_time = SystemTime::now().checked_sub(keep).unwrap_or(SystemTime::UNIX_EPOCH);
+        let mut removed = 0;
+        let mut dir = tokio::fs::read_dir(&self.dir).await.wrap_err("Landing pages dir read failed")?;
+        while let Some(entry) = dir.next_entry().await.wrap_err("Landing pages dir read failed")? {
+            let modified = entry.metadata().await.and_then(|metadata| metadata.modified());
+            if matches!(modified, Ok(modified) if modified < expire_time) {
+                tokio::fs::remove_file(entry.path()).await.ok();
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Фоновое удаление устаревших страниц
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            match self.cleanup_expired().await {
+                Ok(0) => {}
+                Ok(removed) => debug!("Expired landing pages removed: {}", removed),
+                Err(err) => warn!("Landing pages cleanup failed: {:#}", err),
+            }
+            tokio::time::sleep(CLEANUP_PERIOD).await;
+        }
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_landing_page() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = LandingPagesConfig {
+            dir: dir.path().to_owned(),
+            keep_days: 30,
+        };
+        let pages = LandingPages::open(config, "https://proxy.example.com/").unwrap();
+        let metadata: BTreeMap<String, String> = vec![("bundle_id".to_owned(), "com.example.<app>".to_owned())].into_iter().collect();
+
+        let created = pages
+            .create("project", "REQUEST", "notes.txt", 10, &metadata, "https://storage/notes.txt")
+            .await
+            .unwrap();
+        assert!(created.is_none());
+
+        let page_url = pages
+            .create("project", "REQUEST", "App.ipa", 10, &metadata, "https://storage/App.ipa")
+            .await
+            .unwrap()
+            .unwrap();
+        let id = page_url.strip_prefix("https://proxy.example.com/pages/").unwrap();
+        assert!(pages.get("../secret").await.unwrap().is_none());
+        let page = pages.get(id).await.unwrap().unwrap();
+
+        let manifest = page.render_manifest().unwrap();
+        assert!(manifest.contains("<string>com.example.&lt;app&gt;</string>"));
+        assert!(manifest.contains("<string>https://storage/App.ipa</string>"));
+
+        let html = page.render_html(&page_url, &QrRenderer::new(None).unwrap()).unwrap();
+        // Атрибуты экранируются целиком, браузер раскрывает сущности обратно
+        assert!(html.contains("itms-services:&#x2f;&#x2f;?action=download-manifest&amp;url=https%3A%2F%2Fproxy.example.com%2Fpages%2F"));
+        assert!(html.contains("com.example.&lt;app&gt;"));
+        assert!(html.contains("data:image&#x2f;png;base64,"));
+        assert!(html.contains("<td>10 B</td>"));
+
+        // Свежие страницы остаются, без срока хранения удаляются все
+        assert_eq!(pages.cleanup_expired().await.unwrap(), 0);
+        let pages = LandingPages { keep_days: 0, ..pages };
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(pages.cleanup_expired().await.unwrap(), 1);
+        assert!(pages.get(id).await.unwrap().is_none());
+    }
+}
@@ -1,35 +1,57 @@
 mod app_arguments;
 mod app_config;
+mod async_uploads;
 mod auth_token_provider;
+mod client_ip;
+mod commands;
+mod config_interpolation;
+mod config_reload;
 mod error;
 mod handlers;
 mod helpers;
+mod landing_pages;
+mod message_template;
+mod notification_outbox;
 mod oauth2;
+mod persistent_queue;
 mod project;
+mod projects_state;
 mod prometheus;
+mod quota;
+mod rate_limit;
+mod retry;
+mod token_provider_registry;
 mod types;
+mod upload_queue;
 
 use self::{
-    app_arguments::AppArguments,
+    app_arguments::{AppArguments, AppCommand},
     app_config::Config,
-    handlers::handle_request,
+    client_ip::resolve_client_ip,
+    commands::{check_config, print_config},
+    config_reload::watch_config_changes,
+    handlers::{handle_admin_request, handle_request},
     helpers::{response_with_status_and_error, response_with_status_desc_and_trace_id},
-    project::Project,
+    landing_pages::LandingPages,
+    notification_outbox::NotificationOutbox,
+    projects_state::ProjectsFactory,
     prometheus::{count_request, count_request_time, count_response_status, prometheus_metrics},
     types::{App, HttpClient},
+    upload_queue::UploadQueue,
 };
+use arc_swap::ArcSwap;
 use error::{ErrorWithStatusAndDesc, WrapErrorWithStatusAndDesc};
 use eyre::WrapErr;
 use futures::FutureExt;
 use hyper::{
     body::Body as BodyStruct,
-    http::{Method, StatusCode},
+    http::{header, HeaderValue, Method, StatusCode},
     server::{conn::AddrStream, Server},
     service::{make_service_fn, service_fn},
     Client, Request, Response,
 };
 use hyper_rustls::HttpsConnector;
-use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Arc};
+use std::{convert::Infallible, future::Future, net::SocketAddr, sync::Arc};
 use structopt::StructOpt;
 use tracing::{debug, error, Instrument};
 
@@ -98,7 +120,14 @@ fn unwrap_result_to_response_with_trace_id(
             error!("{}", err);
 
             // Ответ в виде ошибки
-            response_with_status_desc_and_trace_id(err.status, &err.desc, trace_id)
+            let mut response = response_with_status_desc_and_trace_id(err.status, &err.desc, trace_id);
+
+            // Подсказка клиенту когда можно повторить
+            if let Some(retry_after) = err.retry_after {
+                response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(retry_after));
+            }
+
+            response
         }
     }
 }
@@ -118,7 +147,7 @@ fn unwrap_result_to_response(res: Result<Response<BodyStruct>, ErrorWithStatusAn
 }
 
 /// Непосредственно обработчик запроса без внешней мишуры
-async fn process_req(app: Arc<App>, req: Request<BodyStruct>) -> Response<BodyStruct> {
+async fn process_req(app: Arc<App>, remote_addr: SocketAddr, req: Request<BodyStruct>) -> Response<BodyStruct> {
     let method = req.method();
     let path = req.uri().path().trim_end_matches('/');
 
@@ -130,11 +159,17 @@ async fn process_req(app: Arc<App>, req: Request<BodyStruct>) -> Response<BodySt
 
         // Работоспособность сервиса, тоже не учитываем в статистике
         (&Method::GET, "/health") => {
-            // Пустой ответ со статусом 200
-            let resp = hyper::Response::builder()
-                .status(StatusCode::OK)
-                .body(BodyStruct::empty())
-                .wrap_err_with_500_desc("Empty body struct build".into());
+            // Статус 200 + состояние получения токенов по каждому сервисному аккаунту
+            let credentials = app.factory.token_providers().statuses();
+            let resp = serde_json::to_string(&serde_json::json!({ "credentials": credentials }))
+                .wrap_err_with_500_desc("Health json serialize".into())
+                .and_then(|json_text| {
+                    hyper::Response::builder()
+                        .status(StatusCode::OK)
+                        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.essence_str())
+                        .body(BodyStruct::from(json_text))
+                        .wrap_err_with_500_desc("Health body build".into())
+                });
             unwrap_result_to_response(resp)
         }
 
@@ -143,9 +178,13 @@ async fn process_req(app: Arc<App>, req: Request<BodyStruct>) -> Response<BodySt
             // Создаем идентификатор трассировки для отслеживания ошибок в общих логах
             let request_id = format!("{:x}", uuid::Uuid::new_v4());
 
+            // Реальный адрес клиента с учетом доверенных проксей
+            let client_ip = resolve_client_ip(remote_addr.ip(), req.headers(), &app.state.load().config.settings.trusted_proxies);
+
             // Создаем span с идентификатором трассировки
             let span = tracing::error_span!("request", 
-                %request_id);
+                %request_id,
+                %client_ip);
             let _entered_span = span.enter();
 
             // Увеличиваем общий счетчик запросов
@@ -164,7 +203,9 @@ async fn process_req(app: Arc<App>, req: Request<BodyStruct>) -> Response<BodySt
             // Обработка сервиса
             let response = {
                 // Для асинхронщины обязательно проставляем текущий span для трассиовки
-                let response_res = handle_request(&app, path, method, req, &request_id).in_current_span().await;
+                let response_res = handle_request(&app, path, method, req, &request_id, client_ip)
+                    .in_current_span()
+                    .await;
                 unwrap_result_to_response_with_trace_id(response_res, &request_id)
             };
 
@@ -179,11 +220,26 @@ async fn process_req(app: Arc<App>, req: Request<BodyStruct>) -> Response<BodySt
     }
 }
 
-// Стартуем сервер
-async fn run_server(port: u16, app: App) -> Result<(), eyre::Error> {
-    // Перемещаем в кучу для свободного доступа из разных обработчиков
-    let app = Arc::new(app);
+/// Обработчик запросов API администрирования
+async fn process_admin_req(app: Arc<App>, remote_addr: SocketAddr, req: Request<BodyStruct>) -> Response<BodyStruct> {
+    // Создаем идентификатор трассировки для отслеживания ошибок в общих логах
+    let request_id = format!("{:x}", uuid::Uuid::new_v4());
 
+    // Реальный адрес клиента с учетом доверенных проксей
+    let client_ip = resolve_client_ip(remote_addr.ip(), req.headers(), &app.state.load().config.settings.trusted_proxies);
+
+    let span = tracing::error_span!("admin_request", %request_id, %client_ip);
+
+    let response_res = handle_admin_request(&app, req, client_ip).instrument(span).await;
+    unwrap_result_to_response_with_trace_id(response_res, &request_id)
+}
+
+// Стартуем сервер на порту с указанным обработчиком
+async fn serve<H, F>(port: u16, app: Arc<App>, handler: H) -> Result<(), eyre::Error>
+where
+    H: Fn(Arc<App>, SocketAddr, Request<BodyStruct>) -> F + Clone + Send + Sync + 'static,
+    F: Future<Output = Response<BodyStruct>> + Send + 'static,
+{
     // Адрес
     let addr = SocketAddr::from(([0, 0, 0, 0], port)); // TODO: ???
 
@@ -191,15 +247,17 @@ async fn run_server(port: u16, app: App) -> Result<(), eyre::Error> {
     let root_span = tracing::trace_span!("root");
 
     // Сервис необходим для каждого соединения, поэтому создаем враппер, который будет генерировать наш сервис
-    let make_svc = make_service_fn(move |_: &AddrStream| {
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
         let app = app.clone();
+        let handler = handler.clone();
+        let remote_addr = conn.remote_addr();
         async move {
             // Создаем сервис из функции с помощью service_fn
             Ok::<_, Infallible>(service_fn(move |req| {
                 let app = app.clone();
 
                 // Обработка запроса, мапим результат в infallible тип
-                process_req(app, req).map(Ok::<_, Infallible>).in_current_span()
+                handler(app, remote_addr, req).map(Ok::<_, Infallible>).in_current_span()
             }))
         }
         .instrument(root_span.clone())
@@ -229,6 +287,52 @@ async fn run_server(port: u16, app: App) -> Result<(), eyre::Error> {
     Ok(())
 }
 
+// Стартуем основной сервер и сервер администрирования если он включен
+async fn run_server(app: App) -> Result<(), eyre::Error> {
+    // Перемещаем в кучу для свободного доступа из разных обработчиков
+    let app = Arc::new(app);
+
+    // Перезагрузка конфига по сигналу и изменению файла
+    tokio::spawn({
+        let app = app.clone();
+        async move {
+            if let Err(err) = watch_config_changes(app).await {
+                error!("Config reload watching failed: {:#}", err);
+            }
+        }
+    });
+
+    let (port, admin_port) = {
+        let state = app.state.load();
+        let settings = &state.config.settings;
+        (settings.port, settings.admin.as_ref().map(|v| v.port))
+    };
+
+    // Фоновая выгрузка из очереди на диске
+    if let Some(upload_queue) = app.upload_queue.clone() {
+        tokio::spawn(upload_queue.run(app.clone()));
+    }
+
+    // Фоновые повторы уведомлений
+    tokio::spawn(app.notification_outbox.clone().run(app.clone()));
+
+    // Удаление устаревших страниц выгрузок
+    if let Some(landing_pages) = app.landing_pages.clone() {
+        tokio::spawn(landing_pages.run());
+    }
+
+    let main_server = serve(port, app.clone(), process_req);
+    match admin_port {
+        Some(admin_port) => {
+            let admin_server = serve(admin_port, app, process_admin_req);
+            futures::try_join!(main_server, admin_server)?;
+        }
+        None => main_server.await?,
+    }
+
+    Ok(())
+}
+
 fn build_http_client() -> HttpClient {
     // Коннектор для работы уже с HTTPS
     let https_connector = HttpsConnector::with_native_roots();
@@ -253,8 +357,33 @@ fn main() {
     // Проверка аргументов приложения
     app_arguments.validate_arguments().expect("Invalid argument");
 
+    // Создаем рантайм для работы сервера
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("Tokio runtime build");
+
+    // Служебные команды вместо запуска сервера
+    match app_arguments.command {
+        Some(AppCommand::CheckConfig { online }) => {
+            let success = runtime.block_on(check_config(&app_arguments.config, online, build_http_client()));
+            if !success {
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(AppCommand::PrintConfig) => {
+            print_config(&app_arguments.config).expect("Config print failed");
+            return;
+        }
+        None => {}
+    }
+
+    // Проекты запускают фоновые задачи, поэтому создаются внутри рантайма
+    let _runtime_guard = runtime.enter();
+
     // Загружаем файлик конфига
-    let config = Config::parse_from_file(app_arguments.config).expect("Config load failed");
+    let config = Config::parse_from_file(&app_arguments.config).expect("Config load failed");
 
     // Клиентs для https
     // Клиенты разные, так как каждой библиотеке требуется своего типа клиент
@@ -262,33 +391,43 @@ fn main() {
     let http_client_high_level = reqwest::Client::new();
 
     // Создаем объекты проектов для всего из конфига
-    let projects = {
-        let mut projects = HashMap::with_capacity(config.projects.len());
-        for (index, config) in config.projects.into_iter().enumerate() {
-            debug!("Project {} config: {:?}", index, config);
-            let proj = Project::new(
-                config.google_storage_target,
-                config.slack_link_dub,
-                http_client_low_level.clone(),
-                http_client_high_level.clone(),
-            )
-            .expect("Project object create error");
-            projects.insert(config.api_token, proj);
-        }
-        projects
+    // Страницы выгрузок живут по внешнему адресу прокси
+    let landing_pages = match (config.settings.landing_pages.clone(), config.settings.public_url.as_deref()) {
+        (Some(landing_pages), Some(public_url)) => Some(Arc::new(
+            LandingPages::open(landing_pages, public_url).expect("Landing pages open failed"),
+        )),
+        _ => None,
     };
+    let factory = ProjectsFactory::new(
+        http_client_low_level,
+        http_client_high_level,
+        config.settings.state_dir.clone(),
+        landing_pages.clone(),
+    );
+    let upload_queue = config
+        .settings
+        .spool
+        .clone()
+        .map(|spool| UploadQueue::open(spool).map(Arc::new))
+        .transpose()
+        .expect("Upload queue open failed");
+    let notification_outbox = NotificationOutbox::open(config.settings.notification_outbox.clone(), config.settings.state_dir.as_deref())
+        .map(Arc::new)
+        .expect("Notification outbox open failed");
+    let state = factory.build(config).expect("Project object create error");
 
     // Контейнер со всеми менеджерами и тд
-    let app = App { projects };
-
-    // Создаем рантайм для работы сервера
-    let runtime = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .expect("Tokio runtime build");
+    let app = App {
+        state: ArcSwap::from_pointee(state),
+        factory,
+        config_path: app_arguments.config,
+        state_update_lock: Default::default(),
+        upload_queue,
+        async_uploads: Default::default(),
+        notification_outbox,
+        landing_pages,
+    };
 
     // Стартуем сервер
-    runtime
-        .block_on(run_server(config.settings.port, app))
-        .expect("Server running fail");
+    runtime.block_on(run_server(app)).expect("Server running fail");
 }
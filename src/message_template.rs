@@ -0,0 +1,68 @@
+use eyre::WrapErr;
+use minijinja::Environment;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Значения, доступные в шаблонах сообщений
+#[derive(Debug, Serialize)]
+pub struct TemplateContext<'a> {
+    pub project: &'a str,
+    pub link: &'a str,
+    pub file_name: &'a str,
+    /// Размер входных данных из Content-Length
+    pub size: u64,
+    /// Метка токена, которым выполнена выгрузка
+    pub token_label: &'a str,
+    pub request_id: &'a str,
+    /// Метаданные из запроса, например номер сборки и ветка
+    pub metadata: &'a BTreeMap<String, String>,
+    /// Текст сообщения, используется в шаблонах писем
+    pub text: &'a str,
+}
+
+/// Проверка синтаксиса шаблона при загрузке конфига
+pub fn check_template(template: &str) -> Result<(), eyre::Error> {
+    let env = Environment::new();
+    env.template_from_str(template).wrap_err("Template parsing failed")?;
+    Ok(())
+}
+
+/// Шаблоны MiniJinja: {{ file_name }}, {{ metadata.build }}, {% if ... %}
+pub fn render_template(template: &str, context: &TemplateContext) -> Result<String, eyre::Error> {
+    let env = Environment::new();
+    env.render_str(template, context).wrap_err("Template render failed")
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_template() {
+        let metadata = [("build".to_owned(), "42".to_owned()), ("branch".to_owned(), "main".to_owned())]
+            .into_iter()
+            .collect();
+        let context = TemplateContext {
+            project: "project",
+            link: "https://storage/app.apk",
+            file_name: "app.apk",
+            size: 100,
+            token_label: "ci",
+            request_id: "REQUEST",
+            metadata: &metadata,
+            text: "",
+        };
+        let text = render_template(
+            "{{ file_name }} #{{ metadata.build }} ({{ metadata.branch }}) by {{ token_label }}: {{ link }}{% if metadata.missing %}!{% endif %}",
+            &context,
+        )
+        .unwrap();
+        assert_eq!(text, "app.apk #42 (main) by ci: https://storage/app.apk");
+
+        assert!(check_template("{{ link }").is_err());
+    }
+}
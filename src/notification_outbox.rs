@@ -0,0 +1,353 @@
+use crate::{
+    app_config::NotificationOutboxConfig,
+    error::ErrorWithStatusAndDesc,
+    persistent_queue::{PersistentQueue, QueueEntry},
+    project::{NotificationTarget, Project, UploadParams, WEBHOOK_NOTIFIER},
+    types::App,
+    upload_queue::is_transient_error,
+};
+use hyper::{body::Bytes, http::StatusCode};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::Path, sync::Arc};
+use tracing::{error, info, warn, Instrument};
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Состояние уведомления отдельного получателя
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationStatus {
+    /// Ждет повтора
+    Queued,
+    Sent,
+    Failed,
+}
+
+/// Состояние уведомлений по ключу получателя
+pub type NotificationReport = BTreeMap<String, NotificationStatus>;
+
+/// Ссылка дошла до всех получателей
+pub fn all_sent(report: &NotificationReport) -> bool {
+    !report.is_empty() && report.values().all(|status| *status == NotificationStatus::Sent)
+}
+
+/// Уведомление для повтора, сохраняется на диск
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct OutboxEntry {
+    id: String,
+    project: String,
+    params: UploadParams,
+    link: String,
+    target: NotificationTarget,
+    status: NotificationStatus,
+    /// Unix timestamp создания
+    created_at: i64,
+    /// Unix timestamp последнего изменения
+    updated_at: i64,
+    attempts: u32,
+    /// Unix timestamp следующей попытки
+    next_attempt_at: i64,
+    error: Option<String>,
+    /// Тело вебхука, у уведомлений в мессенджеры его нет
+    #[serde(default)]
+    webhook_payload: Option<String>,
+}
+
+impl QueueEntry for OutboxEntry {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn is_pending(&self) -> bool {
+        self.status == NotificationStatus::Queued
+    }
+
+    fn is_finished(&self) -> bool {
+        self.status != NotificationStatus::Queued
+    }
+
+    fn created_at(&self) -> i64 {
+        self.created_at
+    }
+
+    fn updated_at(&self) -> i64 {
+        self.updated_at
+    }
+
+    fn set_updated_at(&mut self, updated_at: i64) {
+        self.updated_at = updated_at;
+    }
+
+    fn next_attempt_at(&self) -> i64 {
+        self.next_attempt_at
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Очередь уведомлений.
+/// Первая попытка выполняется сразу при ответе на запрос, неудачные повторяются в фоне.
+/// Файл для отправки вместе со ссылкой к повторам уже не прикладывается.
+pub struct NotificationOutbox {
+    config: NotificationOutboxConfig,
+    entries: PersistentQueue<OutboxEntry>,
+}
+
+impl NotificationOutbox {
+    /// Загружаем уведомления, оставшиеся с прошлого запуска.
+    /// Без отдельного каталога очередь хранится в каталоге состояния.
+    pub fn open(mut config: NotificationOutboxConfig, state_dir: Option<&Path>) -> Result<NotificationOutbox, eyre::Error> {
+        if config.dir.is_none() {
+            config.dir = state_dir.map(|dir| dir.join("notification_outbox"));
+        }
+        if config.dir.is_none() {
+            warn!("Notification outbox directory is not configured, pending notifications will be lost on restart");
+        }
+
+        let entries = PersistentQueue::open("Notification outbox", (&config).into())?;
+        Ok(NotificationOutbox { config, entries })
+    }
+
+    /// Отправляем ссылку всем получателям, неудачные отправки ставим в очередь
+    pub async fn send(&self, project: &Project, params: &UploadParams, link: &str, attachment: Option<&Bytes>) -> NotificationReport {
+        let targets = project.notification_targets(params);
+
+        let futures_iter = targets
+            .iter()
+            .map(|target| project.notify_target(params, link, target, attachment).in_current_span());
+        let results = futures::future::join_all(futures_iter).in_current_span().await;
+
+        let mut report = NotificationReport::new();
+        for (target, result) in targets.into_iter().zip(results) {
+            let key = target.key();
+            let status = match result {
+                Ok(()) => NotificationStatus::Sent,
+                Err(err) if self.config.max_attempts > 1 && is_transient_error(&err) => {
+                    let delay = self.entries.retry_delay_secs(1);
+                    warn!("Notification {} failed, retry in {} sec: {}", key, delay, err);
+                    match self.enqueue(project.name(), params, link, target, &err, delay).await {
+                        Ok(()) => NotificationStatus::Queued,
+                        Err(err) => {
+                            error!("Notification {} enqueue failed: {:#}", key, err);
+                            NotificationStatus::Failed
+                        }
+                    }
+                }
+                Err(err) => {
+                    error!("Notification {} failed: {}", key, err);
+                    NotificationStatus::Failed
+                }
+            };
+            report.insert(key, status);
+        }
+        report
+    }
+
+    async fn enqueue(
+        &self,
+        project: &str,
+        params: &UploadParams,
+        link: &str,
+        target: NotificationTarget,
+        err: &ErrorWithStatusAndDesc,
+        delay: i64,
+    ) -> Result<(), eyre::Error> {
+        let now = chrono::Utc::now().timestamp();
+        let entry = OutboxEntry {
+            id: uuid::Uuid::new_v4().to_simple().to_string(),
+            project: project.to_owned(),
+            params: params.clone(),
+            link: link.to_owned(),
+            target,
+            status: NotificationStatus::Queued,
+            created_at: now,
+            updated_at: now,
+            attempts: 1,
+            next_attempt_at: now + delay,
+            error: Some(err.to_string()),
+            webhook_payload: None,
+        };
+        self.entries.add(entry).await
+    }
+
+    /// Ставим вебхуки в очередь. Первая попытка тоже выполняется из очереди,
+    /// поэтому доставка переживает перезапуск и не задерживает ответ клиенту.
+    pub async fn enqueue_webhooks(&self, project: &str, params: &UploadParams, link: &str, urls: Vec<String>, payload: String) {
+        let now = chrono::Utc::now().timestamp();
+        for url in urls {
+            let entry = OutboxEntry {
+                id: uuid::Uuid::new_v4().to_simple().to_string(),
+                project: project.to_owned(),
+                params: params.clone(),
+                link: link.to_owned(),
+                target: NotificationTarget {
+                    notifier: WEBHOOK_NOTIFIER.to_owned(),
+                    target: url,
+                },
+                status: NotificationStatus::Queued,
+                created_at: now,
+                updated_at: now,
+                attempts: 0,
+                next_attempt_at: now,
+                error: None,
+                webhook_payload: Some(payload.clone()),
+            };
+            if let Err(err) = self.entries.add(entry).await {
+                error!("Webhook enqueue failed: {:#}", err);
+            }
+        }
+    }
+
+    /// Текущее состояние уведомлений из очереди для выгрузки.
+    /// Вебхуки в отчет не попадают, их адреса клиенту не показываем.
+    pub fn report(&self, project: &str, request_id: &str) -> NotificationReport {
+        self.entries.with_entries(|entries| {
+            entries
+                .values()
+                .filter(|entry| entry.webhook_payload.is_none())
+                .filter(|entry| entry.project == project && entry.params.request_id == request_id)
+                .map(|entry| (entry.target.key(), entry.status))
+                .collect()
+        })
+    }
+
+    /// Фоновые повторы уведомлений
+    pub async fn run(self: Arc<Self>, app: Arc<App>) {
+        self.entries
+            .run(|entry| {
+                let span = tracing::info_span!(
+                    "notification",
+                    notification_id = %entry.id,
+                    request_id = %entry.params.request_id,
+                    project = %entry.project
+                );
+                self.process(&app, entry).instrument(span)
+            })
+            .await;
+    }
+
+    async fn process(&self, app: &App, mut entry: OutboxEntry) {
+        entry.attempts += 1;
+
+        // Проект могли удалить или выключить, пока уведомление ждало
+        let project = app.state.load().projects.get(&entry.project).cloned();
+        let result = match (&project, &entry.webhook_payload) {
+            (Some(project), Some(payload)) => match project.webhook_sender() {
+                Some(webhook_sender) => {
+                    webhook_sender
+                        .deliver(&entry.target.target, &entry.params.request_id, payload)
+                        .in_current_span()
+                        .await
+                }
+                None => Err(ErrorWithStatusAndDesc::new_with_status_desc(
+                    StatusCode::NOT_FOUND,
+                    "Webhooks are not configured".into(),
+                )),
+            },
+            (Some(project), None) => {
+                project
+                    .notify_target(&entry.params, &entry.link, &entry.target, None)
+                    .in_current_span()
+                    .await
+            }
+            (None, _) => Err(ErrorWithStatusAndDesc::new_with_status_desc(
+                StatusCode::NOT_FOUND,
+                format!("Project {} is not available", entry.project).into(),
+            )),
+        };
+
+        let key = entry.target.key();
+        match result {
+            Ok(()) => {
+                info!("Notification {} sent after {} attempts", key, entry.attempts);
+                entry.status = NotificationStatus::Sent;
+                entry.error = None;
+            }
+            Err(err) => {
+                entry.error = Some(err.to_string());
+                if is_transient_error(&err) && entry.attempts < self.config.max_attempts {
+                    let delay = self.entries.retry_delay_secs(entry.attempts);
+                    warn!(
+                        "Notification {} attempt {} failed, retry in {} sec: {}",
+                        key, entry.attempts, delay, err
+                    );
+                    entry.next_attempt_at = chrono::Utc::now().timestamp() + delay;
+                } else {
+                    error!("Notification {} failed: {}", key, err);
+                    entry.status = NotificationStatus::Failed;
+
+                    // Недоставленный вебхук сохраняем для ручной отправки
+                    let webhook_sender = project.as_ref().and_then(|project| project.webhook_sender());
+                    if let (Some(webhook_sender), Some(payload)) = (webhook_sender, &entry.webhook_payload) {
+                        webhook_sender
+                            .dead_letter(&entry.target.target, &entry.params.request_id, payload, &err)
+                            .in_current_span()
+                            .await;
+                    }
+                }
+            }
+        }
+        self.entries.update(&mut entry).await;
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_outbox_report() {
+        let outbox = NotificationOutbox::open(Default::default(), None).unwrap();
+        let params = UploadParams::for_test("test.txt");
+        let target = NotificationTarget {
+            notifier: "slack".to_owned(),
+            target: "#releases".to_owned(),
+        };
+        let err = ErrorWithStatusAndDesc::new_with_status_desc(StatusCode::INTERNAL_SERVER_ERROR, "Slack error".into());
+        outbox.enqueue("project", &params, "https://link", target, &err, 0).await.unwrap();
+
+        let report = outbox.report("project", "REQUEST");
+        assert_eq!(report.get("slack:#releases"), Some(&NotificationStatus::Queued));
+        assert!(outbox.report("other", "REQUEST").is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_outbox_state_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let outbox = NotificationOutbox::open(Default::default(), Some(dir.path())).unwrap();
+        let target = NotificationTarget {
+            notifier: "slack".to_owned(),
+            target: "#releases".to_owned(),
+        };
+        let err = ErrorWithStatusAndDesc::new_with_status_desc(StatusCode::INTERNAL_SERVER_ERROR, "Slack error".into());
+        outbox
+            .enqueue("project", &UploadParams::for_test("test.txt"), "https://link", target, &err, 0)
+            .await
+            .unwrap();
+        outbox
+            .enqueue_webhooks(
+                "project",
+                &UploadParams::for_test("test.txt"),
+                "https://link",
+                vec!["https://ci.example.com/hook".to_owned()],
+                "{}".to_owned(),
+            )
+            .await;
+        drop(outbox);
+
+        // Неотправленные уведомления и вебхуки переживают перезапуск, адреса вебхуков в отчет не попадают
+        let outbox = NotificationOutbox::open(Default::default(), Some(dir.path())).unwrap();
+        let report = outbox.report("project", "REQUEST");
+        assert_eq!(report.len(), 1);
+        assert_eq!(report.get("slack:#releases"), Some(&NotificationStatus::Queued));
+        let webhooks = outbox.entries.with_entries(|entries| {
+            entries
+                .values()
+                .filter_map(|entry| entry.webhook_payload.as_ref().map(|_| entry.target.key()))
+                .collect::<Vec<_>>()
+        });
+        assert_eq!(webhooks, vec!["webhook:https://ci.example.com/hook".to_owned()]);
+    }
+}
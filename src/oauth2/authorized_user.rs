@@ -0,0 +1,53 @@
+use super::{get_token::request_token_data, request_builder, token_data::TokenData};
+use crate::types::HttpClient;
+use eyre::WrapErr;
+use hyper::{body::Body as BodyStruct, http::header, Method, Uri};
+use serde::Deserialize;
+use tracing::trace;
+
+fn default_token_uri() -> String {
+    "https://oauth2.googleapis.com/token".to_owned()
+}
+
+/// Данные пользователя с refresh токеном, например, после gcloud auth application-default login
+#[derive(Deserialize, Clone, PartialEq)]
+pub struct AuthorizedUserData {
+    pub client_id: String,
+    pub client_secret: String,
+    pub refresh_token: String,
+    #[serde(default = "default_token_uri")]
+    pub token_uri: String,
+}
+
+impl std::fmt::Debug for AuthorizedUserData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Секреты в логи не выводим
+        f.debug_struct("AuthorizedUserData")
+            .field("client_id", &self.client_id)
+            .field("token_uri", &self.token_uri)
+            .finish()
+    }
+}
+
+/// Получаем токен доступа по refresh токену
+pub async fn get_token_data_by_refresh_token(http_client: &HttpClient, user_data: &AuthorizedUserData) -> Result<TokenData, eyre::Error> {
+    let uri: Uri = user_data.token_uri.parse().wrap_err("Token uri parse failed")?;
+    trace!(?uri);
+
+    let body_data = format!(
+        "grant_type=refresh_token&client_id={}&client_secret={}&refresh_token={}",
+        urlencoding::encode(&user_data.client_id),
+        urlencoding::encode(&user_data.client_secret),
+        urlencoding::encode(&user_data.refresh_token)
+    );
+
+    let request = request_builder(Method::POST, uri)
+        .header(header::CONTENT_LENGTH, body_data.len())
+        .header(header::ACCEPT, mime::APPLICATION_JSON.essence_str())
+        .header(header::USER_AGENT, "hyper")
+        .header(header::CONTENT_TYPE, mime::APPLICATION_WWW_FORM_URLENCODED.essence_str())
+        .body(BodyStruct::from(body_data))
+        .wrap_err("Request build error")?;
+
+    request_token_data(http_client, request).await
+}
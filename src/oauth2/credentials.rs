@@ -0,0 +1,230 @@
+use super::{
+    authorized_user::{get_token_data_by_refresh_token, AuthorizedUserData},
+    external_account::{get_token_data_by_external_account, ExternalAccountData},
+    get_token::{build_self_signed_jwt, get_token_data},
+    impersonation::{generate_access_token, impersonation_url, service_account_from_url, CLOUD_PLATFORM_SCOPE},
+    metadata_server::{get_token_data_from_metadata, MetadataServerData, DEFAULT_METADATA_ENDPOINT},
+    service_account::ServiceAccountData,
+    token_data::TokenData,
+};
+use crate::types::HttpClient;
+use chrono::Duration;
+use eyre::WrapErr;
+use futures::{future::BoxFuture, FutureExt};
+use serde::Deserialize;
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+/// Тип JSON файла с креденшиалами, поле type
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialsType {
+    ServiceAccount,
+    AuthorizedUser,
+    ExternalAccount,
+    /// Файл gcloud auth application-default login --impersonate-service-account
+    ImpersonatedServiceAccount,
+}
+
+/// Источник токенов доступа Google
+#[derive(Debug, Clone, PartialEq)]
+pub enum Credentials {
+    /// JSON ключ сервисного аккаунта
+    ServiceAccount(ServiceAccountData),
+    /// Пользователь с refresh токеном
+    AuthorizedUser(AuthorizedUserData),
+    /// Метадата сервер GCE/GKE
+    MetadataServer(MetadataServerData),
+    /// Внешний OIDC токен, обмениваемый через STS
+    ExternalAccount(ExternalAccountData),
+    /// Токен другого сервисного аккаунта, полученный от имени исходных креденшиалов
+    Impersonated { source: Box<Credentials>, url: String },
+    /// Подписанный ключом сервисного аккаунта JWT используется как токен напрямую
+    SelfSignedJwt { data: ServiceAccountData, audience: String },
+}
+
+impl Credentials {
+    /// Читаем JSON файл, тип определяется полем type
+    pub fn from_file(path: &Path) -> Result<Credentials, eyre::Error> {
+        let text = std::fs::read_to_string(path).wrap_err_with(|| format!("Credentials file {} read failed", path.display()))?;
+        let value: Value = serde_json::from_str(&text).wrap_err("Credentials json parsing failed")?;
+        Credentials::from_value(value)
+    }
+
+    fn from_value(value: Value) -> Result<Credentials, eyre::Error> {
+        #[derive(Deserialize)]
+        struct TypeData {
+            #[serde(rename = "type")]
+            acc_type: CredentialsType,
+        }
+
+        #[derive(Deserialize)]
+        struct ImpersonatedData {
+            service_account_impersonation_url: String,
+            source_credentials: Value,
+        }
+
+        let type_data = TypeData::deserialize(&value).wrap_err("Credentials type parsing failed")?;
+        let credentials = match type_data.acc_type {
+            CredentialsType::ServiceAccount => {
+                Credentials::ServiceAccount(serde_json::from_value(value).wrap_err("Service account data parsing failed")?)
+            }
+            CredentialsType::AuthorizedUser => {
+                Credentials::AuthorizedUser(serde_json::from_value(value).wrap_err("Authorized user data parsing failed")?)
+            }
+            CredentialsType::ExternalAccount => {
+                let data: ExternalAccountData = serde_json::from_value(value).wrap_err("External account data parsing failed")?;
+                data.validate().wrap_err("Invalid external account data")?;
+                Credentials::ExternalAccount(data)
+            }
+            CredentialsType::ImpersonatedServiceAccount => {
+                let data: ImpersonatedData = serde_json::from_value(value).wrap_err("Impersonated account data parsing failed")?;
+                let source = Credentials::from_value(data.source_credentials).wrap_err("Source credentials parsing failed")?;
+                source.impersonate_url(data.service_account_impersonation_url)
+            }
+        };
+        Ok(credentials)
+    }
+
+    /// Метадата сервер с адресом из GCE_METADATA_HOST, если он задан
+    pub fn metadata_server(endpoint: Option<&str>, service_account: Option<&str>) -> Credentials {
+        let endpoint = match endpoint {
+            Some(endpoint) => endpoint.to_owned(),
+            None => match std::env::var("GCE_METADATA_HOST") {
+                Ok(host) => format!("http://{}", host),
+                Err(_) => DEFAULT_METADATA_ENDPOINT.to_owned(),
+            },
+        };
+        Credentials::MetadataServer(MetadataServerData {
+            endpoint,
+            service_account: service_account.unwrap_or("default").to_owned(),
+        })
+    }
+
+    /// Поиск Application Default Credentials в том же порядке, что и у библиотек Google:
+    /// GOOGLE_APPLICATION_CREDENTIALS, файл gcloud, метадата сервер
+    pub fn application_default() -> Result<Credentials, eyre::Error> {
+        if let Some(path) = std::env::var_os("GOOGLE_APPLICATION_CREDENTIALS") {
+            return Credentials::from_file(Path::new(&path)).wrap_err("GOOGLE_APPLICATION_CREDENTIALS file load failed");
+        }
+
+        let well_known_path = std::env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".config/gcloud/application_default_credentials.json"))
+            .filter(|path| path.is_file());
+        if let Some(path) = well_known_path {
+            return Credentials::from_file(&path).wrap_err("gcloud application default credentials load failed");
+        }
+
+        Ok(Credentials::metadata_server(None, None))
+    }
+
+    /// Токены будут выдаваться для указанного сервисного аккаунта
+    pub fn impersonate(self, service_account: &str) -> Credentials {
+        self.impersonate_url(impersonation_url(service_account))
+    }
+
+    /// Вместо обмена JWT на токен используем сам JWT с указанным aud,
+    /// поддерживается только для ключей сервисных аккаунтов
+    pub fn into_self_signed(self, audience: &str) -> Result<Credentials, eyre::Error> {
+        match self {
+            Credentials::ServiceAccount(data) => Ok(Credentials::SelfSignedJwt {
+                data,
+                audience: audience.to_owned(),
+            }),
+            other => Err(eyre::eyre!(
+                "Self-signed JWT requires service account key, got {}",
+                other.identity()
+            )),
+        }
+    }
+
+    fn impersonate_url(self, url: String) -> Credentials {
+        Credentials::Impersonated {
+            source: Box::new(self),
+            url,
+        }
+    }
+
+    /// Идентификатор аккаунта для логов, метрик и общего использования провайдеров
+    pub fn identity(&self) -> String {
+        match self {
+            Credentials::ServiceAccount(data) => data.client_email.clone(),
+            Credentials::AuthorizedUser(data) => format!("authorized_user:{}", data.client_id),
+            Credentials::MetadataServer(data) => format!("metadata:{}", data.service_account),
+            Credentials::ExternalAccount(data) => match data.service_account_impersonation_url.as_deref() {
+                Some(url) => format!("{} (via external:{})", service_account_from_url(url).unwrap_or(url), data.audience),
+                None => format!("external:{}", data.audience),
+            },
+            Credentials::Impersonated { source, url } => {
+                format!("{} (via {})", service_account_from_url(url).unwrap_or(url), source.identity())
+            }
+            Credentials::SelfSignedJwt { data, .. } => format!("{} (self-signed)", data.client_email),
+        }
+    }
+
+    /// Получаем новый токен
+    pub fn request_token<'a>(&'a self, http_client: &'a HttpClient, scopes: &'a str) -> BoxFuture<'a, Result<TokenData, eyre::Error>> {
+        async move {
+            match self {
+                Credentials::ServiceAccount(data) => get_token_data(http_client, data, scopes, Duration::minutes(60)).await,
+                Credentials::AuthorizedUser(data) => get_token_data_by_refresh_token(http_client, data).await,
+                Credentials::MetadataServer(data) => get_token_data_from_metadata(http_client, data, scopes).await,
+                Credentials::ExternalAccount(data) => get_token_data_by_external_account(http_client, data, scopes).await,
+                Credentials::Impersonated { source, url } => {
+                    let source_token = source
+                        .request_token(http_client, CLOUD_PLATFORM_SCOPE)
+                        .await
+                        .wrap_err("Source credentials token receive failed")?;
+                    generate_access_token(http_client, url, &source_token.access_token, scopes).await
+                }
+                Credentials::SelfSignedJwt { data, audience } => build_self_signed_jwt(data, audience, Duration::minutes(60)),
+            }
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_credentials_type() {
+        let value = serde_json::json!({
+            "type": "external_account",
+            "audience": "//iam.googleapis.com/projects/1/locations/global/workloadIdentityPools/pool/providers/github",
+            "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+            "service_account_impersonation_url":
+                "https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/uploader@proj.iam.gserviceaccount.com:generateAccessToken",
+            "credential_source": {"file": "/var/run/token", "format": {"type": "text"}}
+        });
+        let credentials = Credentials::from_value(value).unwrap();
+        assert!(matches!(credentials, Credentials::ExternalAccount(_)));
+        assert!(credentials
+            .identity()
+            .starts_with("uploader@proj.iam.gserviceaccount.com (via external:"));
+
+        let value = serde_json::json!({
+            "type": "impersonated_service_account",
+            "service_account_impersonation_url":
+                "https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/target@proj.iam.gserviceaccount.com:generateAccessToken",
+            "source_credentials": {"type": "authorized_user", "client_id": "ID", "client_secret": "SECRET", "refresh_token": "TOKEN"}
+        });
+        let credentials = Credentials::from_value(value).unwrap();
+        assert_eq!(
+            credentials.identity(),
+            "target@proj.iam.gserviceaccount.com (via authorized_user:ID)"
+        );
+
+        let value = serde_json::json!({"type": "unknown_type"});
+        assert!(Credentials::from_value(value).is_err());
+
+        let value = serde_json::json!({
+            "type": "external_account",
+            "audience": "aud",
+            "subject_token_type": "urn:ietf:params:oauth:token-type:jwt",
+            "credential_source": {"environment_id": "aws1", "url": "http://169.254.169.254"}
+        });
+        assert!(Credentials::from_value(value).is_err());
+    }
+}
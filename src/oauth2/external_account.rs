@@ -0,0 +1,153 @@
+use super::{
+    get_token::request_token_data,
+    impersonation::{generate_access_token, CLOUD_PLATFORM_SCOPE},
+    request_builder,
+    token_data::TokenData,
+};
+use crate::types::HttpClient;
+use eyre::WrapErr;
+use hyper::{
+    body::{to_bytes, Body as BodyStruct},
+    http::header,
+    Method, Uri,
+};
+use serde::Deserialize;
+use std::{collections::HashMap, path::PathBuf};
+use tracing::trace;
+
+fn default_sts_token_url() -> String {
+    "https://sts.googleapis.com/v1/token".to_owned()
+}
+
+/// Формат файла или ответа с OIDC токеном
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SubjectTokenFormat {
+    /// Токен целиком
+    Text,
+    /// Токен в поле JSON объекта
+    Json { subject_token_field_name: String },
+}
+
+/// Откуда читаем внешний токен
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct CredentialSource {
+    pub file: Option<PathBuf>,
+    pub url: Option<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub format: Option<SubjectTokenFormat>,
+    /// Есть только у AWS источников, которые не поддерживаем
+    pub environment_id: Option<String>,
+}
+
+/// Workload identity federation: обмен внешнего OIDC токена на токен Google через STS
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct ExternalAccountData {
+    pub audience: String,
+    pub subject_token_type: String,
+    #[serde(default = "default_sts_token_url")]
+    pub token_url: String,
+    pub service_account_impersonation_url: Option<String>,
+    pub credential_source: CredentialSource,
+}
+
+impl ExternalAccountData {
+    /// Проверка источника токена сразу при загрузке файла
+    pub fn validate(&self) -> Result<(), eyre::Error> {
+        let source = &self.credential_source;
+        eyre::ensure!(source.environment_id.is_none(), "AWS credential source is not supported");
+        eyre::ensure!(
+            source.file.is_some() != source.url.is_some(),
+            "credential_source must contain exactly one of file or url"
+        );
+        Ok(())
+    }
+}
+
+/// Читаем внешний токен из файла или по адресу
+async fn read_subject_token(http_client: &HttpClient, source: &CredentialSource) -> Result<String, eyre::Error> {
+    let raw_data = match (&source.file, &source.url) {
+        (Some(path), None) => tokio::fs::read(path)
+            .await
+            .wrap_err_with(|| format!("Subject token file {} read failed", path.display()))?,
+        (None, Some(url)) => {
+            let uri: Uri = url.parse().wrap_err("Subject token uri parse failed")?;
+            let mut builder = request_builder(Method::GET, uri).header(header::USER_AGENT, "hyper");
+            for (name, value) in source.headers.iter() {
+                builder = builder.header(name.as_str(), value.as_str());
+            }
+            let request = builder.body(BodyStruct::empty()).wrap_err("Request build error")?;
+
+            let response = http_client.request(request).await.wrap_err("Http response error")?;
+            let status = response.status();
+            let body_data = to_bytes(response).await.wrap_err("Body data receive")?;
+            eyre::ensure!(status.is_success(), "Subject token request failed, status {}", status);
+            body_data.to_vec()
+        }
+        _ => return Err(eyre::eyre!("credential_source must contain exactly one of file or url")),
+    };
+
+    let token = match &source.format {
+        None | Some(SubjectTokenFormat::Text) => String::from_utf8(raw_data).wrap_err("Subject token is not utf-8")?,
+        Some(SubjectTokenFormat::Json { subject_token_field_name }) => {
+            let value: serde_json::Value = serde_json::from_slice(&raw_data).wrap_err("Subject token json parsing failed")?;
+            value
+                .get(subject_token_field_name)
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| eyre::eyre!("Missing subject token field {}", subject_token_field_name))?
+                .to_owned()
+        }
+    };
+    let token = token.trim();
+    eyre::ensure!(!token.is_empty(), "Empty subject token");
+    Ok(token.to_owned())
+}
+
+/// Получаем токен через STS и при необходимости имперсонацию сервисного аккаунта
+pub async fn get_token_data_by_external_account(
+    http_client: &HttpClient,
+    account_data: &ExternalAccountData,
+    scopes: &str,
+) -> Result<TokenData, eyre::Error> {
+    let subject_token = read_subject_token(http_client, &account_data.credential_source)
+        .await
+        .wrap_err("Subject token read failed")?;
+
+    // При имперсонации токен STS нужен только для вызова IAM Credentials
+    let sts_scopes = if account_data.service_account_impersonation_url.is_some() {
+        CLOUD_PLATFORM_SCOPE
+    } else {
+        scopes
+    };
+
+    let uri: Uri = account_data.token_url.parse().wrap_err("STS uri parse failed")?;
+    trace!(?uri);
+
+    let body_data = format!(
+        "grant_type={}&audience={}&scope={}&requested_token_type={}&subject_token={}&subject_token_type={}",
+        urlencoding::encode("urn:ietf:params:oauth:grant-type:token-exchange"),
+        urlencoding::encode(&account_data.audience),
+        urlencoding::encode(sts_scopes),
+        urlencoding::encode("urn:ietf:params:oauth:token-type:access_token"),
+        urlencoding::encode(&subject_token),
+        urlencoding::encode(&account_data.subject_token_type)
+    );
+
+    let request = request_builder(Method::POST, uri)
+        .header(header::CONTENT_LENGTH, body_data.len())
+        .header(header::ACCEPT, mime::APPLICATION_JSON.essence_str())
+        .header(header::USER_AGENT, "hyper")
+        .header(header::CONTENT_TYPE, mime::APPLICATION_WWW_FORM_URLENCODED.essence_str())
+        .body(BodyStruct::from(body_data))
+        .wrap_err("Request build error")?;
+
+    let sts_token = request_token_data(http_client, request)
+        .await
+        .wrap_err("STS token exchange failed")?;
+
+    match &account_data.service_account_impersonation_url {
+        Some(url) => generate_access_token(http_client, url, &sts_token.access_token, scopes).await,
+        None => Ok(sts_token),
+    }
+}
@@ -1,4 +1,4 @@
-use super::{service_account::ServiceAccountData, token_data::TokenData};
+use super::{request_builder, service_account::ServiceAccountData, token_data::TokenData};
 use crate::{
     helpers::{get_content_length, get_content_type},
     types::HttpClient,
@@ -7,48 +7,35 @@ use chrono::{Duration, Utc};
 use eyre::WrapErr;
 use hyper::{
     body::{to_bytes, Body as BodyStruct},
-    http::{header, uri::Authority},
+    http::header,
     Method, Request, Uri,
 };
 use mime::Mime;
 use rsa::{pkcs8::FromPrivateKey, PaddingScheme, RsaPrivateKey};
 use sha2::Digest;
-use std::str::FromStr;
 use tracing::trace;
 
-// #[instrument(level = "error", skip(service_acc_data, scopes))]
-fn build_jwt_string(service_acc_data: &ServiceAccountData, scopes: &str, duration: Duration) -> Result<String, eyre::Error> {
-    // Header
-    /*let jwt_header = r#"{"alg":"RS256","typ":"JWT"}"#;
-    trace!(%jwt_header);
-    let jwt_header = base64::encode(jwt_header);*/
-    // Уже вычисленный вариант
-    let jwt_header = "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9";
+/// Читаем приватный ключ сервисного аккаунта
+pub fn parse_private_key(service_acc_data: &ServiceAccountData) -> Result<RsaPrivateKey, eyre::Error> {
+    // Вроде бы как метод шифрования записан в самом ключе, поэтому используем pkcs8 способ чтения закрытого ключа
+    let private_key = RsaPrivateKey::from_pkcs8_pem(&service_acc_data.private_key).wrap_err("Private key parsing failed")?;
+    private_key.validate().wrap_err("Private key is invalid")?;
+    Ok(private_key)
+}
 
-    // Claims
-    let current_time = Utc::now();
-    let expire_time = current_time
-        .checked_add_signed(duration)
-        .ok_or_else(|| eyre::eyre!("Expire time calc err"))?;
-    let jwt_claims = format!(
-        r###"{{"iss":"{}","scope":"{}","aud":"{}","exp":{},"iat":{}}}"###,
-        service_acc_data.client_email,
-        scopes,
-        service_acc_data.token_uri,
-        expire_time.timestamp(),
-        current_time.timestamp()
-    );
+/// Подписываем JWT ключом сервисного аккаунта, части кодируются в base64url без выравнивания
+fn sign_jwt(service_acc_data: &ServiceAccountData, jwt_header: &str, jwt_claims: &str) -> Result<String, eyre::Error> {
+    trace!(%jwt_header);
     trace!(%jwt_claims);
-    let jwt_claims = base64::encode(jwt_claims);
+    let jwt_header = base64::encode_config(jwt_header, base64::URL_SAFE_NO_PAD);
+    let jwt_claims = base64::encode_config(jwt_claims, base64::URL_SAFE_NO_PAD);
 
     // Исходная строка для подписи
     let jwt_string_for_signature = format!("{}.{}", jwt_header, jwt_claims);
     trace!(%jwt_string_for_signature);
 
     // Приватный ключ читаем
-    // Вроде бы как метод шифрования записан в самом ключе, поэтому используем pkcs8 способ чтения закрытого ключа
-    let private_key = RsaPrivateKey::from_pkcs8_pem(&service_acc_data.private_key).wrap_err("Private key parsing failed")?;
-    private_key.validate().wrap_err("Private key is invalid")?;
+    let private_key = parse_private_key(service_acc_data)?;
 
     // Вычисляем подпись
     // Sign the UTF-8 representation of the input using SHA256withRSA (also known as RSASSA-PKCS1-V1_5-SIGN with the SHA-256 hash function) with the private key obtained from the Google API Console.
@@ -58,13 +45,59 @@ fn build_jwt_string(service_acc_data: &ServiceAccountData, scopes: &str, duratio
         .wrap_err("Sign failed")?;
 
     // Base64 подписи
-    let base_64_signature = base64::encode(signature);
+    let base_64_signature = base64::encode_config(signature, base64::URL_SAFE_NO_PAD);
     trace!(%base_64_signature);
 
     // Результат
     Ok(format!("{}.{}", jwt_string_for_signature, base_64_signature))
 }
 
+// #[instrument(level = "error", skip(service_acc_data, scopes))]
+fn build_jwt_string(service_acc_data: &ServiceAccountData, scopes: &str, duration: Duration) -> Result<String, eyre::Error> {
+    // Claims
+    let current_time = Utc::now();
+    let expire_time = current_time
+        .checked_add_signed(duration)
+        .ok_or_else(|| eyre::eyre!("Expire time calc err"))?;
+    let jwt_claims = serde_json::json!({
+        "iss": service_acc_data.client_email,
+        "scope": scopes,
+        "aud": service_acc_data.token_uri,
+        "exp": expire_time.timestamp(),
+        "iat": current_time.timestamp(),
+    });
+
+    sign_jwt(service_acc_data, r#"{"alg":"RS256","typ":"JWT"}"#, &jwt_claims.to_string())
+}
+
+/// Self-signed JWT: подписанный токен сразу используется как bearer токен для API с указанным aud,
+/// без обмена на сервере авторизации
+pub fn build_self_signed_jwt(service_acc_data: &ServiceAccountData, audience: &str, duration: Duration) -> Result<TokenData, eyre::Error> {
+    let current_time = Utc::now();
+    let expire_time = current_time
+        .checked_add_signed(duration)
+        .ok_or_else(|| eyre::eyre!("Expire time calc err"))?;
+    let jwt_header = serde_json::json!({
+        "alg": "RS256",
+        "typ": "JWT",
+        "kid": service_acc_data.private_key_id,
+    });
+    let jwt_claims = serde_json::json!({
+        "iss": service_acc_data.client_email,
+        "sub": service_acc_data.client_email,
+        "aud": audience,
+        "exp": expire_time.timestamp(),
+        "iat": current_time.timestamp(),
+    });
+
+    let access_token = sign_jwt(service_acc_data, &jwt_header.to_string(), &jwt_claims.to_string())?;
+    Ok(TokenData {
+        access_token,
+        expires_in: duration.num_seconds().max(0) as u64,
+        token_type: "Bearer".to_owned(),
+    })
+}
+
 // #[instrument(level = "error", skip(http_client, service_acc_data, scopes))]
 pub async fn get_token_data(
     http_client: &HttpClient,
@@ -76,14 +109,8 @@ pub async fn get_token_data(
     let jwt_result = build_jwt_string(service_acc_data, scopes, duration).wrap_err("JWT string create")?;
     trace!(%jwt_result);
 
-    // Адрес запроса
-    // TODO: Replace to data from service account
-    let uri = Uri::builder()
-        .scheme("https")
-        .authority(Authority::from_str("oauth2.googleapis.com").wrap_err("Authority parse error")?)
-        .path_and_query("/token")
-        .build()
-        .wrap_err("Uri build failed")?;
+    // Адрес запроса из файла ключа
+    let uri: Uri = service_acc_data.token_uri.parse().wrap_err("Token uri parse failed")?;
     trace!(?uri);
 
     // Form data - это аналог query строки, но в body
@@ -98,10 +125,7 @@ pub async fn get_token_data(
 
     // Объект запроса
     // https://docs.aws.amazon.com/AmazonS3/latest/API/API_PutObject.html
-    let request = Request::builder()
-        .method(Method::POST)
-        .version(hyper::Version::HTTP_2)
-        .uri(uri)
+    let request = request_builder(Method::POST, uri)
         // Добавляется само если флаг выше true,
         // TODO: Что-то не так с установкой значения host, если выставить, то фейлится запрос
         // Может быть дело в регистре?
@@ -114,26 +138,12 @@ pub async fn get_token_data(
         .wrap_err("Request build error")?;
     trace!(?request);
 
-    /*let response = backoff::future::retry(backoff::ExponentialBackoff::default(), || {
-        async move {
-            match http_client.request(request).await {
-                Ok(response) => {
-                    if response.status().is_success() {
-                        Ok(response)
-                    } else {
-                        warn!("Token request failed with status: {}", response.status());
-                        Err(backoff::Error::Transient(eyre::eyre!("Invalid status")))
-                    }
-                }
-                Err(err) => Err(backoff::Error::Permanent(eyre::Error::new(err))),
-            }
-        }
-        .in_current_span()
-    })
-    .await
-    .wrap_err("Http response error")?;
-    trace!(?response);*/
+    // Повторы при ошибках выполняет фоновое обновление в AuthTokenProvider
+    request_token_data(http_client, request).await
+}
 
+/// Выполняем запрос получения токена и разбираем ответ
+pub(super) async fn request_token_data(http_client: &HttpClient, request: Request<BodyStruct>) -> Result<TokenData, eyre::Error> {
     // Объект ответа
     let response = http_client.request(request).await.wrap_err("Http response error")?;
     trace!(?response);
@@ -168,7 +178,11 @@ pub async fn get_token_data(
             return Err(eyre::eyre!("Missing content type"));
         }
     } else {
-        return Err(eyre::eyre!("Invalid token request"));
+        return Err(eyre::eyre!(
+            "Invalid token request, status {}: {}",
+            status,
+            String::from_utf8_lossy(&body_data)
+        ));
     };
 
     Ok(token_data)
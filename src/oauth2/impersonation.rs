@@ -0,0 +1,101 @@
+use super::{request_builder, token_data::TokenData};
+use crate::types::HttpClient;
+use chrono::{DateTime, Utc};
+use eyre::WrapErr;
+use hyper::{
+    body::{to_bytes, Body as BodyStruct},
+    http::header,
+    Method, Uri,
+};
+use serde::Deserialize;
+use tracing::trace;
+
+/// Scope для промежуточного токена, которым вызываем IAM Credentials и STS
+pub const CLOUD_PLATFORM_SCOPE: &str = "https://www.googleapis.com/auth/cloud-platform";
+
+/// Время жизни выдаваемого токена
+const IMPERSONATED_TOKEN_LIFETIME: &str = "3600s";
+
+/// Адрес generateAccessToken для сервисного аккаунта
+pub fn impersonation_url(service_account: &str) -> String {
+    format!(
+        "https://iamcredentials.googleapis.com/v1/projects/-/serviceAccounts/{}:generateAccessToken",
+        service_account
+    )
+}
+
+/// Email аккаунта из адреса generateAccessToken
+pub fn service_account_from_url(url: &str) -> Option<&str> {
+    url.rsplit_once("serviceAccounts/")
+        .and_then(|(_, tail)| tail.strip_suffix(":generateAccessToken"))
+}
+
+/// Получаем токен другого сервисного аккаунта через IAM Credentials API,
+/// у исходного аккаунта должна быть роль Service Account Token Creator
+pub async fn generate_access_token(
+    http_client: &HttpClient,
+    url: &str,
+    source_token: &str,
+    scopes: &str,
+) -> Result<TokenData, eyre::Error> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct GenerateAccessTokenData {
+        access_token: String,
+        expire_time: String,
+    }
+
+    let uri: Uri = url.parse().wrap_err("Impersonation uri parse failed")?;
+    trace!(?uri);
+
+    let body_data = serde_json::json!({
+        "scope": scopes.split_whitespace().collect::<Vec<_>>(),
+        "lifetime": IMPERSONATED_TOKEN_LIFETIME,
+    })
+    .to_string();
+
+    let request = request_builder(Method::POST, uri)
+        .header(header::CONTENT_LENGTH, body_data.len())
+        .header(header::ACCEPT, mime::APPLICATION_JSON.essence_str())
+        .header(header::USER_AGENT, "hyper")
+        .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.essence_str())
+        .header(header::AUTHORIZATION, format!("Bearer {}", source_token))
+        .body(BodyStruct::from(body_data))
+        .wrap_err("Request build error")?;
+
+    let response = http_client.request(request).await.wrap_err("Http response error")?;
+    let status = response.status();
+    let body_data = to_bytes(response).await.wrap_err("Body data receive")?;
+    eyre::ensure!(
+        status.is_success(),
+        "Impersonation request failed, status {}: {}",
+        status,
+        String::from_utf8_lossy(&body_data)
+    );
+
+    let data: GenerateAccessTokenData = serde_json::from_slice(&body_data).wrap_err("Impersonation response parsing failed")?;
+    let expire_time = DateTime::parse_from_rfc3339(&data.expire_time).wrap_err("Impersonated token expire time parsing failed")?;
+    let expires_in = expire_time
+        .with_timezone(&Utc)
+        .signed_duration_since(Utc::now())
+        .num_seconds()
+        .max(0) as u64;
+
+    Ok(TokenData {
+        access_token: data.access_token,
+        expires_in,
+        token_type: "Bearer".to_owned(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_service_account_from_url() {
+        let url = impersonation_url("uploader@project.iam.gserviceaccount.com");
+        assert_eq!(service_account_from_url(&url), Some("uploader@project.iam.gserviceaccount.com"));
+        assert_eq!(service_account_from_url("https://example.com"), None);
+    }
+}
@@ -0,0 +1,43 @@
+use super::{get_token::request_token_data, request_builder, token_data::TokenData};
+use crate::types::HttpClient;
+use eyre::WrapErr;
+use hyper::{body::Body as BodyStruct, http::header, Method, Uri};
+use tracing::trace;
+
+/// Адрес метадата сервера внутри GCE/GKE
+pub const DEFAULT_METADATA_ENDPOINT: &str = "http://169.254.169.254";
+
+/// Сервисный аккаунт виртуальной машины или workload identity в GKE
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetadataServerData {
+    /// Адрес сервера, можно заменить на локальную заглушку
+    pub endpoint: String,
+    /// Email аккаунта или default
+    pub service_account: String,
+}
+
+/// Получаем токен у метадата сервера
+pub async fn get_token_data_from_metadata(
+    http_client: &HttpClient,
+    metadata_data: &MetadataServerData,
+    scopes: &str,
+) -> Result<TokenData, eyre::Error> {
+    let uri: Uri = format!(
+        "{}/computeMetadata/v1/instance/service-accounts/{}/token?scopes={}",
+        metadata_data.endpoint.trim_end_matches('/'),
+        urlencoding::encode(&metadata_data.service_account),
+        urlencoding::encode(scopes)
+    )
+    .parse()
+    .wrap_err("Metadata uri build failed")?;
+    trace!(?uri);
+
+    let request = request_builder(Method::GET, uri)
+        .header("Metadata-Flavor", "Google")
+        .header(header::ACCEPT, mime::APPLICATION_JSON.essence_str())
+        .header(header::USER_AGENT, "hyper")
+        .body(BodyStruct::empty())
+        .wrap_err("Request build error")?;
+
+    request_token_data(http_client, request).await
+}
@@ -1,9 +1,30 @@
 mod service_account;
 mod token_data;
 mod get_token;
+mod authorized_user;
+mod metadata_server;
+mod external_account;
+mod impersonation;
+mod credentials;
 
 pub use self::{
-    service_account::ServiceAccountData,
     token_data::TokenData,
-    get_token::get_token_data
-};
\ No newline at end of file
+    get_token::parse_private_key,
+    credentials::Credentials
+};
+
+/// Заготовка запроса к серверу авторизации.
+/// HTTP/2 используем только для https, метадата сервер и локальные заглушки работают по http.
+/// Клиент создан с set_host(false), поэтому для HTTP/1.1 заголовок Host выставляем сами.
+fn request_builder(method: hyper::Method, uri: hyper::Uri) -> hyper::http::request::Builder {
+    let builder = hyper::Request::builder().method(method);
+    if uri.scheme_str() == Some("https") {
+        builder.version(hyper::Version::HTTP_2).uri(uri)
+    } else {
+        let host = uri.authority().map(|v| v.as_str().to_owned()).unwrap_or_default();
+        builder
+            .version(hyper::Version::HTTP_11)
+            .header(hyper::header::HOST, host)
+            .uri(uri)
+    }
+}
@@ -1,12 +1,10 @@
 use serde::Deserialize;
-use std::{path::Path, io::BufReader, fs::File};
-use serde_json::from_reader;
-use eyre::WrapErr;
+use super::credentials::CredentialsType;
 
-#[derive(Debug, Deserialize, Clone)]
+#[derive(Debug, Deserialize, Clone, PartialEq)]
 pub struct ServiceAccountData{
     #[serde(rename = "type")]
-    pub acc_type: String,   // TODO: Enum
+    pub acc_type: CredentialsType,
     pub project_id: String,
     pub private_key_id: String,
     pub private_key: String,
@@ -18,11 +16,3 @@ pub struct ServiceAccountData{
     pub client_x509_cert_url: String
 }
 
-impl ServiceAccountData{
-    pub fn new_from_file(path: &Path) -> Result<ServiceAccountData, eyre::Error>{
-        let file = File::open(path).wrap_err("Service account file open err")?;
-        let reader = BufReader::new(file);
-        let data: ServiceAccountData = from_reader(reader).wrap_err("Service accound data parsing failed")?;
-        Ok(data)
-    }
-}
\ No newline at end of file
@@ -0,0 +1,339 @@
+use crate::app_config::{NotificationOutboxConfig, SpoolConfig};
+use eyre::WrapErr;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::sync::Notify;
+use tracing::{error, info};
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Как часто удаляем старые завершенные записи, если очередь пустая
+const CLEANUP_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+/// Запись очереди с повторами, на диске лежит в файле `<id>.json`
+pub trait QueueEntry: Clone + Serialize + DeserializeOwned {
+    fn id(&self) -> &str;
+
+    /// Запись ждет очередной попытки
+    fn is_pending(&self) -> bool;
+
+    /// Обработка закончена, запись хранится только для просмотра состояния
+    fn is_finished(&self) -> bool;
+
+    /// Unix timestamp создания
+    fn created_at(&self) -> i64;
+
+    /// Unix timestamp последнего изменения
+    fn updated_at(&self) -> i64;
+
+    fn set_updated_at(&mut self, updated_at: i64);
+
+    /// Unix timestamp следующей попытки
+    fn next_attempt_at(&self) -> i64;
+
+    /// Исправляем запись, прочитанную после перезапуска
+    fn restore(&mut self) {}
+}
+
+/// Настройки повторов и хранения записей
+#[derive(Debug, Clone)]
+pub struct QueueSettings {
+    /// Без каталога записи живут только в памяти
+    pub dir: Option<PathBuf>,
+    pub retry_delay_secs: u64,
+    pub max_retry_delay_secs: u64,
+    pub keep_finished_hours: u64,
+}
+
+impl From<&SpoolConfig> for QueueSettings {
+    fn from(config: &SpoolConfig) -> Self {
+        QueueSettings {
+            dir: Some(config.dir.clone()),
+            retry_delay_secs: config.retry_delay_secs,
+            max_retry_delay_secs: config.max_retry_delay_secs,
+            keep_finished_hours: config.keep_finished_hours,
+        }
+    }
+}
+
+impl From<&NotificationOutboxConfig> for QueueSettings {
+    fn from(config: &NotificationOutboxConfig) -> Self {
+        QueueSettings {
+            dir: config.dir.clone(),
+            retry_delay_secs: config.retry_delay_secs,
+            max_retry_delay_secs: config.max_retry_delay_secs,
+            keep_finished_hours: config.keep_finished_hours,
+        }
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Записи с повторами в памяти и на диске.
+/// Общая часть очереди выгрузок и очереди уведомлений, обработку записей выполняют они сами.
+pub struct PersistentQueue<T> {
+    /// Название очереди для логов
+    name: &'static str,
+    settings: QueueSettings,
+    entries: Mutex<HashMap<String, T>>,
+    /// Появилась новая запись
+    wakeup: Notify,
+}
+
+impl<T: QueueEntry> PersistentQueue<T> {
+    /// Загружаем записи, оставшиеся с прошлого запуска
+    pub fn open(name: &'static str, settings: QueueSettings) -> Result<PersistentQueue<T>, eyre::Error> {
+        let mut entries = HashMap::new();
+        if let Some(dir) = &settings.dir {
+            std::fs::create_dir_all(dir).wrap_err_with(|| format!("{} dir {} create failed", name, dir.display()))?;
+
+            for dir_entry in std::fs::read_dir(dir).wrap_err_with(|| format!("{} dir read failed", name))? {
+                let path = dir_entry.wrap_err_with(|| format!("{} dir read failed", name))?.path();
+                match path.extension().and_then(|v| v.to_str()) {
+                    Some("json") => {}
+                    // Недописанные файлы от прерванной записи
+                    Some("tmp") => {
+                        std::fs::remove_file(&path).ok();
+                        continue;
+                    }
+                    _ => continue,
+                }
+
+                let data = std::fs::read(&path).wrap_err_with(|| format!("{} entry {} read failed", name, path.display()))?;
+                let mut entry: T =
+                    serde_json::from_slice(&data).wrap_err_with(|| format!("{} entry {} parse failed", name, path.display()))?;
+                entry.restore();
+                entries.insert(entry.id().to_owned(), entry);
+            }
+
+            let pending = entries.values().filter(|entry| entry.is_pending()).count();
+            info!("{} opened, pending entries: {}", name, pending);
+        }
+
+        Ok(PersistentQueue {
+            name,
+            settings,
+            entries: Mutex::new(entries),
+            wakeup: Notify::new(),
+        })
+    }
+
+    /// Каталог очереди, если она хранится на диске
+    pub fn dir(&self) -> Option<&Path> {
+        self.settings.dir.as_deref()
+    }
+
+    fn entry_path(&self, id: &str) -> Option<PathBuf> {
+        self.dir().map(|dir| dir.join(format!("{}.json", id)))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, T>> {
+        self.entries.lock().expect("Queue lock poisoned")
+    }
+
+    /// Копия записи
+    pub fn get(&self, id: &str) -> Option<T> {
+        self.lock().get(id).cloned()
+    }
+
+    /// Доступ ко всем записям без копирования
+    pub fn with_entries<R>(&self, f: impl FnOnce(&HashMap<String, T>) -> R) -> R {
+        f(&self.lock())
+    }
+
+    /// Пишем запись через временный файл
+    async fn save(&self, entry: &T) -> Result<(), eyre::Error> {
+        let path = match self.entry_path(entry.id()) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let data = serde_json::to_vec(entry).wrap_err("Queue entry serialize failed")?;
+        let tmp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, data).await.wrap_err("Queue entry write failed")?;
+        tokio::fs::rename(&tmp_path, &path).await.wrap_err("Queue entry rename failed")?;
+        Ok(())
+    }
+
+    /// Новая запись попадает в очередь только после записи на диск
+    pub async fn add(&self, entry: T) -> Result<(), eyre::Error> {
+        self.save(&entry).await?;
+        self.lock().insert(entry.id().to_owned(), entry);
+        self.wakeup.notify_one();
+        Ok(())
+    }
+
+    /// Обновляем запись в памяти и на диске, ошибка записи не должна останавливать обработку
+    pub async fn update(&self, entry: &mut T) {
+        entry.set_updated_at(chrono::Utc::now().timestamp());
+        self.lock().insert(entry.id().to_owned(), entry.clone());
+        if let Err(err) = self.save(entry).await {
+            error!("{} entry {} save failed: {:#}", self.name, entry.id(), err);
+        }
+    }
+
+    /// Задержка перед повтором после указанного количества попыток
+    pub fn retry_delay_secs(&self, attempts: u32) -> i64 {
+        let delay = self
+            .settings
+            .retry_delay_secs
+            .saturating_mul(1 << attempts.saturating_sub(1).min(20))
+            .min(self.settings.max_retry_delay_secs);
+        delay as i64
+    }
+
+    /// Следующая запись для обработки или время до нее
+    fn next_pending(&self) -> Result<T, Duration> {
+        let now = chrono::Utc::now().timestamp();
+        let entries = self.lock();
+        let next = entries
+            .values()
+            .filter(|entry| entry.is_pending())
+            .min_by_key(|entry| (entry.next_attempt_at(), entry.created_at()));
+        match next {
+            Some(entry) if entry.next_attempt_at() <= now => Ok(entry.clone()),
+            Some(entry) => Err(Duration::from_secs((entry.next_attempt_at() - now) as u64).min(CLEANUP_PERIOD)),
+            None => Err(CLEANUP_PERIOD),
+        }
+    }
+
+    /// Удаляем давно завершенные записи, возвращаем их id
+    async fn cleanup_finished(&self) -> Vec<String> {
+        let expire_time = chrono::Utc::now().timestamp() - (self.settings.keep_finished_hours * 60 * 60) as i64;
+        let expired: Vec<String> = {
+            let mut entries = self.lock();
+            let expired: Vec<String> = entries
+                .values()
+                .filter(|entry| entry.is_finished() && entry.updated_at() < expire_time)
+                .map(|entry| entry.id().to_owned())
+                .collect();
+            for id in expired.iter() {
+                entries.remove(id);
+            }
+            expired
+        };
+        for path in expired.iter().filter_map(|id| self.entry_path(id)) {
+            tokio::fs::remove_file(path).await.ok();
+        }
+        expired
+    }
+
+    /// Фоновая обработка: записи отдаются по одной по мере наступления времени попытки
+    pub async fn run<F, Fut>(&self, mut process: F)
+    where
+        F: FnMut(T) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        loop {
+            self.cleanup_finished().await;
+
+            match self.next_pending() {
+                Ok(entry) => process(entry).await,
+                Err(wait) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep(wait) => {}
+                        _ = self.wakeup.notified() => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct TestEntry {
+        id: String,
+        pending: bool,
+        created_at: i64,
+        updated_at: i64,
+        next_attempt_at: i64,
+        /// Выставляется при загрузке
+        #[serde(default)]
+        restored: bool,
+    }
+
+    impl TestEntry {
+        fn new(id: &str, pending: bool, time: i64) -> TestEntry {
+            TestEntry {
+                id: id.to_owned(),
+                pending,
+                created_at: time,
+                updated_at: time,
+                next_attempt_at: time,
+                restored: false,
+            }
+        }
+    }
+
+    impl QueueEntry for TestEntry {
+        fn id(&self) -> &str {
+            &self.id
+        }
+        fn is_pending(&self) -> bool {
+            self.pending
+        }
+        fn is_finished(&self) -> bool {
+            !self.pending
+        }
+        fn created_at(&self) -> i64 {
+            self.created_at
+        }
+        fn updated_at(&self) -> i64 {
+            self.updated_at
+        }
+        fn set_updated_at(&mut self, updated_at: i64) {
+            self.updated_at = updated_at;
+        }
+        fn next_attempt_at(&self) -> i64 {
+            self.next_attempt_at
+        }
+        fn restore(&mut self) {
+            self.restored = true;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let settings = QueueSettings {
+            dir: Some(dir.path().to_owned()),
+            retry_delay_secs: 1,
+            max_retry_delay_secs: 10,
+            keep_finished_hours: 1,
+        };
+        let now = chrono::Utc::now().timestamp();
+
+        let queue = PersistentQueue::open("Test queue", settings.clone()).unwrap();
+        queue.add(TestEntry::new("later", true, now + 60)).await.unwrap();
+        queue.add(TestEntry::new("ready", true, now)).await.unwrap();
+        queue.add(TestEntry::new("expired", false, now - 2 * 60 * 60)).await.unwrap();
+        std::fs::write(dir.path().join("broken.json.tmp"), "{").unwrap();
+        drop(queue);
+
+        let queue = PersistentQueue::<TestEntry>::open("Test queue", settings).unwrap();
+        assert!(!dir.path().join("broken.json.tmp").exists());
+        let next = queue.next_pending().unwrap();
+        assert_eq!(next.id, "ready");
+        assert!(next.restored);
+
+        assert_eq!(queue.cleanup_finished().await, vec!["expired".to_owned()]);
+        assert!(!dir.path().join("expired.json").exists());
+        assert!(queue.get("later").is_some());
+
+        assert_eq!(queue.retry_delay_secs(1), 1);
+        assert_eq!(queue.retry_delay_secs(3), 4);
+        assert_eq!(queue.retry_delay_secs(30), 10);
+    }
+}
@@ -0,0 +1,192 @@
+use super::{
+    notifier::{notifier_error, LinkMessage, Notifier},
+    proj::UploadParams,
+    qr::QrRenderer,
+};
+use crate::{
+    app_config::{EmailConfig, SmtpTls},
+    error::{ErrorWithStatusAndDesc, WrapErrorWithStatusAndDesc},
+    helpers::escape_markup,
+    message_template::render_template,
+};
+use eyre::WrapErr;
+use futures::{future::BoxFuture, FutureExt};
+use hyper::http::StatusCode;
+use lettre::{
+    message::{header::ContentType, Attachment, Mailbox, MultiPart, SinglePart},
+    transport::smtp::authentication::Credentials,
+    AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor,
+};
+use std::sync::Arc;
+use tracing::{debug, Instrument};
+
+///////////////////////////////////////////////////////////////////////////
+
+/// Идентификатор картинки QR кода внутри письма
+const QR_CONTENT_ID: &str = "qr_code";
+
+fn parse_mailboxes(addresses: &[String]) -> Result<Vec<Mailbox>, lettre::address::AddressError> {
+    addresses.iter().map(|address| address.parse()).collect()
+}
+
+///////////////////////////////////////////////////////////////////////////
+
+/// Отправка ссылки письмом через SMTP
+pub struct EmailLinkSender {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    allow_recipient_override: bool,
+    qr_code: bool,
+    qr: Arc<QrRenderer>,
+    subject: String,
+    body: String,
+}
+
+impl EmailLinkSender {
+    pub fn new(config: EmailConfig, qr: Arc<QrRenderer>) -> Result<EmailLinkSender, eyre::Error> {
+        let mut builder = match config.tls {
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(&config.smtp_host),
+            SmtpTls::Starttls => {
+                AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&config.smtp_host).wrap_err("SMTP TLS setup failed")?
+            }
+            SmtpTls::Implicit => AsyncSmtpTransport::<Tokio1Executor>::relay(&config.smtp_host).wrap_err("SMTP TLS setup failed")?,
+        };
+        if let Some(port) = config.smtp_port {
+            builder = builder.port(port);
+        }
+        if let (Some(username), Some(password)) = (config.username, config.password) {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+
+        Ok(EmailLinkSender {
+            transport: builder.build(),
+            from: config.from.parse().wrap_err("Invalid from address")?,
+            to: parse_mailboxes(&config.to).wrap_err("Invalid recipient address")?,
+            allow_recipient_override: config.allow_recipient_override,
+            qr_code: config.qr_code,
+            qr,
+            subject: config.subject,
+            body: config.body,
+        })
+    }
+
+    fn build_message(&self, message: &LinkMessage) -> Result<Message, eyre::Error> {
+        let text = message.plain_text(None);
+        let context = message.context(&text);
+        let subject = render_template(&self.subject, &context)?;
+        let body = render_template(&self.body, &context)?;
+
+        // Получатели из запроса заменяют адреса из настроек
+        let recipients = if message.params.email_to.is_empty() {
+            self.to.clone()
+        } else {
+            parse_mailboxes(&message.params.email_to).wrap_err("Invalid recipient address")?
+        };
+
+        let mut builder = Message::builder().from(self.from.clone()).subject(subject);
+        for recipient in recipients {
+            builder = builder.to(recipient);
+        }
+
+        let message = if self.qr_code {
+            let qr_code_image = self
+                .qr
+                .render_png(message.link, &message.params.file_name)
+                .wrap_err("QR code create failed")?;
+            let html = format!(
+                "<html><body><pre>{}</pre><img src=\"cid:{}\" alt=\"QR code\"></body></html>",
+                escape_markup(&body),
+                QR_CONTENT_ID
+            );
+            let image_type = ContentType::parse(qr_code_image.mime.essence_str()).wrap_err("Content type parse failed")?;
+            builder.multipart(
+                MultiPart::alternative().singlepart(SinglePart::plain(body)).multipart(
+                    MultiPart::related()
+                        .singlepart(SinglePart::html(html))
+                        .singlepart(Attachment::new_inline(QR_CONTENT_ID.to_owned()).body(qr_code_image.data, image_type)),
+                ),
+            )
+        } else {
+            builder.singlepart(SinglePart::plain(body))
+        };
+
+        message.wrap_err("Email build failed")
+    }
+
+    pub async fn post_link(&self, message: &LinkMessage<'_>) -> Result<(), ErrorWithStatusAndDesc> {
+        let email = self.build_message(message).wrap_err_with_500()?;
+        let response = self
+            .transport
+            .send(email)
+            .in_current_span()
+            .await
+            .map_err(|err| notifier_error("Email", err))?;
+        debug!("Email sent: {:?}", response.code());
+        Ok(())
+    }
+}
+
+impl Notifier for EmailLinkSender {
+    fn kind(&self) -> &'static str {
+        "email"
+    }
+
+    fn check_params(&self, params: &UploadParams) -> Result<(), ErrorWithStatusAndDesc> {
+        if params.email_to.is_empty() {
+            return Ok(());
+        }
+        if !self.allow_recipient_override {
+            return Err(ErrorWithStatusAndDesc::new_with_status_desc(
+                StatusCode::BAD_REQUEST,
+                "Email recipients override is not allowed for this application".into(),
+            ));
+        }
+        parse_mailboxes(&params.email_to).wrap_err_with_400_desc("Invalid email recipient".into())?;
+        Ok(())
+    }
+
+    fn post_link<'a>(&'a self, message: &'a LinkMessage<'a>, _target: &'a str) -> BoxFuture<'a, Result<(), ErrorWithStatusAndDesc>> {
+        EmailLinkSender::post_link(self, message).boxed()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_message() {
+        #[rustfmt::skip]
+        let config: EmailConfig = serde_yaml::from_str(r#"
+            smtp_host: localhost
+            smtp_port: 2525
+            tls: none
+            from: "Uploader <uploader@example.com>"
+            to: ["team@example.com"]
+            allow_recipient_override: true
+            qr_code: true
+        "#)
+        .unwrap();
+        let sender = EmailLinkSender::new(config, Arc::new(QrRenderer::new(None).unwrap())).unwrap();
+
+        let params = UploadParams {
+            email_to: vec!["qa@example.com".to_owned()],
+            ..UploadParams::for_test("app.apk")
+        };
+        sender.check_params(&params).unwrap();
+        let message = LinkMessage {
+            project: "project",
+            link: "https://storage/app.apk",
+            params: &params,
+            text: None,
+            attachment: None,
+        };
+        let email = String::from_utf8(sender.build_message(&message).unwrap().formatted()).unwrap();
+        assert!(email.contains("To: qa@example.com"));
+        assert!(email.contains("Subject: File uploaded: app.apk"));
+        assert!(email.contains("Content-ID: <qr_code>"));
+    }
+}
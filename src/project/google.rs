@@ -1,14 +1,17 @@
+use super::replayable_body::ReplayableBody;
 use crate::{
-    app_config::GoogleStorageConfig,
+    app_config::{GoogleStorageConfig, RetryConfig},
     auth_token_provider::AuthTokenProvider,
     error::{ErrorWithStatusAndDesc, WrapErrorWithStatusAndDesc},
-    prometheus::count_uploaded_size,
+    prometheus::{count_upload_retry, count_uploaded_size},
+    retry::RetryPolicy,
+    token_provider_registry::TokenProviderRegistry,
     types::HttpClient,
 };
 use eyre::WrapErr;
 use futures::StreamExt;
 use hyper::{
-    body::{aggregate, to_bytes, Body as BodyStruct, Buf},
+    body::{aggregate, to_bytes, Body as BodyStruct, Buf, Bytes},
     http::{
         header,
         method::Method,
@@ -17,12 +20,32 @@ use hyper::{
     },
     Request, Response,
 };
-use serde::Deserialize;
-use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    Arc,
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
-use tracing::{debug, error, Instrument};
+use tokio::time::timeout;
+use tracing::{debug, error, warn, Instrument};
+
+///////////////////////////////////////////////////////////////////////////
+
+/// Права на чтение и запись объектов
+pub const STORAGE_SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+/// aud для self-signed JWT
+pub const STORAGE_AUDIENCE: &str = "https://storage.googleapis.com/";
+
+/// Начало ссылок на скачивание выгруженных файлов
+const DOWNLOAD_LINK_BASE: &str = "https://storage.cloud.google.com/";
+
+/// Только нужные поля в ответе сервера, https://cloud.google.com/storage/docs/json_api/v1/objects#resource
+/// Все поля UploadResultData должны быть здесь, иначе они всегда будут пустыми
+const UPLOAD_RESPONSE_FIELDS: &str = "id,name,bucket,selfLink,md5Hash,mediaLink,size,crc32c,contentType,metadata";
 
 ///////////////////////////////////////////////////////////////////////////
 
@@ -34,7 +57,7 @@ fn build_upload_uri(bucket_name: &str, file_name: &str) -> Result<Uri, hyper::ht
             "/upload/storage/v1/b/{}/o?name={}&uploadType=media&fields={}",
             urlencoding::encode(bucket_name),
             urlencoding::encode(file_name),
-            urlencoding::encode("id,name,bucket,selfLink,md5Hash,mediaLink") // Только нужные поля в ответе сервера, https://cloud.google.com/storage/docs/json_api/v1/objects#resource
+            urlencoding::encode(UPLOAD_RESPONSE_FIELDS)
         ))
         .build()
 }
@@ -64,8 +87,15 @@ struct UploadResultData {
     bucket: String,
     // #[serde(rename = "selfLink")]
     // self_link: String,
-    // #[serde(rename = "md5Hash")]
-    // md5: String,
+    /// Размер приходит строкой
+    size: Option<String>,
+    #[serde(rename = "md5Hash")]
+    md5_hash: Option<String>,
+    crc32c: Option<String>,
+    #[serde(rename = "contentType")]
+    content_type: Option<String>,
+    #[serde(default)]
+    metadata: HashMap<String, String>,
     // #[serde(rename = "mediaLink")]
     // link: String,
 }
@@ -82,41 +112,243 @@ async fn parse_response_body(response: Response<BodyStruct>) -> Result<UploadRes
     Ok(info)
 }
 
+/// Описание сохраненного объекта
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredObject {
+    pub bucket: String,
+    pub name: String,
+    pub size: Option<u64>,
+    /// Хэши в base64, как их отдает Google
+    pub md5_hash: Option<String>,
+    pub crc32c: Option<String>,
+    pub content_type: Option<String>,
+    pub metadata: HashMap<String, String>,
+}
+
+impl From<UploadResultData> for StoredObject {
+    fn from(info: UploadResultData) -> StoredObject {
+        StoredObject {
+            size: info.size.and_then(|v| v.parse().ok()),
+            bucket: info.bucket,
+            name: info.name,
+            md5_hash: info.md5_hash,
+            crc32c: info.crc32c,
+            content_type: info.content_type,
+            metadata: info.metadata,
+        }
+    }
+}
+
+/// Результат выгрузки в Cloud Storage
+pub struct UploadResult {
+    pub download_link: String,
+    /// Объем данных уже после сжатия
+    pub uploaded_bytes: u64,
+    pub object: StoredObject,
+    /// Страница выгрузки, если включена для проекта
+    pub page_link: Option<String>,
+}
+
+impl UploadResult {
+    /// Мессенджеры получают ссылку на страницу, если она есть
+    pub fn notification_link(&self) -> &str {
+        self.page_link.as_deref().unwrap_or(&self.download_link)
+    }
+}
+
+/// Ошибка отдельной попытки выгрузки
+enum AttemptError {
+    /// Можно повторить: 408, 429, 5xx, ошибки соединения и получения токена
+    Transient {
+        error: ErrorWithStatusAndDesc,
+        retry_after: Option<Duration>,
+    },
+    Permanent(ErrorWithStatusAndDesc),
+}
+
+impl AttemptError {
+    fn into_error(self) -> ErrorWithStatusAndDesc {
+        match self {
+            AttemptError::Transient { error, .. } => error,
+            AttemptError::Permanent(error) => error,
+        }
+    }
+}
+
+fn is_transient_status(status: StatusCode) -> bool {
+    status == StatusCode::REQUEST_TIMEOUT || status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Задержка из заголовка Retry-After, поддерживаем только вариант в секундах
+fn parse_retry_after(response: &Response<BodyStruct>) -> Option<Duration> {
+    response
+        .headers()
+        .get(header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+/// Данные для выгрузки
+pub enum UploadData {
+    /// Поток из запроса, при включенных повторах будет буферизован
+    Stream(BodyStruct),
+    /// Уже сохраненные данные
+    Buffered(ReplayableBody),
+}
+
+impl UploadData {
+    /// Данные, если они целиком в памяти
+    pub fn memory_bytes(&self) -> Option<Bytes> {
+        match self {
+            UploadData::Buffered(ReplayableBody::Memory(data)) => Some(data.clone()),
+            _ => None,
+        }
+    }
+
+    /// Размер данных, если они уже сохранены
+    pub fn buffered_len(&self) -> Option<u64> {
+        match self {
+            UploadData::Stream(_) => None,
+            UploadData::Buffered(body) => Some(body.len()),
+        }
+    }
+}
+
+/// Тела до этого размера буферизуются в памяти, если не задано в настройках повторов
+const DEFAULT_MEMORY_BUFFER_BYTES: u64 = 8 * 1024 * 1024;
+
 pub struct GoogleUploader {
     http_client: HttpClient,
-    token_provider: AuthTokenProvider,
+    token_provider: Arc<AuthTokenProvider>,
     target_bucket: String,
+    retry_policy: RetryPolicy,
+    /// Тела до этого размера буферизуются для повторов в памяти
+    memory_buffer_bytes: u64,
+    spool_dir: Option<PathBuf>,
 }
 
 impl GoogleUploader {
-    pub fn new(http_client: HttpClient, google_config: GoogleStorageConfig) -> Result<GoogleUploader, eyre::Error> {
-        // Создаем провайдер для токенов
-        let token_provider = AuthTokenProvider::new(
-            http_client.clone(),
-            &google_config.credentials_file,
-            "https://www.googleapis.com/auth/devstorage.read_write",
-        )
-        .wrap_err("Token provider create failed")?;
+    pub fn new(
+        http_client: HttpClient,
+        google_config: GoogleStorageConfig,
+        retry_config: Option<RetryConfig>,
+        token_providers: &TokenProviderRegistry,
+    ) -> Result<GoogleUploader, eyre::Error> {
+        // Провайдер токенов общий для всех проектов с тем же аккаунтом
+        let token_provider = token_providers
+            .get_or_create(&http_client, &google_config, STORAGE_SCOPE, STORAGE_AUDIENCE)
+            .wrap_err("Token provider create failed")?;
 
         Ok(GoogleUploader {
             http_client,
             target_bucket: google_config.bucket_name,
             token_provider,
+            retry_policy: RetryPolicy::new(retry_config.as_ref()),
+            memory_buffer_bytes: retry_config
+                .as_ref()
+                .map(|v| v.memory_buffer_bytes)
+                .unwrap_or(DEFAULT_MEMORY_BUFFER_BYTES),
+            spool_dir: retry_config.and_then(|v| v.spool_dir),
         })
     }
 
-    pub async fn upload(&self, filename: &str, body: BodyStruct) -> Result<String, ErrorWithStatusAndDesc> {
+    /// Ссылка на объект в бакете проекта
+    pub fn owns_link(&self, link: &str) -> bool {
+        link.strip_prefix(DOWNLOAD_LINK_BASE)
+            .and_then(|path| path.strip_prefix(self.target_bucket.as_str()))
+            .and_then(|path| path.strip_prefix('/'))
+            .map(|name| !name.is_empty())
+            .unwrap_or(false)
+    }
+
+    /// Сохраняем данные для повторной отправки или отложенной выгрузки
+    pub async fn buffer_body(&self, body: BodyStruct) -> Result<ReplayableBody, ErrorWithStatusAndDesc> {
+        let body = ReplayableBody::read(body, self.memory_buffer_bytes, self.spool_dir.as_deref())
+            .in_current_span()
+            .await
+            .wrap_err_with_status_desc(StatusCode::INTERNAL_SERVER_ERROR, "Upload data buffering failed".into())?;
+        debug!("Upload data buffered: {} bytes", body.len());
+        Ok(body)
+    }
+
+    /// Выгрузка данных, в progress отражается объем отправленных в текущей попытке байт
+    pub async fn upload(
+        &self,
+        filename: &str,
+        data: UploadData,
+        progress: Option<Arc<AtomicU64>>,
+    ) -> Result<UploadResult, ErrorWithStatusAndDesc> {
+        let progress = progress.unwrap_or_default();
+
+        let body = match data {
+            // Без повторов передаем данные потоком как есть
+            UploadData::Stream(body) if !self.retry_policy.is_enabled() => {
+                return self
+                    .upload_attempt(filename, body, progress)
+                    .in_current_span()
+                    .await
+                    .map_err(AttemptError::into_error);
+            }
+            // Для повторов данные должны читаться несколько раз
+            UploadData::Stream(body) => self.buffer_body(body).in_current_span().await?,
+            UploadData::Buffered(body) => body,
+        };
+
+        let started = Instant::now();
+        let mut attempt = 1;
+        loop {
+            let attempt_body = body.to_body().wrap_err_with_500()?;
+            let attempt_future = self.upload_attempt(filename, attempt_body, progress.clone()).in_current_span();
+
+            // Попытка не должна выходить за общий дедлайн
+            let result = match self.retry_policy.time_left(started.elapsed()) {
+                Some(time_left) => match timeout(time_left, attempt_future).await {
+                    Ok(result) => result,
+                    Err(_) => Err(AttemptError::Permanent(ErrorWithStatusAndDesc::new_with_status_desc(
+                        StatusCode::GATEWAY_TIMEOUT,
+                        "Google cloud upload deadline exceeded".into(),
+                    ))),
+                },
+                None => attempt_future.await,
+            };
+
+            let (error, retry_after) = match result {
+                Ok(result) => return Ok(result),
+                Err(AttemptError::Permanent(error)) => return Err(error),
+                Err(AttemptError::Transient { error, retry_after }) => (error, retry_after),
+            };
+
+            match self.retry_policy.next_delay(attempt, started.elapsed(), retry_after) {
+                Some(delay) => {
+                    warn!(attempt, ?delay, "Google cloud upload failed, retrying: {}", error);
+                    count_upload_retry();
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                None => return Err(error),
+            }
+        }
+    }
+
+    async fn upload_attempt(
+        &self,
+        filename: &str,
+        body: BodyStruct,
+        bytes_upload_counter: Arc<AtomicU64>,
+    ) -> Result<UploadResult, AttemptError> {
         // Получаем токен для Google API
         let token = self
             .token_provider
             .get_token()
             .in_current_span()
             .await
-            .wrap_err_with_status_desc(StatusCode::UNAUTHORIZED, "Google cloud token receive failed".into())?;
+            .wrap_err_with_status_desc(StatusCode::UNAUTHORIZED, "Google cloud token receive failed".into())
+            .map_err(|error| AttemptError::Transient { error, retry_after: None })?;
 
         // Специальный счетчик выгружаемых байт
         // Подсчитываем объем данных уже после компрессии
-        let bytes_upload_counter = Arc::new(AtomicU64::new(0));
+        bytes_upload_counter.store(0, Ordering::Relaxed);
         let result_body = body.map({
             let bytes_upload_counter = bytes_upload_counter.clone();
             move |v| {
@@ -128,20 +360,25 @@ impl GoogleUploader {
         });
 
         // Адрес запроса
-        let uri = build_upload_uri(&self.target_bucket, filename).wrap_err_with_500()?;
+        let uri = build_upload_uri(&self.target_bucket, filename)
+            .wrap_err_with_500()
+            .map_err(AttemptError::Permanent)?;
         debug!("Request uri: {}", uri);
 
         // Объект запроса
-        let request = build_upload_request(uri, token, BodyStruct::wrap_stream(result_body)).wrap_err_with_500()?;
+        let request = build_upload_request(uri, token, BodyStruct::wrap_stream(result_body))
+            .wrap_err_with_500()
+            .map_err(AttemptError::Permanent)?;
         debug!("Request object: {:?}", request);
 
-        // Объект ответа
+        // Объект ответа, ошибки соединения можно повторить
         let response = self
             .http_client
             .request(request)
             .in_current_span()
             .await
-            .wrap_err_with_status_desc(StatusCode::INTERNAL_SERVER_ERROR, "Google cloud error".into())?;
+            .wrap_err_with_status_desc(StatusCode::INTERNAL_SERVER_ERROR, "Google cloud error".into())
+            .map_err(|error| AttemptError::Transient { error, retry_after: None })?;
         debug!("Google response: {:?}", response);
 
         // Статус
@@ -151,25 +388,45 @@ impl GoogleUploader {
         // Обрабатываем в зависимости от ответа
         if status.is_success() {
             // Подсчет выгруженных конечных данных
-            count_uploaded_size(bytes_upload_counter.load(Ordering::Acquire), true);
+            let uploaded_bytes = bytes_upload_counter.load(Ordering::Acquire);
+            count_uploaded_size(uploaded_bytes, true);
 
             // Данные парсим
-            let info = parse_response_body(response).in_current_span().await?;
+            let info = parse_response_body(response)
+                .in_current_span()
+                .await
+                .map_err(AttemptError::Permanent)?;
             debug!("Uploading result: {:?}", info);
 
             // Ссылка для загрузки c поддержкой проверки пермишенов на скачивание
-            let download_link = format!("https://storage.cloud.google.com/{}/{}", info.bucket, info.name);
+            let download_link = format!("{}{}/{}", DOWNLOAD_LINK_BASE, info.bucket, info.name);
 
-            Ok(download_link)
+            Ok(UploadResult {
+                download_link,
+                page_link: None,
+                uploaded_bytes,
+                object: StoredObject::from(info),
+            })
         } else {
             // Подсчет выгруженных конечных данных
             count_uploaded_size(bytes_upload_counter.load(Ordering::Acquire), true);
 
+            // Временные ошибки можно повторить
+            let retry_after = parse_retry_after(&response);
+            let wrap_error = |error| {
+                if is_transient_status(status) {
+                    AttemptError::Transient { error, retry_after }
+                } else {
+                    AttemptError::Permanent(error)
+                }
+            };
+
             // Данные
             let body_data = to_bytes(response)
                 .in_current_span()
                 .await
-                .wrap_err_with_status_desc(StatusCode::INTERNAL_SERVER_ERROR, "Google cloud response receive failed".into())?;
+                .wrap_err_with_status_desc(StatusCode::INTERNAL_SERVER_ERROR, "Google cloud response receive failed".into())
+                .map_err(wrap_error)?;
             //error!("Upload fail result: {:?}", body_data);
 
             // Если есть внятный ответ - пробрасываем его
@@ -180,16 +437,140 @@ impl GoogleUploader {
 
                     error!("Upload fail result text: {}", minified_text);
                     let resp = format!("Google error response: {}", minified_text);
-                    Err(ErrorWithStatusAndDesc::new_with_status_desc(
+                    Err(wrap_error(ErrorWithStatusAndDesc::new_with_status_desc(
                         StatusCode::INTERNAL_SERVER_ERROR,
                         resp.into(),
-                    ))
+                    )))
                 }
-                None => Err(ErrorWithStatusAndDesc::new_with_status_desc(
+                None => Err(wrap_error(ErrorWithStatusAndDesc::new_with_status_desc(
                     StatusCode::INTERNAL_SERVER_ERROR,
                     "Google uploading failed".into(),
-                )),
+                ))),
             }
         }
     }
+
+    /// Проверяем, что токен получается и у аккаунта есть права на запись в корзину.
+    /// Используем testIamPermissions, чтобы ничего не выгружать.
+    pub async fn check_write_permission(&self) -> Result<(), eyre::Error> {
+        #[derive(Debug, Deserialize)]
+        struct TestPermissionsData {
+            #[serde(default)]
+            permissions: Vec<String>,
+        }
+
+        const CREATE_PERMISSION: &str = "storage.objects.create";
+
+        let token = self
+            .token_provider
+            .get_token()
+            .in_current_span()
+            .await
+            .wrap_err("Google cloud token receive failed")?;
+
+        let uri = Uri::builder()
+            .scheme("https")
+            .authority(Authority::from_static("storage.googleapis.com"))
+            .path_and_query(format!(
+                "/storage/v1/b/{}/iam/testPermissions?permissions={}",
+                urlencoding::encode(&self.target_bucket),
+                CREATE_PERMISSION
+            ))
+            .build()
+            .wrap_err("Uri build failed")?;
+
+        let request = Request::builder()
+            .method(Method::GET)
+            .version(hyper::Version::HTTP_2)
+            .uri(uri)
+            .header(header::USER_AGENT, "hyper")
+            .header(header::ACCEPT, mime::APPLICATION_JSON.essence_str())
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(BodyStruct::empty())
+            .wrap_err("Request build error")?;
+
+        let response = self
+            .http_client
+            .request(request)
+            .in_current_span()
+            .await
+            .wrap_err("Http response error")?;
+        let status = response.status();
+        let body_data = to_bytes(response).in_current_span().await.wrap_err("Body data receive")?;
+        eyre::ensure!(
+            status.is_success(),
+            "Bucket {} permissions request failed with status {}: {}",
+            self.target_bucket,
+            status,
+            String::from_utf8_lossy(&body_data)
+        );
+
+        let data: TestPermissionsData = serde_json::from_slice(&body_data).wrap_err("Permissions response parsing failed")?;
+        eyre::ensure!(
+            data.permissions.iter().any(|v| v == CREATE_PERMISSION),
+            "No {} permission for bucket {}",
+            CREATE_PERMISSION,
+            self.target_bucket
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ответ Cloud Storage на выгрузку без фильтра полей
+    const FULL_UPLOAD_RESPONSE: &str = r#"{
+        "kind": "storage#object",
+        "id": "builds/ios/app.ipa/1650000000000000",
+        "selfLink": "https://www.googleapis.com/storage/v1/b/builds/o/ios%2Fapp.ipa",
+        "mediaLink": "https://storage.googleapis.com/download/storage/v1/b/builds/o/ios%2Fapp.ipa?generation=1650000000000000&alt=media",
+        "name": "ios/app.ipa",
+        "bucket": "builds",
+        "generation": "1650000000000000",
+        "metageneration": "1",
+        "contentType": "application/octet-stream",
+        "storageClass": "STANDARD",
+        "size": "1048576",
+        "md5Hash": "XrY7u+Ae7tCTyyK7j1rNww==",
+        "crc32c": "yZRlqg==",
+        "etag": "CICAgICAgICAgIAQ=",
+        "timeCreated": "2022-04-15T10:00:00.000Z",
+        "updated": "2022-04-15T10:00:00.000Z",
+        "timeStorageClassUpdated": "2022-04-15T10:00:00.000Z",
+        "metadata": { "build": "42" }
+    }"#;
+
+    /// Оставляем только поля из fields, как это делает сервер
+    fn apply_fields_selector(response: &str, uri: &Uri) -> serde_json::Value {
+        let fields = uri
+            .query()
+            .unwrap()
+            .split('&')
+            .find_map(|v| v.strip_prefix("fields="))
+            .map(|v| urlencoding::decode(v).unwrap().into_owned())
+            .unwrap();
+        let mut value: serde_json::Value = serde_json::from_str(response).unwrap();
+        let object = value.as_object_mut().unwrap();
+        object.retain(|key, _| fields.split(',').any(|field| field == key));
+        value
+    }
+
+    #[test]
+    fn test_upload_response_fields() {
+        let uri = build_upload_uri("builds", "ios/app.ipa").unwrap();
+        let response = apply_fields_selector(FULL_UPLOAD_RESPONSE, &uri);
+        let info: UploadResultData = serde_json::from_value(response).unwrap();
+        let object = StoredObject::from(info);
+
+        assert_eq!(object.bucket, "builds");
+        assert_eq!(object.name, "ios/app.ipa");
+        assert_eq!(object.size, Some(1048576));
+        assert_eq!(object.md5_hash.as_deref(), Some("XrY7u+Ae7tCTyyK7j1rNww=="));
+        assert_eq!(object.crc32c.as_deref(), Some("yZRlqg=="));
+        assert_eq!(object.content_type.as_deref(), Some("application/octet-stream"));
+        assert_eq!(object.metadata.get("build").map(String::as_str), Some("42"));
+    }
 }
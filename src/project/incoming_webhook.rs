@@ -0,0 +1,92 @@
+use super::{
+    notifier::{notifier_http_error, LinkMessage, Notifier},
+    proj::UploadParams,
+};
+use crate::{app_config::IncomingWebhookConfig, error::ErrorWithStatusAndDesc};
+use futures::{future::BoxFuture, FutureExt};
+use hyper::http::StatusCode;
+use tracing::Instrument;
+
+///////////////////////////////////////////////////////////////////////////
+
+/// Формат сообщения входящего вебхука
+#[derive(Debug, Clone, Copy)]
+enum WebhookFormat {
+    /// https://discord.com/developers/docs/resources/webhook#execute-webhook
+    Discord,
+    /// https://learn.microsoft.com/microsoftteams/platform/webhooks-and-connectors/how-to/add-incoming-webhook
+    Teams,
+}
+
+/// Отправка ссылки во входящие вебхуки Discord и Microsoft Teams
+pub struct IncomingWebhookSender {
+    http_client: reqwest::Client,
+    format: WebhookFormat,
+    webhook_urls: Vec<String>,
+    default_text_before: Option<String>,
+}
+
+impl IncomingWebhookSender {
+    fn new(http_client: reqwest::Client, format: WebhookFormat, config: IncomingWebhookConfig) -> IncomingWebhookSender {
+        IncomingWebhookSender {
+            http_client,
+            format,
+            webhook_urls: config.webhook_urls,
+            default_text_before: config.default_text_before,
+        }
+    }
+
+    pub fn discord(http_client: reqwest::Client, config: IncomingWebhookConfig) -> IncomingWebhookSender {
+        IncomingWebhookSender::new(http_client, WebhookFormat::Discord, config)
+    }
+
+    pub fn teams(http_client: reqwest::Client, config: IncomingWebhookConfig) -> IncomingWebhookSender {
+        IncomingWebhookSender::new(http_client, WebhookFormat::Teams, config)
+    }
+
+    /// Получатель задается номером вебхука, начиная с 1, сам адрес содержит секрет
+    pub async fn post_link(&self, message: &LinkMessage<'_>, target: &str) -> Result<(), ErrorWithStatusAndDesc> {
+        let url = target
+            .parse::<usize>()
+            .ok()
+            .and_then(|number| self.webhook_urls.get(number.checked_sub(1)?))
+            .ok_or_else(|| {
+                ErrorWithStatusAndDesc::new_with_status_desc(
+                    StatusCode::NOT_FOUND,
+                    format!("{} webhook {} is not configured", self.kind(), target).into(),
+                )
+            })?;
+
+        let text = message.plain_text(self.default_text_before.as_deref());
+        let body = match self.format {
+            WebhookFormat::Discord => serde_json::json!({ "content": text }),
+            WebhookFormat::Teams => serde_json::json!({ "text": text }),
+        };
+
+        let result = async { self.http_client.post(url).json(&body).send().await?.error_for_status() }
+            .in_current_span()
+            .await;
+
+        // В адресе вебхука секрет, поэтому в ошибку его не пишем
+        result.map_err(|err| notifier_http_error(self.kind(), err))?;
+
+        Ok(())
+    }
+}
+
+impl Notifier for IncomingWebhookSender {
+    fn kind(&self) -> &'static str {
+        match self.format {
+            WebhookFormat::Discord => "discord",
+            WebhookFormat::Teams => "teams",
+        }
+    }
+
+    fn targets(&self, _params: &UploadParams) -> Vec<String> {
+        (1..=self.webhook_urls.len()).map(|number| number.to_string()).collect()
+    }
+
+    fn post_link<'a>(&'a self, message: &'a LinkMessage<'a>, target: &'a str) -> BoxFuture<'a, Result<(), ErrorWithStatusAndDesc>> {
+        IncomingWebhookSender::post_link(self, message, target).boxed()
+    }
+}
@@ -1,5 +1,18 @@
+mod email;
 mod google;
+mod incoming_webhook;
+mod notifier;
 mod proj;
+mod qr;
+mod replayable_body;
 mod slack;
+mod telegram;
+mod webhook;
 
-pub use self::proj::Project;
\ No newline at end of file
+pub use self::{
+    google::{GoogleUploader, UploadData, STORAGE_AUDIENCE},
+    notifier::NotificationTarget,
+    proj::{Project, UploadParams},
+    qr::QrRenderer,
+    webhook::WEBHOOK_NOTIFIER,
+};
@@ -0,0 +1,179 @@
+use super::{
+    email::EmailLinkSender, incoming_webhook::IncomingWebhookSender, proj::UploadParams, qr::QrRenderer, slack::SlackLinkSender,
+    telegram::TelegramLinkSender,
+};
+use crate::{
+    app_config::{NotifierConfig, SlackConfig},
+    error::ErrorWithStatusAndDesc,
+    message_template::TemplateContext,
+};
+use eyre::WrapErr;
+use futures::future::BoxFuture;
+use hyper::{body::Bytes, http::StatusCode};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+///////////////////////////////////////////////////////////////////////////
+
+/// Сообщение о завершенной выгрузке
+pub struct LinkMessage<'a> {
+    pub project: &'a str,
+    pub link: &'a str,
+    pub params: &'a UploadParams,
+    /// Текст по шаблону проекта
+    pub text: Option<String>,
+    /// Содержимое небольшого файла для отправки рядом со ссылкой
+    pub attachment: Option<&'a Bytes>,
+}
+
+impl<'a> LinkMessage<'a> {
+    /// Префикс из запроса заменяет текст из настроек
+    pub fn text_prefix(&self) -> Option<&'a str> {
+        self.params.slack_text_prefix.as_deref()
+    }
+
+    /// Текст без разметки: по шаблону проекта, либо префикс и ссылка
+    pub fn plain_text(&self, default_text_before: Option<&str>) -> String {
+        match &self.text {
+            Some(text) => text.clone(),
+            None => match self.text_prefix().or(default_text_before) {
+                Some(text_prefix) => format!("{}{}", text_prefix, self.link),
+                None => format!("Download file url: {}", self.link),
+            },
+        }
+    }
+
+    /// Значения для шаблонов
+    pub fn context<'b>(&'b self, text: &'b str) -> TemplateContext<'b> {
+        TemplateContext {
+            project: self.project,
+            link: self.link,
+            file_name: &self.params.file_name,
+            size: self.params.data_length,
+            token_label: &self.params.token_label,
+            request_id: &self.params.request_id,
+            metadata: &self.params.metadata,
+            text,
+        }
+    }
+}
+
+/// Получатель ссылки на выгруженный файл
+pub trait Notifier: Send + Sync {
+    /// Имя типа для логов и ошибок
+    fn kind(&self) -> &'static str;
+
+    /// Файлы до этого размера отправляются вместе со ссылкой
+    fn attachment_limit(&self) -> Option<u64> {
+        None
+    }
+
+    /// Проверка параметров запроса до начала выгрузки
+    fn check_params(&self, _params: &UploadParams) -> Result<(), ErrorWithStatusAndDesc> {
+        Ok(())
+    }
+
+    /// Получатели внутри одного отправителя: каналы, чаты и тд.
+    /// Каждому отправляем отдельно, чтобы ошибка одного не мешала остальным.
+    fn targets(&self, _params: &UploadParams) -> Vec<String> {
+        vec![String::new()]
+    }
+
+    fn post_link<'a>(&'a self, message: &'a LinkMessage<'a>, target: &'a str) -> BoxFuture<'a, Result<(), ErrorWithStatusAndDesc>>;
+}
+
+/// Конкретный получатель ссылки, сохраняется в очереди уведомлений
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NotificationTarget {
+    /// Постоянное имя отправителя в проекте, см. notifier_name
+    pub notifier: String,
+    pub target: String,
+}
+
+impl NotificationTarget {
+    /// Ключ для отчета о статусе, например slack:#releases
+    pub fn key(&self) -> String {
+        if self.target.is_empty() {
+            self.notifier.clone()
+        } else {
+            format!("{}:{}", self.notifier, self.target)
+        }
+    }
+}
+
+/// Отправитель с постоянным именем для очереди уведомлений
+pub struct NamedNotifier {
+    pub name: String,
+    pub notifier: Box<dyn Notifier>,
+}
+
+/// Имя отправителя: тип и хеш его настроек.
+/// Не зависит от порядка отправителей, поэтому после перезагрузки конфига
+/// уведомления из очереди не уйдут другому получателю.
+fn notifier_name(config: &NotifierConfig) -> Result<String, eyre::Error> {
+    use sha2::Digest;
+
+    let data = serde_json::to_vec(config).wrap_err("Notifier config serialize failed")?;
+    let hash = hex::encode(sha2::Sha256::digest(&data));
+    Ok(format!("{}-{}", config.kind(), &hash[..8]))
+}
+
+/// Создаем получателей из настроек проекта, slack_link_dub идет первым
+pub fn build_notifiers(
+    http_client: reqwest::Client,
+    slack_link_dub: Option<SlackConfig>,
+    configs: Vec<NotifierConfig>,
+    qr: Arc<QrRenderer>,
+) -> Result<Vec<NamedNotifier>, eyre::Error> {
+    let configs = slack_link_dub.map(NotifierConfig::Slack).into_iter().chain(configs);
+    configs
+        .map(|config| -> Result<NamedNotifier, eyre::Error> {
+            let name = notifier_name(&config)?;
+            let notifier: Box<dyn Notifier> = match config {
+                NotifierConfig::Slack(config) => Box::new(SlackLinkSender::new(http_client.clone(), config, qr.clone())),
+                NotifierConfig::Telegram(config) => Box::new(TelegramLinkSender::new(http_client.clone(), config, qr.clone())),
+                NotifierConfig::Discord(config) => Box::new(IncomingWebhookSender::discord(http_client.clone(), config)),
+                NotifierConfig::Teams(config) => Box::new(IncomingWebhookSender::teams(http_client.clone(), config)),
+                NotifierConfig::Email(config) => Box::new(EmailLinkSender::new(config, qr.clone())?),
+            };
+            Ok(NamedNotifier { name, notifier })
+        })
+        .collect()
+}
+
+/// Ошибка отправки в мессенджер
+pub fn notifier_error(kind: &str, err: impl std::fmt::Display) -> ErrorWithStatusAndDesc {
+    ErrorWithStatusAndDesc::new_with_status_desc(StatusCode::INTERNAL_SERVER_ERROR, format!("{} error: {}", kind, err).into())
+}
+
+/// Ошибка HTTP запроса в мессенджер, адрес вырезаем, так как в нем бывают секреты
+pub fn notifier_http_error(kind: &str, err: reqwest::Error) -> ErrorWithStatusAndDesc {
+    let text = err.to_string();
+    match err.url() {
+        Some(url) => notifier_error(kind, text.replace(url.as_str(), "<hidden>")),
+        None => notifier_error(kind, text),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_config::IncomingWebhookConfig;
+
+    #[test]
+    fn test_notifier_name() {
+        let discord = |url: &str| {
+            NotifierConfig::Discord(IncomingWebhookConfig {
+                webhook_urls: vec![url.to_owned()],
+                default_text_before: None,
+            })
+        };
+        let first = notifier_name(&discord("https://discord.com/api/webhooks/1/A")).unwrap();
+        let second = notifier_name(&discord("https://discord.com/api/webhooks/2/B")).unwrap();
+
+        // Имя зависит только от настроек отправителя
+        assert!(first.starts_with("discord-"));
+        assert_ne!(first, second);
+        assert_eq!(notifier_name(&discord("https://discord.com/api/webhooks/1/A")).unwrap(), first);
+    }
+}
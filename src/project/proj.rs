@@ -1,87 +1,474 @@
-use super::{google::GoogleUploader, slack::SlackLinkSender};
+use super::{
+    google::{GoogleUploader, UploadData, UploadResult},
+    notifier::{build_notifiers, LinkMessage, NamedNotifier, NotificationTarget},
+    qr::QrRenderer,
+    webhook::WebhookSender,
+};
 use crate::{
-    app_config::{GoogleStorageConfig, SlackConfig},
+    app_config::{validate_webhook_url, LimitsConfig, ProjectConfig},
+    client_ip::IpAccessList,
     error::{ErrorWithStatusAndDesc, WrapErrorWithStatusAndDesc},
+    landing_pages::LandingPages,
+    message_template::render_template,
+    notification_outbox::{all_sent, NotificationOutbox, NotificationReport},
+    quota::{QuotaTracker, QuotaUsage},
+    rate_limit::Limiter,
+    token_provider_registry::TokenProviderRegistry,
     types::HttpClient,
 };
+use eyre::WrapErr;
 use hyper::{
-    body::Body as BodyStruct,
-    http::{header, StatusCode},
-    Response,
+    body::{Body as BodyStruct, Bytes},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    net::IpAddr,
+    path::Path,
+    sync::{atomic::AtomicU64, Arc},
 };
-use tracing::Instrument;
+use tracing::{debug, error, info, Instrument};
+
+///////////////////////////////////////////////////////////////////////////
+
+/// Ответ на синхронную выгрузку
+#[derive(Debug, Serialize)]
+pub struct UploadResponse {
+    pub link: String,
+    pub request_id: String,
+    pub slack_sent: bool,
+    pub notification_status: NotificationReport,
+    /// Страница выгрузки
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub page_link: Option<String>,
+    /// QR код ссылки, если запрошен
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub qr_code: Option<String>,
+}
+
+/// Параметры отдельной выгрузки
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadParams {
+    pub file_name: String,
+    /// Размер входных данных из Content-Length
+    pub data_length: u64,
+    pub link_to_slack: bool,
+    pub slack_text_prefix: Option<String>,
+    pub request_id: String,
+    pub client_ip: IpAddr,
+    /// Дополнительный адрес уведомления о завершении
+    #[serde(default)]
+    pub callback_url: Option<String>,
+    /// Каналы Slack вместо каналов из настроек
+    #[serde(default)]
+    pub slack_channels: Vec<String>,
+    /// Пользователи Slack для личных сообщений: id или email
+    #[serde(default)]
+    pub slack_users: Vec<String>,
+    /// Ответить в существующий тред Slack, нужен ровно один канал в slack_channels
+    #[serde(default)]
+    pub slack_thread_ts: Option<String>,
+    /// Получатели писем вместо адресов из настроек
+    #[serde(default)]
+    pub email_to: Vec<String>,
+    /// Метка токена, которым выполнена выгрузка
+    #[serde(default)]
+    pub token_label: String,
+    /// Метаданные из запроса для шаблонов сообщений
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+}
+
+#[cfg(test)]
+impl UploadParams {
+    /// Параметры для тестов, нужные поля меняются после создания
+    pub fn for_test(file_name: &str) -> UploadParams {
+        UploadParams {
+            file_name: file_name.to_owned(),
+            data_length: 4,
+            link_to_slack: false,
+            slack_text_prefix: None,
+            request_id: "REQUEST".to_owned(),
+            client_ip: IpAddr::from([127, 0, 0, 1]),
+            callback_url: None,
+            slack_channels: Vec::new(),
+            slack_users: Vec::new(),
+            slack_thread_ts: None,
+            email_to: Vec::new(),
+            token_label: "ci".to_owned(),
+            metadata: Default::default(),
+        }
+    }
+}
 
 ///////////////////////////////////////////////////////////////////////////
 
 pub struct Project {
+    name: String,
+    ip_access: IpAccessList,
+    limiter: Arc<Limiter>,
+    /// Настройки, из которых создан ограничитель
+    limits: Option<LimitsConfig>,
+    quota: Option<Arc<QuotaTracker>>,
     google_uploader: GoogleUploader,
-    slack_link_sender: Option<SlackLinkSender>,
+    /// Получатели ссылки: Slack и другие мессенджеры
+    notifiers: Vec<NamedNotifier>,
+    webhook_sender: Option<WebhookSender>,
+    spool_uploads: bool,
+    /// Шаблон текста сообщений в мессенджеры
+    message_template: Option<String>,
+    qr: Arc<QrRenderer>,
+    /// Страницы выгрузок, если включены для проекта
+    landing_pages: Option<Arc<LandingPages>>,
 }
 
 impl Project {
-    /// Создаем объект отдельного проекта
+    /// Создаем объект отдельного проекта.
+    /// Если передан прошлый вариант проекта, то счетчики ограничений и квот переносятся из него.
     pub fn new(
-        google_storage_target: GoogleStorageConfig,
-        slack_link_dub: Option<SlackConfig>,
+        config: ProjectConfig,
+        state_dir: Option<&Path>,
+        previous: Option<&Project>,
+        token_providers: &TokenProviderRegistry,
         http_client_low_level: HttpClient,
         http_client_high_level: reqwest::Client,
+        landing_pages: Option<Arc<LandingPages>>,
     ) -> Result<Project, eyre::Error> {
-        let google_uploader = GoogleUploader::new(http_client_low_level, google_storage_target)?;
+        // Счетчики квот с сохранением на диск
+        let previous_quota = previous.and_then(|v| v.quota.clone());
+        let quota = match (config.quota, state_dir, previous_quota) {
+            // Один и тот же файл состояния не должны писать сразу два объекта
+            (Some(quota_config), Some(_), Some(tracker)) => {
+                tracker.set_config(quota_config);
+                Some(tracker)
+            }
+            (Some(quota_config), Some(state_dir), None) => {
+                let path = state_dir.join("quota").join(format!("{}.json", config.name));
+                let tracker = QuotaTracker::load(quota_config, path).wrap_err("Quota state load failed")?;
+                Some(Arc::new(tracker))
+            }
+            (Some(_), None, _) => return Err(eyre::eyre!("Quota requires state directory")),
+            (None, _, _) => None,
+        };
+
+        let google_uploader = GoogleUploader::new(http_client_low_level, config.google_storage_target, config.retry, token_providers)?;
+
+        let landing_pages = match (config.landing_page, landing_pages) {
+            (true, Some(landing_pages)) => Some(landing_pages),
+            (true, None) => return Err(eyre::eyre!("Landing page requires settings.landing_pages and settings.public_url")),
+            (false, _) => None,
+        };
+
+        let qr = Arc::new(QrRenderer::new(config.qr.as_ref()).wrap_err("QR renderer create failed")?);
+
+        let notifiers = build_notifiers(http_client_high_level.clone(), config.slack_link_dub, config.notifiers, qr.clone())
+            .wrap_err("Notifiers create failed")?;
 
-        let slack_link_sender = slack_link_dub.map(|conf| SlackLinkSender::new(http_client_high_level, conf));
+        let webhook_sender = config.webhooks.map(|conf| WebhookSender::new(http_client_high_level, conf));
+
+        // Ограничитель с теми же настройками переиспользуем вместе с его состоянием
+        let limiter = match previous {
+            Some(previous) if previous.limits == config.limits => previous.limiter.clone(),
+            _ => Arc::new(Limiter::new(config.limits.as_ref())),
+        };
 
         Ok(Project {
+            name: config.name,
+            ip_access: IpAccessList::new(config.ip_allowlist, config.ip_denylist),
+            limiter,
+            limits: config.limits,
+            quota,
             google_uploader,
-            slack_link_sender,
+            notifiers,
+            webhook_sender,
+            spool_uploads: config.spool_uploads,
+            message_template: config.message_template,
+            qr,
+            landing_pages,
         })
     }
 
-    /// Выполнение отгрузки на данном проекте
-    pub async fn upload(
-        &self,
-        file_name: String,
-        body: BodyStruct,
-        link_to_slack: bool,
-        slack_text_prefix: Option<String>,
-        request_id: &str,
-    ) -> Result<Response<BodyStruct>, ErrorWithStatusAndDesc> {
+    /// Имя проекта
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Разрешена ли выгрузка с данного адреса
+    pub fn is_ip_allowed(&self, ip: &IpAddr) -> bool {
+        self.ip_access.is_allowed(ip)
+    }
+
+    /// Отправка вебхуков о завершенных выгрузках, если настроена
+    pub fn webhook_sender(&self) -> Option<&WebhookSender> {
+        self.webhook_sender.as_ref()
+    }
+
+    /// Отрисовка QR кодов по настройкам проекта
+    pub fn qr(&self) -> &QrRenderer {
+        &self.qr
+    }
+
+    /// Ссылка указывает на файл, выгруженный этим проектом
+    pub fn owns_link(&self, link: &str) -> bool {
+        self.google_uploader.owns_link(link)
+    }
+
+    /// Выгрузки идут через очередь на диске
+    pub fn spool_uploads(&self) -> bool {
+        self.spool_uploads
+    }
+
+    /// Общие ограничения на выгрузки проекта
+    pub fn limiter(&self) -> &Limiter {
+        &self.limiter
+    }
+
+    /// Текущее использование квоты, если она настроена
+    pub fn quota_usage(&self) -> Option<QuotaUsage> {
+        self.quota.as_ref().map(|quota| quota.usage())
+    }
+
+    /// Проверки параметров до начала выгрузки
+    pub fn check_params(&self, params: &UploadParams) -> Result<(), ErrorWithStatusAndDesc> {
         // Заранее проверим перед выгрузкой: можем ли мы постить в слак если хотят этого?
-        let slack_sender = if link_to_slack {
-            if self.slack_link_sender.is_some() {
-                self.slack_link_sender.as_ref()
-            } else {
+        if params.link_to_slack && self.notifiers.is_empty() {
+            return Err(ErrorWithStatusAndDesc::new_with_status_desc(
+                StatusCode::BAD_REQUEST,
+                "Slack posting is not configured for this application".into(),
+            ));
+        }
+
+        // Адрес уведомления из запроса разрешается только явно в настройках
+        if let Some(callback_url) = &params.callback_url {
+            if !self
+                .webhook_sender
+                .as_ref()
+                .map(WebhookSender::allows_callback_url)
+                .unwrap_or(false)
+            {
                 return Err(ErrorWithStatusAndDesc::new_with_status_desc(
                     StatusCode::BAD_REQUEST,
-                    "Slack posting is not configured for this application".into(),
+                    "Callback url is not allowed for this application".into(),
                 ));
             }
-        } else {
-            None
+            validate_webhook_url(callback_url).wrap_err_with_400_desc("Invalid callback url".into())?;
+        }
+
+        // Получатели в Slack из запроса
+        let has_slack_targets = !params.slack_channels.is_empty() || !params.slack_users.is_empty();
+        if has_slack_targets && !self.notifiers.iter().any(|named| named.notifier.kind() == "slack") {
+            return Err(ErrorWithStatusAndDesc::new_with_status_desc(
+                StatusCode::BAD_REQUEST,
+                "Slack posting is not configured for this application".into(),
+            ));
+        }
+
+        // Получатели писем из запроса
+        if !params.email_to.is_empty() && !self.notifiers.iter().any(|named| named.notifier.kind() == "email") {
+            return Err(ErrorWithStatusAndDesc::new_with_status_desc(
+                StatusCode::BAD_REQUEST,
+                "Email notifications are not configured for this application".into(),
+            ));
+        }
+        for named in self.notifiers.iter() {
+            named.notifier.check_params(params)?;
+        }
+
+        // Квоту проверяем до приема данных, чтобы не ставить в очередь заведомо отклоняемые выгрузки
+        if let Some(quota) = &self.quota {
+            quota.check(params.data_length)?;
+        }
+
+        Ok(())
+    }
+
+    /// Сохраняем тело запроса, чтобы выгрузить его уже после ответа клиенту
+    pub async fn buffer_body(&self, body: BodyStruct) -> Result<UploadData, ErrorWithStatusAndDesc> {
+        let body = self.google_uploader.buffer_body(body).in_current_span().await?;
+        Ok(UploadData::Buffered(body))
+    }
+
+    /// Выгрузка данных в Storage с учетом квот, в progress отражается объем отправленных данных
+    pub async fn store(
+        &self,
+        params: &UploadParams,
+        data: UploadData,
+        progress: Option<Arc<AtomicU64>>,
+        outbox: &NotificationOutbox,
+    ) -> Result<UploadResult, ErrorWithStatusAndDesc> {
+        // Заранее проверяем квоты по размеру входных данных
+        let quota_reservation = match &self.quota {
+            Some(quota) => Some(quota.reserve(params.data_length)?),
+            None => None,
         };
 
         // Загружаем в Storage
-        let download_link = self.google_uploader.upload(file_name.as_str(), body).in_current_span().await?;
+        let mut upload_result = self
+            .google_uploader
+            .upload(params.file_name.as_str(), data, progress)
+            .in_current_span()
+            .await?;
 
-        // Дублируем ссылку в Slack если нужно
-        let slack_sent = if let Some(slack) = slack_sender {
-            slack.post_link(&download_link, slack_text_prefix).in_current_span().await?;
-            true
+        // Учитываем в квоте фактический объем после сжатия
+        if let Some(reservation) = quota_reservation {
+            reservation.commit(upload_result.uploaded_bytes).in_current_span().await;
+        }
+
+        // Журнал аудита выгрузок
+        info!(
+            target: "audit",
+            project = %self.name,
+            client_ip = %params.client_ip,
+            file_name = %params.file_name,
+            download_link = %upload_result.download_link,
+            "File uploaded"
+        );
+
+        // Уведомляем внешние системы через очередь уведомлений, ответ клиенту доставки не ждет
+        if let Some(webhook_sender) = &self.webhook_sender {
+            let payload = webhook_sender.payload(
+                &self.name,
+                params,
+                &upload_result.download_link,
+                upload_result.uploaded_bytes,
+                &upload_result.object,
+            );
+            if let Some(payload) = payload {
+                outbox
+                    .enqueue_webhooks(
+                        &self.name,
+                        params,
+                        &upload_result.download_link,
+                        webhook_sender.urls(params),
+                        payload,
+                    )
+                    .in_current_span()
+                    .await;
+            }
+        }
+
+        // Страница выгрузки, без нее мессенджеры получат прямую ссылку
+        if let Some(landing_pages) = &self.landing_pages {
+            let size = upload_result.object.size.unwrap_or(params.data_length);
+            let page_result = landing_pages
+                .create(
+                    &self.name,
+                    &params.request_id,
+                    &params.file_name,
+                    size,
+                    &params.metadata,
+                    &upload_result.download_link,
+                )
+                .in_current_span()
+                .await;
+            match page_result {
+                Ok(page_link) => upload_result.page_link = page_link,
+                Err(err) => error!("Landing page create failed: {:#}", err),
+            }
+        }
+
+        Ok(upload_result)
+    }
+
+    /// Максимальный размер файла, который кто-то из получателей хочет получить вместе со ссылкой
+    pub fn attachment_limit(&self) -> Option<u64> {
+        self.notifiers.iter().filter_map(|named| named.notifier.attachment_limit()).max()
+    }
+
+    /// Данные файла для отправки вместе со ссылкой, если они уже в памяти и подходят по размеру
+    pub fn attachment(&self, params: &UploadParams, data: &UploadData) -> Option<Bytes> {
+        let limit = self.attachment_limit()?;
+        if params.data_length >= limit {
+            return None;
+        }
+        data.memory_bytes()
+    }
+
+    /// Все получатели ссылки для данной выгрузки
+    pub fn notification_targets(&self, params: &UploadParams) -> Vec<NotificationTarget> {
+        if !params.link_to_slack {
+            return Vec::new();
+        }
+        self.notifiers
+            .iter()
+            .flat_map(|named| {
+                named.notifier.targets(params).into_iter().map(move |target| NotificationTarget {
+                    notifier: named.name.clone(),
+                    target,
+                })
+            })
+            .collect()
+    }
+
+    /// Отправляем ссылку одному получателю
+    pub async fn notify_target(
+        &self,
+        params: &UploadParams,
+        download_link: &str,
+        target: &NotificationTarget,
+        attachment: Option<&Bytes>,
+    ) -> Result<(), ErrorWithStatusAndDesc> {
+        // Настройки проекта могли поменяться, пока уведомление ждало повтора
+        let notifier = self
+            .notifiers
+            .iter()
+            .find(|named| named.name == target.notifier)
+            .map(|named| &named.notifier)
+            .wrap_err_with_status_desc(
+                StatusCode::NOT_FOUND,
+                format!("Notifier {} is not available", target.notifier).into(),
+            )?;
+
+        let mut message = LinkMessage {
+            project: &self.name,
+            link: download_link,
+            params,
+            text: None,
+            attachment,
+        };
+        if let Some(template) = &self.message_template {
+            let text = render_template(template, &message.context("")).wrap_err_with_500_desc("Message template render failed".into())?;
+            message.text = Some(text);
+        }
+
+        debug!("Send link with {} notifier to {:?}", notifier.kind(), target.target);
+        notifier.post_link(&message, &target.target).in_current_span().await
+    }
+
+    /// Выполнение отгрузки на данном проекте
+    pub async fn upload(
+        &self,
+        params: UploadParams,
+        body: BodyStruct,
+        outbox: &NotificationOutbox,
+    ) -> Result<UploadResponse, ErrorWithStatusAndDesc> {
+        self.check_params(&params)?;
+
+        // Небольшой файл читаем в память, чтобы отправить его вместе со ссылкой
+        let wants_attachment = self.attachment_limit().map(|limit| params.data_length < limit).unwrap_or(false);
+        let data = if wants_attachment {
+            self.buffer_body(body).in_current_span().await?
         } else {
-            false
+            UploadData::Stream(body)
         };
+        let attachment = self.attachment(&params, &data);
 
-        // Формируем ответ
-        let json_text = format!(
-            r#"{{"link": "{}", "request_id": "{}", "slack_sent": {}}}"#,
-            download_link, request_id, slack_sent
-        );
-        let response = Response::builder()
-            .status(StatusCode::OK)
-            .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.essence_str())
-            .header(header::CONTENT_LENGTH, json_text.as_bytes().len())
-            .body(BodyStruct::from(json_text))
-            .wrap_err_with_500()?;
-
-        Ok(response)
+        let upload_result = self.store(&params, data, None, outbox).in_current_span().await?;
+
+        // Ошибки уведомлений не ломают ответ, файл уже выгружен
+        let notification_status = outbox
+            .send(self, &params, upload_result.notification_link(), attachment.as_ref())
+            .in_current_span()
+            .await;
+
+        Ok(UploadResponse {
+            link: upload_result.download_link,
+            request_id: params.request_id,
+            slack_sent: all_sent(&notification_status),
+            notification_status,
+            page_link: upload_result.page_link,
+            qr_code: None,
+        })
     }
 }
@@ -0,0 +1,304 @@
+use crate::{
+    app_config::{parse_color, QrConfig, QrErrorCorrection, QrFormat},
+    helpers::escape_markup,
+};
+use eyre::WrapErr;
+use image::{imageops::FilterType, Rgb, RgbImage, RgbaImage};
+use qrcode::{Color, EcLevel, QrCode};
+use rusttype::{point, Font, Scale};
+
+///////////////////////////////////////////////////////////////////////////
+
+/// Какую часть ширины кода занимает логотип
+const LOGO_FRACTION: u32 = 5;
+
+/// Картинка QR кода
+pub struct QrImage {
+    pub data: Vec<u8>,
+    pub mime: mime::Mime,
+    /// Имя файла для мессенджеров
+    pub file_name: &'static str,
+}
+
+fn svg_color(color: [u8; 3]) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+/// Размер логотипа с сохранением пропорций, большая сторона равна max_size
+fn logo_size(logo: &RgbaImage, max_size: f32) -> (f32, f32) {
+    let (width, height) = (logo.width().max(1) as f32, logo.height().max(1) as f32);
+    let scale = max_size / width.max(height);
+    (width * scale, height * scale)
+}
+
+/// Смешиваем цвета, alpha от 0 до 1
+fn blend(background: [u8; 3], foreground: [u8; 3], alpha: f32) -> Rgb<u8> {
+    let mut result = [0; 3];
+    for (index, value) in result.iter_mut().enumerate() {
+        *value = (background[index] as f32 * (1.0 - alpha) + foreground[index] as f32 * alpha).round() as u8;
+    }
+    Rgb(result)
+}
+
+///////////////////////////////////////////////////////////////////////////
+
+/// Отрисовка QR кодов по настройкам проекта
+pub struct QrRenderer {
+    module_size: u32,
+    quiet_zone: u32,
+    ec_level: EcLevel,
+    format: QrFormat,
+    dark: [u8; 3],
+    light: [u8; 3],
+    /// Логотип и его исходный PNG для вставки в SVG
+    logo: Option<(RgbaImage, Vec<u8>)>,
+    caption: bool,
+    caption_font: Option<Font<'static>>,
+}
+
+impl QrRenderer {
+    /// Без настроек рисуем черно-белый PNG
+    pub fn new(config: Option<&QrConfig>) -> Result<QrRenderer, eyre::Error> {
+        let config = config.cloned().unwrap_or_default();
+
+        let logo = match &config.logo {
+            Some(path) => {
+                let data = std::fs::read(path).wrap_err_with(|| format!("QR logo {} read failed", path.display()))?;
+                let image = image::load_from_memory(&data).wrap_err("QR logo must be a PNG image")?.to_rgba8();
+                Some((image, data))
+            }
+            None => None,
+        };
+        let caption_font = match &config.caption_font {
+            Some(path) => {
+                let data = std::fs::read(path).wrap_err_with(|| format!("QR font {} read failed", path.display()))?;
+                Some(Font::try_from_vec(data).ok_or_else(|| eyre::eyre!("QR font {} parse failed", path.display()))?)
+            }
+            None => None,
+        };
+
+        Ok(QrRenderer {
+            module_size: config.module_size,
+            quiet_zone: config.quiet_zone,
+            ec_level: match config.error_correction {
+                QrErrorCorrection::L => EcLevel::L,
+                QrErrorCorrection::M => EcLevel::M,
+                QrErrorCorrection::Q => EcLevel::Q,
+                QrErrorCorrection::H => EcLevel::H,
+            },
+            format: config.format,
+            dark: parse_color(&config.dark_color)?,
+            light: parse_color(&config.light_color)?,
+            logo,
+            caption: config.caption,
+            caption_font,
+        })
+    }
+
+    /// Модули кода, true для темных
+    fn modules(&self, qr_text: &str) -> Result<(Vec<bool>, u32), eyre::Error> {
+        let code = QrCode::with_error_correction_level(qr_text.as_bytes(), self.ec_level)?;
+        let width = code.width() as u32;
+        let modules = code.to_colors().into_iter().map(|color| color == Color::Dark).collect();
+        Ok((modules, width))
+    }
+
+    /// Картинка в формате из настроек
+    pub fn render(&self, qr_text: &str, caption: &str) -> Result<QrImage, eyre::Error> {
+        match self.format {
+            QrFormat::Png => self.render_png(qr_text, caption),
+            QrFormat::Svg => self.render_svg(qr_text, caption),
+        }
+    }
+
+    /// PNG картинка, нужна там, где SVG не отображается
+    pub fn render_png(&self, qr_text: &str, caption: &str) -> Result<QrImage, eyre::Error> {
+        let (modules, width) = self.modules(qr_text)?;
+        let code_size = (width + self.quiet_zone * 2) * self.module_size;
+
+        // Под подпись оставляем место снизу
+        let font = self.caption_font.as_ref().filter(|_| self.caption && !caption.is_empty());
+        let caption_height = if font.is_some() { (self.module_size * 3).max(16) } else { 0 };
+
+        let mut image = RgbImage::from_pixel(code_size, code_size + caption_height, Rgb(self.light));
+        for (index, _) in modules.iter().enumerate().filter(|(_, dark)| **dark) {
+            let x = (index as u32 % width + self.quiet_zone) * self.module_size;
+            let y = (index as u32 / width + self.quiet_zone) * self.module_size;
+            for dy in 0..self.module_size {
+                for dx in 0..self.module_size {
+                    image.put_pixel(x + dx, y + dy, Rgb(self.dark));
+                }
+            }
+        }
+
+        // Логотип в центре на подложке цвета фона
+        if let Some((logo, _)) = &self.logo {
+            let (logo_width, logo_height) = logo_size(logo, (width * self.module_size / LOGO_FRACTION) as f32);
+            let (logo_width, logo_height) = ((logo_width as u32).max(1), (logo_height as u32).max(1));
+            let logo = image::imageops::resize(logo, logo_width, logo_height, FilterType::Triangle);
+            let left = (code_size - logo_width) / 2;
+            let top = (code_size - logo_height) / 2;
+            let padding = self.module_size;
+            for y in top.saturating_sub(padding)..(top + logo_height + padding).min(code_size) {
+                for x in left.saturating_sub(padding)..(left + logo_width + padding).min(code_size) {
+                    image.put_pixel(x, y, Rgb(self.light));
+                }
+            }
+            for (x, y, pixel) in logo.enumerate_pixels() {
+                let [r, g, b, a] = pixel.0;
+                image.put_pixel(left + x, top + y, blend(self.light, [r, g, b], a as f32 / 255.0));
+            }
+        }
+
+        // Подпись уменьшаем, пока не влезет по ширине
+        if let Some(font) = font {
+            let max_width = code_size.saturating_sub(self.module_size * 2) as f32;
+            let mut scale = Scale::uniform(caption_height as f32 * 0.8);
+            let text_width = |scale: Scale| {
+                font.layout(caption, scale, point(0.0, 0.0))
+                    .last()
+                    .map(|glyph| glyph.position().x + glyph.unpositioned().h_metrics().advance_width)
+                    .unwrap_or(0.0)
+            };
+            let full_width = text_width(scale);
+            if full_width > max_width {
+                scale = Scale::uniform(scale.y * max_width / full_width);
+            }
+            let v_metrics = font.v_metrics(scale);
+            let left = (code_size as f32 - text_width(scale)).max(0.0) / 2.0;
+            let baseline = code_size as f32 + (caption_height as f32 + v_metrics.ascent + v_metrics.descent) / 2.0;
+            for glyph in font.layout(caption, scale, point(left, baseline)) {
+                if let Some(bounds) = glyph.pixel_bounding_box() {
+                    glyph.draw(|x, y, alpha| {
+                        let x = bounds.min.x + x as i32;
+                        let y = bounds.min.y + y as i32;
+                        if x >= 0 && y >= 0 && (x as u32) < image.width() && (y as u32) < image.height() {
+                            let background = image.get_pixel(x as u32, y as u32).0;
+                            image.put_pixel(x as u32, y as u32, blend(background, self.dark, alpha));
+                        }
+                    });
+                }
+            }
+        }
+
+        let mut data = Vec::new();
+        image::png::PngEncoder::new(&mut data).encode(&image, image.width(), image.height(), image::ColorType::Rgb8)?;
+
+        Ok(QrImage {
+            data,
+            mime: mime::IMAGE_PNG,
+            file_name: "qr.png",
+        })
+    }
+
+    /// SVG картинка, размеры в модулях
+    pub fn render_svg(&self, qr_text: &str, caption: &str) -> Result<QrImage, eyre::Error> {
+        let (modules, width) = self.modules(qr_text)?;
+        let code_size = width + self.quiet_zone * 2;
+        let caption_height = if self.caption && !caption.is_empty() { 3 } else { 0 };
+
+        let mut path = String::new();
+        for (index, _) in modules.iter().enumerate().filter(|(_, dark)| **dark) {
+            let x = index as u32 % width + self.quiet_zone;
+            let y = index as u32 / width + self.quiet_zone;
+            path.push_str(&format!("M{},{}h1v1h-1z", x, y));
+        }
+
+        let mut svg = format!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" version="1.1" width="{}" height="{}" viewBox="0 0 {} {}" shape-rendering="crispEdges"><rect width="100%" height="100%" fill="{}"/><path d="{}" fill="{}"/>"#,
+            code_size * self.module_size,
+            (code_size + caption_height) * self.module_size,
+            code_size,
+            code_size + caption_height,
+            svg_color(self.light),
+            path,
+            svg_color(self.dark)
+        );
+
+        if let Some((logo, data)) = &self.logo {
+            let (logo_width, logo_height) = logo_size(logo, width as f32 / LOGO_FRACTION as f32);
+            let left = (code_size as f32 - logo_width) / 2.0;
+            let top = (code_size as f32 - logo_height) / 2.0;
+            svg.push_str(&format!(
+                r#"<rect x="{}" y="{}" width="{}" height="{}" fill="{}"/><image x="{}" y="{}" width="{}" height="{}" href="data:image/png;base64,{}"/>"#,
+                left - 1.0,
+                top - 1.0,
+                logo_width + 2.0,
+                logo_height + 2.0,
+                svg_color(self.light),
+                left,
+                top,
+                logo_width,
+                logo_height,
+                base64::encode(data)
+            ));
+        }
+
+        if caption_height > 0 {
+            // Средняя ширина символа примерно 0.6 от размера шрифта
+            let font_size = (code_size.saturating_sub(2) as f32 / (0.6 * caption.chars().count() as f32)).min(2.0);
+            svg.push_str(&format!(
+                r#"<text x="{}" y="{}" font-family="sans-serif" font-size="{:.2}" text-anchor="middle" fill="{}">{}</text>"#,
+                code_size as f32 / 2.0,
+                code_size + 1,
+                font_size,
+                svg_color(self.dark),
+                escape_markup(caption)
+            ));
+        }
+        svg.push_str("</svg>");
+
+        Ok(QrImage {
+            data: svg.into_bytes(),
+            mime: "image/svg+xml".parse().expect("Valid mime"),
+            file_name: "qr.svg",
+        })
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::GenericImageView;
+
+    #[test]
+    fn test_render() {
+        let renderer = QrRenderer::new(None).unwrap();
+        let image = renderer.render("https://storage/app.apk", "app.apk").unwrap();
+        assert_eq!(image.mime, mime::IMAGE_PNG);
+        let png = image::load_from_memory(&image.data).unwrap();
+        // Версия 2: 25 модулей и по 4 модуля отступа, модуль 8 пикселей
+        assert_eq!(png.width(), (25 + 8) * 8);
+
+        let config = QrConfig {
+            format: QrFormat::Svg,
+            dark_color: "#112233".to_owned(),
+            caption: true,
+            ..Default::default()
+        };
+        let renderer = QrRenderer::new(Some(&config)).unwrap();
+        let image = renderer.render("https://storage/app.apk", "<app>.apk").unwrap();
+        let svg = String::from_utf8(image.data).unwrap();
+        assert!(svg.starts_with("<svg"));
+        assert!(svg.contains(r##"fill="#112233""##));
+        assert!(svg.contains("&lt;app&gt;.apk"));
+    }
+
+    #[test]
+    fn test_tall_logo() {
+        let dir = tempfile::tempdir().unwrap();
+        let logo_path = dir.path().join("logo.png");
+        RgbaImage::new(1, 100).save(&logo_path).unwrap();
+
+        let config = QrConfig {
+            logo: Some(logo_path),
+            ..Default::default()
+        };
+        let renderer = QrRenderer::new(Some(&config)).unwrap();
+        let image = renderer.render("https://storage/app.apk", "app.apk").unwrap();
+        let png = image::load_from_memory(&image.data).unwrap();
+        assert_eq!(png.height(), (25 + 8) * 8);
+    }
+}
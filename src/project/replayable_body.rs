@@ -0,0 +1,100 @@
+use eyre::WrapErr;
+use futures::StreamExt;
+use hyper::body::{Body as BodyStruct, Bytes};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+/// Тело запроса, которое можно отправить повторно.
+/// Небольшие данные держим в памяти, большие пишем во временный файл, который удаляется вместе с объектом.
+pub enum ReplayableBody {
+    Memory(Bytes),
+    File { file: NamedTempFile, len: u64 },
+}
+
+impl ReplayableBody {
+    /// Вычитываем тело целиком
+    pub async fn read(mut body: BodyStruct, memory_limit: u64, spool_dir: Option<&Path>) -> Result<ReplayableBody, eyre::Error> {
+        let mut buffer = Vec::new();
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.wrap_err("Body data receive")?;
+            if (buffer.len() + chunk.len()) as u64 > memory_limit {
+                return ReplayableBody::spool(buffer.into(), chunk, body, spool_dir.map(Path::to_path_buf)).await;
+            }
+            buffer.extend_from_slice(&chunk);
+        }
+        Ok(ReplayableBody::Memory(buffer.into()))
+    }
+
+    /// Данные не влезли в память, дописываем все во временный файл
+    async fn spool(head: Bytes, chunk: Bytes, mut body: BodyStruct, spool_dir: Option<PathBuf>) -> Result<ReplayableBody, eyre::Error> {
+        let temp_file = tokio::task::spawn_blocking(move || match spool_dir {
+            Some(dir) => NamedTempFile::new_in(dir),
+            None => NamedTempFile::new(),
+        })
+        .await
+        .wrap_err("Temp file task failed")?
+        .wrap_err("Temp file create failed")?;
+
+        let mut file = tokio::fs::File::from_std(temp_file.reopen().wrap_err("Temp file open failed")?);
+        let mut len = 0;
+        for data in [head, chunk] {
+            file.write_all(&data).await.wrap_err("Temp file write failed")?;
+            len += data.len() as u64;
+        }
+        while let Some(chunk) = body.next().await {
+            let chunk = chunk.wrap_err("Body data receive")?;
+            file.write_all(&chunk).await.wrap_err("Temp file write failed")?;
+            len += chunk.len() as u64;
+        }
+        file.flush().await.wrap_err("Temp file write failed")?;
+
+        Ok(ReplayableBody::File { file: temp_file, len })
+    }
+
+    /// Размер данных
+    pub fn len(&self) -> u64 {
+        match self {
+            ReplayableBody::Memory(data) => data.len() as u64,
+            ReplayableBody::File { len, .. } => *len,
+        }
+    }
+
+    /// Новое тело запроса для очередной попытки
+    pub fn to_body(&self) -> Result<BodyStruct, eyre::Error> {
+        match self {
+            ReplayableBody::Memory(data) => Ok(BodyStruct::from(data.clone())),
+            ReplayableBody::File { file, .. } => {
+                let file = tokio::fs::File::from_std(file.reopen().wrap_err("Temp file open failed")?);
+                Ok(BodyStruct::wrap_stream(ReaderStream::new(file)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::to_bytes;
+
+    #[tokio::test]
+    async fn test_replayable_body() {
+        let data = "0123456789".repeat(10);
+
+        let body = ReplayableBody::read(BodyStruct::from(data.clone()), 1000, None).await.unwrap();
+        assert!(matches!(body, ReplayableBody::Memory(_)));
+
+        let chunks: Vec<Result<String, std::io::Error>> = vec![Ok(data[..30].to_owned()), Ok(data[30..].to_owned())];
+        let stream_body = BodyStruct::wrap_stream(futures::stream::iter(chunks));
+        let body = ReplayableBody::read(stream_body, 50, None).await.unwrap();
+        assert!(matches!(body, ReplayableBody::File { .. }));
+        assert_eq!(body.len(), data.len() as u64);
+
+        // Каждый раз отдаются полные данные
+        for _ in 0..2 {
+            let replay = to_bytes(body.to_body().unwrap()).await.unwrap();
+            assert_eq!(replay, data.as_bytes());
+        }
+    }
+}
@@ -1,115 +1,386 @@
+use super::{
+    notifier::{notifier_error, LinkMessage, Notifier},
+    proj::UploadParams,
+    qr::QrRenderer,
+};
 use crate::{
     app_config::SlackConfig,
     error::{ErrorWithStatusAndDesc, WrapErrorWithStatusAndDesc},
 };
+use futures::{future::BoxFuture, FutureExt};
 use hyper::http::StatusCode;
-use image::Luma;
-use qrcode::QrCode;
-use slack_client_lib::{
-    // SlackUserMessageTarget,
-    SlackChannelMessageTarget,
-    SlackClient,
-    SlackThreadImageTarget,
-};
-use tracing::Instrument;
+use serde::Deserialize;
+use slack_client_lib::{SlackChannelMessageTarget, SlackClient, SlackThreadImageTarget, SlackUserMessageTarget};
+use std::sync::Arc;
+use tracing::{warn, Instrument};
 
 ///////////////////////////////////////////////////////////////////////////
 
-pub fn create_qr_data(qr_text: &str) -> Result<Vec<u8>, eyre::Error> {
-    // Encode some data into bits.
-    let code = QrCode::new(qr_text.as_bytes())?; // Конвертация ошибки произойдет автоматически
+/// Адрес Web API, библиотека клиента использует его же
+const SLACK_API_URL: &str = "https://slack.com/api";
 
-    // Рендерим картинку
-    let image_obj = code.render::<Luma<u8>>().build();
+/// В блоке контекста не больше 10 элементов
+const MAX_CONTEXT_ELEMENTS: usize = 10;
 
-    // Ширина и высота
-    let width = image_obj.width();
-    let height = image_obj.height();
+/// Ответ chat.postMessage
+#[derive(Debug, Deserialize)]
+struct PostMessageResponse {
+    ok: bool,
+    error: Option<String>,
+    channel: Option<String>,
+    ts: Option<String>,
+}
 
-    // Фактический вектор с данными
-    let mut png_image_data: Vec<u8> = Vec::new();
+/// Блоки сообщения: текст, кнопка скачивания и контекст с описанием файла
+fn build_blocks(message: &LinkMessage, text: &str) -> serde_json::Value {
+    let mut context = vec![
+        format!("*File:* {}", message.params.file_name),
+        format!("*Size:* {}", message.params.data_length),
+    ];
+    context.extend(message.params.metadata.iter().map(|(key, value)| format!("*{}:* {}", key, value)));
+    context.push(format!("*Request:* {}", message.params.request_id));
+    context.truncate(MAX_CONTEXT_ELEMENTS);
 
-    // Создаем курсор на мутабельный вектор, курсор
-    let png_image_data_cursor = std::io::Cursor::new(&mut png_image_data);
+    serde_json::json!([
+        {
+            "type": "section",
+            "text": {"type": "mrkdwn", "text": text},
+        },
+        {
+            "type": "actions",
+            "elements": [{
+                "type": "button",
+                "text": {"type": "plain_text", "text": "Download"},
+                "url": message.link,
+                "style": "primary",
+            }],
+        },
+        {
+            "type": "context",
+            "elements": context
+                .into_iter()
+                .map(|text| serde_json::json!({"type": "mrkdwn", "text": text}))
+                .collect::<Vec<_>>(),
+        },
+    ])
+}
 
-    // Создаем буффер с мутабельной ссылкой на вектор
-    // Можно сразу передавать &mut png_image_data вместо курсора, но с курсором нагляднее
-    let png_image_buffer = std::io::BufWriter::with_capacity(2048, png_image_data_cursor);
+/// Ответ users.lookupByEmail
+#[derive(Debug, Deserialize)]
+struct LookupUserResponse {
+    ok: bool,
+    error: Option<String>,
+    user: Option<LookupUser>,
+}
 
-    // Конвертим
-    image::png::PngEncoder::new(png_image_buffer).encode(&image_obj, width, height, image::ColorType::L8)?;
+#[derive(Debug, Deserialize)]
+struct LookupUser {
+    id: String,
+}
 
-    Ok(png_image_data)
+/// Куда отправляем сообщение
+#[derive(Debug, Clone, Copy)]
+enum SlackTarget<'a> {
+    Channel(&'a str),
+    /// Личное сообщение, id или email пользователя
+    User(&'a str),
 }
 
-///////////////////////////////////////////////////////////////////////////
+impl<'a> SlackTarget<'a> {
+    /// Пользователи в списке получателей записаны через @
+    fn parse(target: &'a str) -> SlackTarget<'a> {
+        match target.strip_prefix('@') {
+            Some(user) => SlackTarget::User(user),
+            None => SlackTarget::Channel(target),
+        }
+    }
+}
+
+/// Есть ли значение в списке разрешенных.
+/// Запись вида *@example.com разрешает все адреса домена.
+fn is_allowed(allowlist: &[String], value: &str) -> bool {
+    allowlist.iter().any(|allowed| match allowed.strip_prefix('*') {
+        Some(suffix) if suffix.starts_with('@') => value.to_lowercase().ends_with(&suffix.to_lowercase()),
+        _ => allowed == value,
+    })
+}
+
+/// Канал и тред отправленного сообщения
+struct SentMessage {
+    channel_id: String,
+    thread_id: String,
+}
 
 pub struct SlackLinkSender {
     client: SlackClient,
+    http_client: reqwest::Client,
+    token: String,
     targets: Vec<String>,
+    allowed_channels: Vec<String>,
+    allowed_users: Vec<String>,
     qr_code: bool,
+    qr: Arc<QrRenderer>,
+    block_kit: bool,
+    upload_files_below_bytes: Option<u64>,
     default_text_before: Option<String>,
 }
 
 impl SlackLinkSender {
-    pub fn new(http_client: reqwest::Client, config: SlackConfig) -> SlackLinkSender {
-        let client = SlackClient::new(http_client, config.token);
+    pub fn new(http_client: reqwest::Client, config: SlackConfig, qr: Arc<QrRenderer>) -> SlackLinkSender {
+        let client = SlackClient::new(http_client.clone(), config.token.clone());
 
         SlackLinkSender {
             client,
+            http_client,
+            token: config.token,
             targets: config.targets,
+            allowed_channels: config.allowed_channels,
+            allowed_users: config.allowed_users,
             qr_code: config.qr_code,
+            qr,
+            block_kit: config.block_kit,
+            upload_files_below_bytes: config.upload_files_below_bytes,
             default_text_before: config.default_text_before,
         }
     }
 
-    /// Выдаем в слак нашу ссылку
-    pub async fn post_link(&self, link: &str, text_prefix: Option<String>) -> Result<(), ErrorWithStatusAndDesc> {
+    /// Текст сообщения в формате mrkdwn
+    fn message_text(&self, message: &LinkMessage) -> String {
+        if let Some(text) = &message.text {
+            return text.clone();
+        }
+        match message.text_prefix().or(self.default_text_before.as_deref()) {
+            // В блоках ссылка уже есть на кнопке
+            Some(text) if self.block_kit => text.to_owned(),
+            Some(text) => format!("{}<{}|link>", text, message.link),
+            None if self.block_kit => format!("File uploaded: {}", message.params.file_name),
+            None => format!("Download file url: <{}|link>", message.link),
+        }
+    }
+
+    /// Сообщение из блоков отправляем напрямую через Web API, клиент умеет только текст
+    /// Ответы в тред тоже отправляем напрямую, клиент умеет треды только для картинок
+    async fn post_message(
+        &self,
+        target: &str,
+        text: &str,
+        blocks: Option<&serde_json::Value>,
+        thread_ts: Option<&str>,
+    ) -> Result<Option<SentMessage>, eyre::Error> {
+        let response: PostMessageResponse = self
+            .http_client
+            .post(format!("{}/chat.postMessage", SLACK_API_URL))
+            .bearer_auth(&self.token)
+            .json(&serde_json::json!({
+                "channel": target,
+                "text": text,
+                "blocks": blocks,
+                "thread_ts": thread_ts,
+            }))
+            .send()
+            .in_current_span()
+            .await?
+            .error_for_status()?
+            .json()
+            .in_current_span()
+            .await?;
+        if !response.ok {
+            return Err(eyre::eyre!("{}", response.error.unwrap_or_default()));
+        }
+        // Картинки и файлы пойдут в тот же тред
+        let thread_ts = thread_ts.map(str::to_owned).or(response.ts);
+        Ok(response
+            .channel
+            .zip(thread_ts)
+            .map(|(channel_id, thread_id)| SentMessage { channel_id, thread_id }))
+    }
+
+    /// Id пользователя по email
+    async fn lookup_user(&self, email: &str) -> Result<String, eyre::Error> {
+        let response: LookupUserResponse = self
+            .http_client
+            .get(format!("{}/users.lookupByEmail", SLACK_API_URL))
+            .bearer_auth(&self.token)
+            .query(&[("email", email)])
+            .send()
+            .in_current_span()
+            .await?
+            .error_for_status()?
+            .json()
+            .in_current_span()
+            .await?;
+        match response.user {
+            Some(user) if response.ok => Ok(user.id),
+            _ => Err(eyre::eyre!("User {} lookup failed: {}", email, response.error.unwrap_or_default())),
+        }
+    }
+
+    async fn send_text(&self, target: SlackTarget<'_>, text: &str) -> Result<Option<SentMessage>, eyre::Error> {
+        let result = match target {
+            SlackTarget::Channel(channel) => {
+                self.client
+                    .send_message(text, SlackChannelMessageTarget::new(channel))
+                    .in_current_span()
+                    .await
+            }
+            SlackTarget::User(user_id) => {
+                self.client
+                    .send_message(text, SlackUserMessageTarget::new(user_id))
+                    .in_current_span()
+                    .await
+            }
+        };
+        let message = result.map_err(|err| eyre::eyre!("{}", err))?;
+        Ok(message.map(|message| SentMessage {
+            channel_id: message.get_channel_id().to_owned(),
+            thread_id: message.get_thread_id().to_owned(),
+        }))
+    }
+
+    /// Выдаем в слак нашу ссылку для одного получателя
+    pub async fn post_link(&self, message: &LinkMessage<'_>, target: &str) -> Result<(), ErrorWithStatusAndDesc> {
         // Формируем текст сообщения
-        let text = if let Some(mut text) = text_prefix.or_else(|| self.default_text_before.clone()) {
-            text.push('<');
-            text.push_str(link);
-            text.push_str("|link>");
-            text
-        } else {
-            format!("Download file url: <{link}|link>")
+        let text = self.message_text(message);
+        let blocks = if self.block_kit { Some(build_blocks(message, &text)) } else { None };
+
+        // Ответ в существующий тред
+        let thread_ts = message.params.slack_thread_ts.as_deref();
+
+        let send_result = async {
+            // Личные сообщения по email отправляем после поиска пользователя
+            let user_id;
+            let target = match SlackTarget::parse(target) {
+                SlackTarget::User(user) if user.contains('@') => {
+                    user_id = self.lookup_user(user).await?;
+                    SlackTarget::User(&user_id)
+                }
+                target => target,
+            };
+            match (&blocks, thread_ts, target) {
+                (None, None, target) => self.send_text(target, &text).await,
+                (blocks, thread_ts, SlackTarget::Channel(channel) | SlackTarget::User(channel)) => {
+                    self.post_message(channel, &text, blocks.as_ref(), thread_ts).await
+                }
+            }
+        }
+        .in_current_span()
+        .await;
+        let sent = match send_result.map_err(|err| notifier_error("Slack", err))? {
+            Some(sent) => sent,
+            None => return Ok(()),
         };
 
-        // Футура ожидания сообщений от всех таргетов
-        let futures_iter = self.targets.iter().map(|target| {
-            // debug!("Send message to target: {} -> {}", text, target);
+        // Ссылка уже у получателя, повтор уведомления отправил бы ее второй раз
+        if let Err(err) = self.post_follow_ups(message, &sent).in_current_span().await {
+            warn!("Slack follow-up to {} failed, link is already sent: {}", target, err);
+        }
+
+        Ok(())
+    }
+
+    /// QR код и небольшой файл в тред отправленной ссылки
+    async fn post_follow_ups(&self, message: &LinkMessage<'_>, sent: &SentMessage) -> Result<(), ErrorWithStatusAndDesc> {
+        // Отправляем QR код в тред
+        if self.qr_code {
+            let qr_code_image = self
+                .qr
+                .render(message.link, &message.params.file_name)
+                .wrap_err_with_500_desc("QR code create failed".into())?;
             self.client
-                .send_message(&text, SlackChannelMessageTarget::new(target))
+                .send_image(
+                    qr_code_image.data,
+                    Some(qr_code_image.file_name.to_owned()),
+                    SlackThreadImageTarget::new(&sent.channel_id, &sent.thread_id),
+                )
                 .in_current_span()
+                .await
+                .map_err(|err| notifier_error("Slack qr send", err))?;
+        }
+
+        // Небольшие файлы выкладываем рядом со ссылкой
+        let attachment = message.attachment.filter(|data| {
+            self.upload_files_below_bytes
+                .map(|limit| (data.len() as u64) < limit)
+                .unwrap_or(false)
         });
+        if let Some(data) = attachment {
+            self.client
+                .send_image(
+                    data.to_vec(),
+                    Some(message.params.file_name.clone()),
+                    SlackThreadImageTarget::new(&sent.channel_id, &sent.thread_id),
+                )
+                .in_current_span()
+                .await
+                .map_err(|err| notifier_error("Slack file send", err))?;
+        }
 
-        // Делаем запрос выгрузки в каждый таргет сообщения
-        let send_results = futures::future::try_join_all(futures_iter).in_current_span().await.map_err(|err| {
-            ErrorWithStatusAndDesc::new_with_status_desc(StatusCode::INTERNAL_SERVER_ERROR, format!("Slack error: {}", err).into())
-        })?;
+        Ok(())
+    }
+}
 
-        // Отправляем QR код в треды
-        if self.qr_code {
-            let qr_code_image = create_qr_data(link).wrap_err_with_500_desc("QR code create failed".into())?;
+impl Notifier for SlackLinkSender {
+    fn kind(&self) -> &'static str {
+        "slack"
+    }
 
-            let qr_send_iter = send_results.iter().filter_map(|v| v.as_ref()).map(|message| {
-                self.client
-                    .send_image(
-                        qr_code_image.clone(),
-                        None,
-                        SlackThreadImageTarget::new(message.get_channel_id(), message.get_thread_id()),
-                    )
-                    .in_current_span()
-            });
+    fn attachment_limit(&self) -> Option<u64> {
+        self.upload_files_below_bytes
+    }
 
-            futures::future::try_join_all(qr_send_iter).in_current_span().await.map_err(|err| {
-                ErrorWithStatusAndDesc::new_with_status_desc(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    format!("Slack qr send error: {}", err).into(),
-                )
-            })?;
+    fn check_params(&self, params: &UploadParams) -> Result<(), ErrorWithStatusAndDesc> {
+        // Тред принадлежит одному каналу
+        if params.slack_thread_ts.is_some() && (params.slack_channels.len() != 1 || !params.slack_users.is_empty()) {
+            return Err(ErrorWithStatusAndDesc::new_with_status_desc(
+                StatusCode::BAD_REQUEST,
+                "slack_thread_ts requires exactly one slack channel".into(),
+            ));
+        }
+        let channel = params.slack_channels.iter().find(|v| !is_allowed(&self.allowed_channels, v));
+        if let Some(channel) = channel {
+            return Err(ErrorWithStatusAndDesc::new_with_status_desc(
+                StatusCode::BAD_REQUEST,
+                format!("Slack channel {} is not allowed", channel).into(),
+            ));
+        }
+        let user = params.slack_users.iter().find(|v| !is_allowed(&self.allowed_users, v));
+        if let Some(user) = user {
+            return Err(ErrorWithStatusAndDesc::new_with_status_desc(
+                StatusCode::BAD_REQUEST,
+                format!("Slack user {} is not allowed", user).into(),
+            ));
         }
-
         Ok(())
     }
+
+    /// Получатели из запроса заменяют каналы из настроек
+    fn targets(&self, params: &UploadParams) -> Vec<String> {
+        if params.slack_channels.is_empty() && params.slack_users.is_empty() {
+            self.targets.clone()
+        } else {
+            let users = params.slack_users.iter().map(|v| format!("@{}", v));
+            params.slack_channels.iter().cloned().chain(users).collect()
+        }
+    }
+
+    fn post_link<'a>(&'a self, message: &'a LinkMessage<'a>, target: &'a str) -> BoxFuture<'a, Result<(), ErrorWithStatusAndDesc>> {
+        SlackLinkSender::post_link(self, message, target).boxed()
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_allowed() {
+        let allowlist = vec!["U123".to_owned(), "*@example.com".to_owned()];
+        assert!(is_allowed(&allowlist, "U123"));
+        assert!(is_allowed(&allowlist, "Dev@Example.com"));
+        assert!(!is_allowed(&allowlist, "dev@example.com.evil"));
+        assert!(!is_allowed(&allowlist, "U1234"));
+        assert!(!is_allowed(&[], "U123"));
+    }
 }
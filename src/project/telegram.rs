@@ -0,0 +1,108 @@
+use super::{
+    notifier::{notifier_http_error, LinkMessage, Notifier},
+    proj::UploadParams,
+    qr::QrRenderer,
+};
+use crate::{
+    app_config::TelegramConfig,
+    error::{ErrorWithStatusAndDesc, WrapErrorWithStatusAndDesc},
+};
+use futures::{future::BoxFuture, FutureExt};
+use reqwest::multipart::{Form, Part};
+use std::sync::Arc;
+use tracing::Instrument;
+
+///////////////////////////////////////////////////////////////////////////
+
+/// Отправка ссылки через Telegram Bot API
+pub struct TelegramLinkSender {
+    http_client: reqwest::Client,
+    /// Адрес методов бота вида {base_url}/bot{token}
+    api_url: String,
+    chat_ids: Vec<String>,
+    qr_code: bool,
+    qr: Arc<QrRenderer>,
+    default_text_before: Option<String>,
+}
+
+impl TelegramLinkSender {
+    pub fn new(http_client: reqwest::Client, config: TelegramConfig, qr: Arc<QrRenderer>) -> TelegramLinkSender {
+        TelegramLinkSender {
+            http_client,
+            api_url: format!("{}/bot{}", config.base_url.trim_end_matches('/'), config.bot_token),
+            chat_ids: config.chat_ids,
+            qr_code: config.qr_code,
+            qr,
+            default_text_before: config.default_text_before,
+        }
+    }
+
+    /// Текстовое сообщение
+    async fn send_message(&self, chat_id: &str, text: &str) -> Result<(), reqwest::Error> {
+        self.http_client
+            .post(format!("{}/sendMessage", self.api_url))
+            .json(&serde_json::json!({
+                "chat_id": chat_id,
+                "text": text,
+            }))
+            .send()
+            .in_current_span()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Картинка QR кода с текстом в подписи
+    async fn send_photo(&self, chat_id: &str, text: &str, photo: Vec<u8>) -> Result<(), reqwest::Error> {
+        let photo = Part::bytes(photo).file_name("qr.png").mime_str(mime::IMAGE_PNG.essence_str())?;
+        let form = Form::new()
+            .text("chat_id", chat_id.to_owned())
+            .text("caption", text.to_owned())
+            .part("photo", photo);
+        self.http_client
+            .post(format!("{}/sendPhoto", self.api_url))
+            .multipart(form)
+            .send()
+            .in_current_span()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    pub async fn post_link(&self, message: &LinkMessage<'_>, chat_id: &str) -> Result<(), ErrorWithStatusAndDesc> {
+        let link = message.link;
+        let text = message.plain_text(self.default_text_before.as_deref());
+
+        let qr_code_image = if self.qr_code {
+            let image = self
+                .qr
+                .render_png(link, &message.params.file_name)
+                .wrap_err_with_500_desc("QR code create failed".into())?;
+            Some(image.data)
+        } else {
+            None
+        };
+
+        let result = match qr_code_image {
+            Some(image) => self.send_photo(chat_id, &text, image).in_current_span().await,
+            None => self.send_message(chat_id, &text).in_current_span().await,
+        };
+        result.map_err(|err| notifier_http_error("Telegram", err))?;
+
+        Ok(())
+    }
+}
+
+impl Notifier for TelegramLinkSender {
+    fn kind(&self) -> &'static str {
+        "telegram"
+    }
+
+    fn targets(&self, _params: &UploadParams) -> Vec<String> {
+        self.chat_ids.clone()
+    }
+
+    fn post_link<'a>(&'a self, message: &'a LinkMessage<'a>, target: &'a str) -> BoxFuture<'a, Result<(), ErrorWithStatusAndDesc>> {
+        TelegramLinkSender::post_link(self, message, target).boxed()
+    }
+}
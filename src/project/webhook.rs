@@ -0,0 +1,171 @@
+use super::{google::StoredObject, notifier::notifier_http_error, proj::UploadParams};
+use crate::{app_config::WebhooksConfig, error::ErrorWithStatusAndDesc};
+use eyre::WrapErr;
+use hmac::{Hmac, Mac, NewMac};
+use serde::Serialize;
+use sha2::Sha256;
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, error, Instrument};
+
+///////////////////////////////////////////////////////////////////////////
+
+/// Заголовок с подписью тела запроса в виде sha256=<hex>
+pub const SIGNATURE_HEADER: &str = "X-Upload-Signature-256";
+
+/// Имя отправителя вебхуков в очереди уведомлений
+pub const WEBHOOK_NOTIFIER: &str = "webhook";
+
+/// Тело уведомления о завершенной выгрузке
+#[derive(Debug, Serialize)]
+pub struct WebhookPayload<'a> {
+    pub event: &'static str,
+    pub project: &'a str,
+    pub request_id: &'a str,
+    pub link: &'a str,
+    pub file_name: &'a str,
+    /// Размер входных данных из Content-Length
+    pub data_length: u64,
+    /// Объем данных уже после сжатия
+    pub uploaded_bytes: u64,
+    pub object: &'a StoredObject,
+    /// Метаданные из запроса
+    pub metadata: &'a BTreeMap<String, String>,
+}
+
+/// Подпись HMAC-SHA256 тела запроса
+fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(body);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+///////////////////////////////////////////////////////////////////////////
+
+pub struct WebhookSender {
+    http_client: reqwest::Client,
+    urls: Vec<String>,
+    allow_callback_url: bool,
+    secret: String,
+    timeout: Duration,
+    dead_letter_file: Option<PathBuf>,
+}
+
+impl WebhookSender {
+    pub fn new(http_client: reqwest::Client, config: WebhooksConfig) -> WebhookSender {
+        WebhookSender {
+            http_client,
+            urls: config.urls,
+            allow_callback_url: config.allow_callback_url,
+            secret: config.secret,
+            timeout: Duration::from_secs(config.timeout_secs),
+            dead_letter_file: config.dead_letter_file,
+        }
+    }
+
+    /// Можно ли указывать адрес уведомления в запросе
+    pub fn allows_callback_url(&self) -> bool {
+        self.allow_callback_url
+    }
+
+    /// Адреса уведомлений о выгрузке: из настроек и из запроса
+    pub fn urls(&self, params: &UploadParams) -> Vec<String> {
+        self.urls.iter().chain(params.callback_url.iter()).cloned().collect()
+    }
+
+    /// Тело уведомления о завершенной выгрузке
+    pub fn payload(&self, project: &str, params: &UploadParams, link: &str, uploaded_bytes: u64, object: &StoredObject) -> Option<String> {
+        let payload = WebhookPayload {
+            event: "upload_finished",
+            project,
+            request_id: &params.request_id,
+            link,
+            file_name: &params.file_name,
+            data_length: params.data_length,
+            uploaded_bytes,
+            object,
+            metadata: &params.metadata,
+        };
+        match serde_json::to_string(&payload) {
+            Ok(payload) => Some(payload),
+            Err(err) => {
+                error!("Webhook payload serialize failed: {}", err);
+                None
+            }
+        }
+    }
+
+    /// Одна попытка доставки, повторы выполняет очередь уведомлений.
+    /// Подписываем при отправке, чтобы после смены ключа повторы шли с новой подписью.
+    pub async fn deliver(&self, url: &str, request_id: &str, payload: &str) -> Result<(), ErrorWithStatusAndDesc> {
+        let response = self
+            .http_client
+            .post(url)
+            .timeout(self.timeout)
+            .header(reqwest::header::CONTENT_TYPE, mime::APPLICATION_JSON.essence_str())
+            .header(SIGNATURE_HEADER, sign(&self.secret, payload.as_bytes()))
+            .header("X-Request-Id", request_id)
+            .body(payload.to_owned())
+            .send()
+            .in_current_span()
+            .await
+            .map_err(|err| notifier_http_error("Webhook", err))?;
+
+        // Повторы по статусу решает очередь: 408, 429 и 5xx
+        let status = response.status();
+        if !status.is_success() {
+            return Err(ErrorWithStatusAndDesc::new_with_status_desc(
+                status,
+                format!("Webhook response status {}", status).into(),
+            ));
+        }
+
+        debug!("Webhook {} delivered", url);
+        Ok(())
+    }
+
+    /// Недоставленные уведомления пишем в журнал, чтобы их можно было отправить вручную
+    pub async fn dead_letter(&self, url: &str, request_id: &str, payload: &str, err: &ErrorWithStatusAndDesc) {
+        error!(target: "webhook_dead_letter", url, request_id, payload = %payload, "Webhook delivery failed: {}", err);
+
+        if let Some(path) = &self.dead_letter_file {
+            let line = serde_json::json!({
+                "time": chrono::Utc::now().to_rfc3339(),
+                "url": url,
+                "request_id": request_id,
+                "error": err.to_string(),
+                "payload": serde_json::from_str::<serde_json::Value>(payload).unwrap_or_default(),
+            });
+            let write_result = async {
+                let mut file = tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .wrap_err("Dead letter file open failed")?;
+                file.write_all(format!("{}\n", line).as_bytes())
+                    .await
+                    .wrap_err("Dead letter file write failed")?;
+                Ok::<_, eyre::Error>(())
+            }
+            .await;
+            if let Err(err) = write_result {
+                error!("Webhook dead letter save failed: {:#}", err);
+            }
+        }
+    }
+}
+
+///////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign() {
+        // Пример из RFC 4231, test case 2
+        let signature = sign("Jefe", b"what do ya want for nothing?");
+        assert_eq!(signature, "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+    }
+}
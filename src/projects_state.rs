@@ -0,0 +1,181 @@
+use crate::{
+    app_config::{Config, LimitsConfig, ProjectConfig, DEFAULT_TOKEN_LABEL},
+    landing_pages::LandingPages,
+    project::Project,
+    rate_limit::Limiter,
+    token_provider_registry::TokenProviderRegistry,
+    types::{ApiToken, HttpClient},
+};
+use eyre::WrapErr;
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
+use tracing::debug;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Описание токена из конфига проекта
+struct TokenDesc {
+    token: String,
+    label: String,
+    limits: Option<LimitsConfig>,
+}
+
+/// Включенные токены проекта с итоговыми ограничениями
+fn enabled_tokens(config: &ProjectConfig) -> Vec<TokenDesc> {
+    let mut tokens = Vec::with_capacity(config.extra_api_tokens.len() + 1);
+    if !config.api_token_disabled {
+        tokens.push(TokenDesc {
+            token: config.api_token.clone(),
+            label: DEFAULT_TOKEN_LABEL.to_owned(),
+            limits: config.token_limits.clone(),
+        });
+    }
+    tokens.extend(
+        config
+            .extra_api_tokens
+            .iter()
+            .filter(|extra| !extra.disabled)
+            .map(|extra| TokenDesc {
+                token: extra.token.clone(),
+                label: extra.label.clone(),
+                limits: extra.limits.clone().or_else(|| config.token_limits.clone()),
+            }),
+    );
+    tokens
+}
+
+/// Совпадают ли настройки проекта без учета токенов, чтобы не пересоздавать проект при изменении токенов
+fn same_project_settings(a: &ProjectConfig, b: &ProjectConfig) -> bool {
+    let strip = |config: &ProjectConfig| ProjectConfig {
+        api_token: String::new(),
+        api_token_disabled: false,
+        extra_api_tokens: Vec::new(),
+        token_limits: None,
+        ..config.clone()
+    };
+    strip(a) == strip(b)
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Собранные из конфига проекты и токены, подменяются в приложении целиком
+pub struct ProjectsState {
+    /// Конфиг, из которого собрано состояние
+    pub config: Config,
+    /// Включенные проекты по именам
+    pub projects: HashMap<String, Arc<Project>>,
+    /// Проекты по токенам доступа
+    pub tokens: HashMap<String, Arc<ApiToken>>,
+}
+
+/// Создание объектов проектов из конфига
+pub struct ProjectsFactory {
+    http_client_low_level: HttpClient,
+    http_client_high_level: reqwest::Client,
+    state_dir: Option<PathBuf>,
+    token_providers: TokenProviderRegistry,
+    landing_pages: Option<Arc<LandingPages>>,
+}
+
+impl ProjectsFactory {
+    pub fn new(
+        http_client_low_level: HttpClient,
+        http_client_high_level: reqwest::Client,
+        state_dir: Option<PathBuf>,
+        landing_pages: Option<Arc<LandingPages>>,
+    ) -> ProjectsFactory {
+        ProjectsFactory {
+            http_client_low_level,
+            http_client_high_level,
+            state_dir,
+            token_providers: TokenProviderRegistry::default(),
+            landing_pages,
+        }
+    }
+
+    /// Общие провайдеры токенов Google
+    pub fn token_providers(&self) -> &TokenProviderRegistry {
+        &self.token_providers
+    }
+
+    fn build_project(&self, config: &ProjectConfig, previous: Option<&Project>) -> Result<Arc<Project>, eyre::Error> {
+        let project = Project::new(
+            config.clone(),
+            self.state_dir.as_deref(),
+            previous,
+            &self.token_providers,
+            self.http_client_low_level.clone(),
+            self.http_client_high_level.clone(),
+            self.landing_pages.clone(),
+        )
+        .wrap_err_with(|| format!("Project {} create failed", config.name))?;
+        Ok(Arc::new(project))
+    }
+
+    /// Собираем состояние с нуля
+    pub fn build(&self, config: Config) -> Result<ProjectsState, eyre::Error> {
+        self.build_with_previous(config, None, false)
+    }
+
+    /// Собираем состояние, переиспользуя проекты и токены с неизменившимися настройками.
+    /// Так не сбрасываются счетчики ограничений и не пересоздаются провайдеры токенов Google.
+    pub fn rebuild(&self, config: Config, previous: &ProjectsState) -> Result<ProjectsState, eyre::Error> {
+        self.build_with_previous(config, Some(previous), false)
+    }
+
+    /// Пересоздаем все проекты, чтобы заново прочитать ключи сервисных аккаунтов и токены Slack.
+    /// Счетчики ограничений и квот переносятся из прошлого состояния.
+    pub fn reload(&self, config: Config, previous: &ProjectsState) -> Result<ProjectsState, eyre::Error> {
+        self.build_with_previous(config, Some(previous), true)
+    }
+
+    fn build_with_previous(&self, config: Config, previous: Option<&ProjectsState>, recreate: bool) -> Result<ProjectsState, eyre::Error> {
+        let mut projects = HashMap::with_capacity(config.projects.len());
+        let mut tokens = HashMap::new();
+
+        for project_config in config.projects.iter().filter(|v| !v.disabled) {
+            debug!("Project {} config: {:?}", project_config.name, project_config);
+
+            // Прошлый вариант проекта с тем же именем
+            let previous_project = previous.and_then(|previous| {
+                let previous_config = previous.config.projects.iter().find(|v| v.name == project_config.name)?;
+                let previous_project = previous.projects.get(&project_config.name)?;
+                Some((previous_config, previous_project))
+            });
+            let project = match previous_project {
+                // Настройки не изменились - используем проект как есть
+                Some((previous_config, previous_project)) if !recreate && same_project_settings(previous_config, project_config) => {
+                    previous_project.clone()
+                }
+                _ => self.build_project(project_config, previous_project.map(|(_, v)| v.as_ref()))?,
+            };
+
+            // Токены проекта вместе с их ограничениями
+            for desc in enabled_tokens(project_config) {
+                // Переиспользуем ограничитель токена с теми же параметрами вместе с его состоянием
+                let previous_token = previous
+                    .and_then(|previous| previous.tokens.get(&desc.token))
+                    .filter(|prev| prev.project.name() == project.name() && prev.label == desc.label && prev.limits == desc.limits);
+                let api_token = match previous_token {
+                    Some(prev) if Arc::ptr_eq(&prev.project, &project) => prev.clone(),
+                    Some(prev) => Arc::new(ApiToken {
+                        label: desc.label,
+                        project: project.clone(),
+                        limiter: prev.limiter.clone(),
+                        limits: desc.limits,
+                    }),
+                    None => Arc::new(ApiToken {
+                        limiter: Arc::new(Limiter::new(desc.limits.as_ref())),
+                        label: desc.label,
+                        limits: desc.limits,
+                        project: project.clone(),
+                    }),
+                };
+                tokens.insert(desc.token, api_token);
+            }
+
+            projects.insert(project_config.name.clone(), project);
+        }
+
+        Ok(ProjectsState { config, projects, tokens })
+    }
+}
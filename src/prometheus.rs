@@ -6,8 +6,8 @@ use hyper::{
 };
 use lazy_static::lazy_static;
 use prometheus::{
-    exponential_buckets, gather, register_histogram_vec, register_int_counter, register_int_counter_vec, Encoder, HistogramTimer,
-    HistogramVec, IntCounter, IntCounterVec, TextEncoder,
+    exponential_buckets, gather, register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, HistogramTimer, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
 };
 
 lazy_static! {
@@ -51,12 +51,67 @@ lazy_static! {
             }
         )
         .unwrap();
+
+    /// Отклоненные из-за превышения лимитов запросы
+    static ref RATE_LIMIT_REJECTIONS: IntCounterVec = register_int_counter_vec!(
+        "rate_limit_rejections",
+        "Uploads rejected by rate or concurrency limits",
+        &["project", "scope", "reason"]
+    )
+    .unwrap();
+
+    /// Перезагрузки конфига с результатом
+    static ref CONFIG_RELOADS: IntCounterVec = register_int_counter_vec!(
+        "config_reloads",
+        "Config reloads count by trigger and result",
+        &["trigger", "result"]
+    )
+    .unwrap();
+
+    /// Возраст текущего токена Google по аккаунтам
+    static ref GOOGLE_TOKEN_AGE: IntGaugeVec = register_int_gauge_vec!(
+        "google_token_age_seconds",
+        "Age of the current Google access token",
+        &["account", "scope"]
+    )
+    .unwrap();
+
+    /// Ошибки получения токенов Google
+    static ref GOOGLE_TOKEN_FAILURES: IntCounterVec = register_int_counter_vec!(
+        "google_token_refresh_failures",
+        "Google access token refresh failures",
+        &["account", "scope"]
+    )
+    .unwrap();
+
+    /// Повторы выгрузок в Google Storage после временных ошибок
+    static ref UPLOAD_RETRIES: IntCounter = register_int_counter!("google_upload_retries", "Google storage upload retries after transient errors").unwrap();
+
+    /// Время последней успешной перезагрузки конфига
+    static ref CONFIG_LAST_RELOAD_SUCCESS: IntGauge = register_int_gauge!(
+        "config_last_reload_success_timestamp_seconds",
+        "Unix timestamp of the last successful config reload"
+    )
+    .unwrap();
+}
+
+/// Путь для метки метрик, идентификаторы из пути заменяем, иначе каждый запрос создает новую серию
+fn api_path_label(api_path: &str) -> &str {
+    if api_path.starts_with("/jobs/") {
+        "/jobs/{id}"
+    } else if api_path.starts_with("/pages/") && api_path.ends_with("/manifest.plist") {
+        "/pages/{id}/manifest.plist"
+    } else if api_path.starts_with("/pages/") {
+        "/pages/{id}"
+    } else {
+        api_path
+    }
 }
 
 /// Подсчитываем количество успешных и фейловых кодов при работе отгрузчика на основе статуса
 pub fn count_response_status(api_path: &str, method: &Method, status: &StatusCode) {
     HTTP_RETURN_CODES
-        .with_label_values(&[api_path, method.as_str(), status.as_str()])
+        .with_label_values(&[api_path_label(api_path), method.as_str(), status.as_str()])
         .inc();
 }
 
@@ -68,7 +123,7 @@ pub fn count_request() {
 /// Подсчет распределения времени выполнения запроса
 pub fn count_request_time(api_path: &str, method: &Method) -> HistogramTimer {
     HTTP_RESPONSE_TIME_SECONDS
-        .with_label_values(&[api_path, method.as_str()])
+        .with_label_values(&[api_path_label(api_path), method.as_str()])
         .start_timer()
 }
 
@@ -82,6 +137,40 @@ pub fn count_uploaded_size(data_size: u64, success: bool) {
     TOTAL_BYTES_UPLOADED_SIZE.with_label_values(&[status]).observe(data_size as f64);
 }
 
+/// Подсчет повторов выгрузки
+pub fn count_upload_retry() {
+    UPLOAD_RETRIES.inc();
+}
+
+/// Подсчет отклоненных лимитами запросов
+pub fn count_rate_limit_rejection(project: &str, scope: &str, reason: &str) {
+    RATE_LIMIT_REJECTIONS.with_label_values(&[project, scope, reason]).inc();
+}
+
+/// Подсчет перезагрузок конфига
+pub fn count_config_reload(trigger: &str, success: bool) {
+    let result = if success { "ok" } else { "fail" };
+    CONFIG_RELOADS.with_label_values(&[trigger, result]).inc();
+    if success {
+        CONFIG_LAST_RELOAD_SUCCESS.set(chrono::Utc::now().timestamp());
+    }
+}
+
+/// Возраст токена Google
+pub fn set_google_token_age(account: &str, scope: &str, age_seconds: u64) {
+    GOOGLE_TOKEN_AGE.with_label_values(&[account, scope]).set(age_seconds as i64);
+}
+
+/// Подсчет ошибок получения токена Google
+pub fn count_google_token_failure(account: &str, scope: &str) {
+    GOOGLE_TOKEN_FAILURES.with_label_values(&[account, scope]).inc();
+}
+
+/// Провайдер токенов уничтожен, возраст его токена больше не актуален
+pub fn remove_google_token_metrics(account: &str, scope: &str) {
+    GOOGLE_TOKEN_AGE.remove_label_values(&[account, scope]).ok();
+}
+
 /// Обработчик отдачи статистики для Prometheus
 pub async fn prometheus_metrics() -> Result<Response<BodyStruct>, ErrorWithStatusAndDesc> {
     // Получаем данные из Prometheus
@@ -103,3 +192,16 @@ pub async fn prometheus_metrics() -> Result<Response<BodyStruct>, ErrorWithStatu
 
     Ok(resp)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_api_path_label() {
+        assert_eq!(api_path_label("/upload_file"), "/upload_file");
+        assert_eq!(api_path_label("/jobs/0123abcd"), "/jobs/{id}");
+        assert_eq!(api_path_label("/pages/0123abcd"), "/pages/{id}");
+        assert_eq!(api_path_label("/pages/0123abcd/manifest.plist"), "/pages/{id}/manifest.plist");
+    }
+}
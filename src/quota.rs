@@ -0,0 +1,337 @@
+use crate::{
+    app_config::QuotaConfig,
+    error::{ErrorKind, ErrorWithStatusAndDesc},
+};
+use eyre::WrapErr;
+use hyper::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+use tracing::warn;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+const SECONDS_IN_HOUR: i64 = 60 * 60;
+const DAY_HOURS: i64 = 24;
+const MONTH_HOURS: i64 = 30 * DAY_HOURS;
+
+/// Сохраняемые на диск счетчики.
+/// Байты храним по часам, чтобы можно было считать скользящие окна за сутки и месяц.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct QuotaState {
+    hourly_bytes: BTreeMap<i64, u64>,
+    total_bytes: u64,
+    total_objects: u64,
+}
+
+impl QuotaState {
+    /// Сумма байт за последние window_hours часов, включая текущий
+    fn bytes_in_window(&self, current_hour: i64, window_hours: i64) -> u64 {
+        self.hourly_bytes.range((current_hour - window_hours + 1)..).map(|(_, v)| *v).sum()
+    }
+
+    /// Через сколько секунд в окне освободится нужное количество байт
+    fn seconds_until_free(&self, now: i64, window_hours: i64, bytes_needed: u64) -> u64 {
+        let current_hour = now / SECONDS_IN_HOUR;
+        let mut released = 0;
+        for (hour, bytes) in self.hourly_bytes.range((current_hour - window_hours + 1)..) {
+            released += bytes;
+            if released >= bytes_needed {
+                let free_at = (hour + window_hours) * SECONDS_IN_HOUR;
+                return (free_at - now).max(1) as u64;
+            }
+        }
+        (window_hours * SECONDS_IN_HOUR) as u64
+    }
+
+    /// Старые данные за пределами месячного окна больше не нужны
+    fn prune(&mut self, current_hour: i64) {
+        let first_hour = current_hour - MONTH_HOURS + 1;
+        self.hourly_bytes = self.hourly_bytes.split_off(&first_hour);
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Отказ в выгрузке из-за квоты
+#[derive(Debug)]
+pub struct QuotaRejection {
+    pub status: StatusCode,
+    pub desc: String,
+    pub retry_after: Option<u64>,
+}
+
+impl From<QuotaRejection> for ErrorWithStatusAndDesc {
+    fn from(rejection: QuotaRejection) -> ErrorWithStatusAndDesc {
+        let err = ErrorWithStatusAndDesc::new_with_status_desc(rejection.status, rejection.desc.into()).with_kind(ErrorKind::QuotaExceeded);
+        match rejection.retry_after {
+            Some(retry_after) => err.with_retry_after(retry_after),
+            None => err,
+        }
+    }
+}
+
+/// Текущее использование квоты для отдачи клиенту
+#[derive(Debug, Serialize)]
+pub struct QuotaUsage {
+    pub bytes_last_day: u64,
+    pub bytes_last_month: u64,
+    pub total_bytes: u64,
+    pub total_objects: u64,
+    pub reserved_bytes: u64,
+    pub limits: QuotaConfig,
+}
+
+#[derive(Debug)]
+struct QuotaInner {
+    config: QuotaConfig,
+    state: QuotaState,
+    // Байты выгрузок, которые сейчас в процессе
+    reserved_bytes: u64,
+    reserved_objects: u64,
+}
+
+/// Учет квот отдельного проекта
+#[derive(Debug)]
+pub struct QuotaTracker {
+    state_path: PathBuf,
+    inner: Mutex<QuotaInner>,
+    // Сериализуем запись файла, чтобы последним всегда писался самый свежий снимок
+    save_lock: tokio::sync::Mutex<()>,
+}
+
+impl QuotaTracker {
+    /// Загружаем сохраненное состояние если оно есть
+    pub fn load(config: QuotaConfig, state_path: PathBuf) -> Result<QuotaTracker, eyre::Error> {
+        let state = if state_path.exists() {
+            let data = std::fs::read(&state_path).wrap_err_with(|| format!("Quota state read failed: {}", state_path.display()))?;
+            serde_json::from_slice(&data).wrap_err_with(|| format!("Quota state parse failed: {}", state_path.display()))?
+        } else {
+            QuotaState::default()
+        };
+
+        Ok(QuotaTracker {
+            state_path,
+            inner: Mutex::new(QuotaInner {
+                config,
+                state,
+                reserved_bytes: 0,
+                reserved_objects: 0,
+            }),
+            save_lock: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// Текущее использование
+    pub fn usage(&self) -> QuotaUsage {
+        let current_hour = chrono::Utc::now().timestamp() / SECONDS_IN_HOUR;
+        let inner = self.inner.lock().expect("Quota lock poisoned");
+        QuotaUsage {
+            bytes_last_day: inner.state.bytes_in_window(current_hour, DAY_HOURS),
+            bytes_last_month: inner.state.bytes_in_window(current_hour, MONTH_HOURS),
+            total_bytes: inner.state.total_bytes,
+            total_objects: inner.state.total_objects,
+            reserved_bytes: inner.reserved_bytes,
+            limits: inner.config.clone(),
+        }
+    }
+
+    /// Меняем ограничения без сброса счетчиков, используется при перезагрузке конфига
+    pub fn set_config(&self, config: QuotaConfig) {
+        self.inner.lock().expect("Quota lock poisoned").config = config;
+    }
+
+    /// Проверяем квоты заранее по размеру входных данных и резервируем место под выгрузку
+    pub fn reserve(self: &Arc<Self>, bytes: u64) -> Result<QuotaReservation, QuotaRejection> {
+        let now = chrono::Utc::now().timestamp();
+        let current_hour = now / SECONDS_IN_HOUR;
+
+        let mut inner = self.inner.lock().expect("Quota lock poisoned");
+
+        // Количество объектов со временем не уменьшается
+        if let Some(max_objects) = inner.config.max_objects {
+            if inner.state.total_objects + inner.reserved_objects >= max_objects {
+                return Err(QuotaRejection {
+                    status: StatusCode::TOO_MANY_REQUESTS,
+                    desc: format!("Objects quota exceeded: {} objects", max_objects),
+                    retry_after: None,
+                });
+            }
+        }
+
+        // Скользящие окна по байтам
+        let windows = [
+            ("daily", inner.config.bytes_per_day, DAY_HOURS),
+            ("monthly", inner.config.bytes_per_month, MONTH_HOURS),
+        ];
+        for (name, limit, window_hours) in windows {
+            let limit = match limit {
+                Some(limit) => limit,
+                None => continue,
+            };
+
+            // Такой объем не влезет никогда
+            if bytes > limit {
+                return Err(QuotaRejection {
+                    status: StatusCode::PAYLOAD_TOO_LARGE,
+                    desc: format!("Upload size {} exceeds {} quota {}", bytes, name, limit),
+                    retry_after: None,
+                });
+            }
+
+            let used = inner.state.bytes_in_window(current_hour, window_hours) + inner.reserved_bytes;
+            if used + bytes > limit {
+                let bytes_needed = used + bytes - limit;
+                return Err(QuotaRejection {
+                    status: StatusCode::TOO_MANY_REQUESTS,
+                    desc: format!("The {} quota {} is exhausted, used {}", name, limit, used),
+                    retry_after: Some(inner.state.seconds_until_free(now, window_hours, bytes_needed)),
+                });
+            }
+        }
+
+        inner.reserved_bytes += bytes;
+        inner.reserved_objects += 1;
+
+        Ok(QuotaReservation {
+            tracker: self.clone(),
+            bytes,
+            committed: false,
+        })
+    }
+
+    /// Проверяем, что выгрузка такого размера сейчас поместится в квоту, ничего не резервируя
+    pub fn check(self: &Arc<Self>, bytes: u64) -> Result<(), QuotaRejection> {
+        self.reserve(bytes).map(drop)
+    }
+
+    /// Учитываем фактически выгруженные данные и сохраняем состояние на диск
+    async fn record(&self, bytes: u64) {
+        let _save_guard = self.save_lock.lock().await;
+
+        let data = {
+            let current_hour = chrono::Utc::now().timestamp() / SECONDS_IN_HOUR;
+            let mut inner = self.inner.lock().expect("Quota lock poisoned");
+            *inner.state.hourly_bytes.entry(current_hour).or_insert(0) += bytes;
+            inner.state.total_bytes += bytes;
+            inner.state.total_objects += 1;
+            inner.state.prune(current_hour);
+            serde_json::to_vec(&inner.state)
+        };
+
+        // Ошибка сохранения не должна ломать уже завершенную выгрузку
+        if let Err(err) = self.save(data).await {
+            warn!("Quota state save failed: {:?}", err);
+        }
+    }
+
+    async fn save(&self, data: Result<Vec<u8>, serde_json::Error>) -> Result<(), eyre::Error> {
+        let data = data.wrap_err("Quota state serialize failed")?;
+
+        if let Some(dir) = self.state_path.parent() {
+            tokio::fs::create_dir_all(dir).await.wrap_err("Quota state dir create failed")?;
+        }
+
+        // Пишем через временный файл, чтобы не получить обрезанный файл при падении
+        let tmp_path = self.state_path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, data).await.wrap_err("Quota state write failed")?;
+        tokio::fs::rename(&tmp_path, &self.state_path)
+            .await
+            .wrap_err("Quota state rename failed")?;
+
+        Ok(())
+    }
+
+    fn release(&self, bytes: u64) {
+        let mut inner = self.inner.lock().expect("Quota lock poisoned");
+        inner.reserved_bytes = inner.reserved_bytes.saturating_sub(bytes);
+        inner.reserved_objects = inner.reserved_objects.saturating_sub(1);
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Зарезервированное под выгрузку место, освобождается при уничтожении
+#[derive(Debug)]
+pub struct QuotaReservation {
+    tracker: Arc<QuotaTracker>,
+    bytes: u64,
+    committed: bool,
+}
+
+impl QuotaReservation {
+    /// Заменяем резерв фактически выгруженным объемом
+    pub async fn commit(mut self, uploaded_bytes: u64) {
+        self.tracker.release(self.bytes);
+        self.committed = true;
+        self.tracker.record(uploaded_bytes).await;
+    }
+}
+
+impl Drop for QuotaReservation {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.tracker.release(self.bytes);
+        }
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rolling_windows() {
+        let hour = 1000;
+        let mut state = QuotaState::default();
+        state.hourly_bytes.insert(hour - MONTH_HOURS, 1); // Уже вне месячного окна
+        state.hourly_bytes.insert(hour - DAY_HOURS, 10); // Вне суточного окна
+        state.hourly_bytes.insert(hour - 1, 100);
+        state.hourly_bytes.insert(hour, 1000);
+
+        assert_eq!(state.bytes_in_window(hour, DAY_HOURS), 1100);
+        assert_eq!(state.bytes_in_window(hour, MONTH_HOURS), 1110);
+
+        state.prune(hour);
+        assert_eq!(state.hourly_bytes.len(), 3);
+
+        // Чтобы освободить 50 байт в сутках, ждем пока уйдет час hour - 1
+        let now = hour * SECONDS_IN_HOUR + 10;
+        assert_eq!(
+            state.seconds_until_free(now, DAY_HOURS, 50),
+            ((hour - 1 + DAY_HOURS) * SECONDS_IN_HOUR - now) as u64
+        );
+    }
+
+    #[test]
+    fn test_reservation() {
+        let config = QuotaConfig {
+            bytes_per_day: Some(100),
+            bytes_per_month: None,
+            max_objects: Some(2),
+        };
+        let tracker = Arc::new(QuotaTracker::load(config, PathBuf::from("/nonexistent/quota.json")).unwrap());
+
+        assert_eq!(tracker.reserve(101).unwrap_err().status, StatusCode::PAYLOAD_TOO_LARGE);
+
+        let first = tracker.reserve(60).unwrap();
+        assert_eq!(tracker.reserve(60).unwrap_err().status, StatusCode::TOO_MANY_REQUESTS);
+
+        // После отмены резерв освобождается
+        drop(first);
+        let _second = tracker.reserve(60).unwrap();
+        let third = tracker.reserve(40).unwrap();
+        assert_eq!(tracker.reserve(1).unwrap_err().status, StatusCode::TOO_MANY_REQUESTS);
+
+        // Проверка ничего не резервирует
+        drop(third);
+        tracker.check(40).unwrap();
+        tracker.check(40).unwrap();
+        assert_eq!(tracker.check(41).unwrap_err().status, StatusCode::TOO_MANY_REQUESTS);
+    }
+}
@@ -0,0 +1,201 @@
+use crate::app_config::LimitsConfig;
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Классический token bucket: пополняется с постоянной скоростью до размера burst
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    state: Mutex<BucketState>,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst: Option<u32>) -> TokenBucket {
+        let capacity = burst.map(f64::from).unwrap_or_else(|| rate.ceil().max(1.0));
+        TokenBucket {
+            rate,
+            capacity,
+            state: Mutex::new(BucketState {
+                tokens: capacity,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Пробуем взять токен, при неудаче возвращаем время до появления следующего
+    fn try_take(&self, now: Instant) -> Result<(), Duration> {
+        let mut state = self.state.lock().expect("Token bucket lock poisoned");
+
+        // Пополняем на прошедшее время
+        let elapsed = now.saturating_duration_since(state.last_refill).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.rate).min(self.capacity);
+        state.last_refill = now;
+
+        if state.tokens >= 1.0 {
+            state.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - state.tokens) / self.rate))
+        }
+    }
+
+    /// Возвращаем взятый токен обратно
+    fn put_back(&self) {
+        let mut state = self.state.lock().expect("Token bucket lock poisoned");
+        state.tokens = (state.tokens + 1.0).min(self.capacity);
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Причина отказа в выполнении запроса
+#[derive(Debug)]
+pub enum LimitRejection {
+    /// Превышена частота запросов
+    Rate { retry_after: Duration },
+    /// Превышено количество одновременных выгрузок
+    Concurrency,
+}
+
+impl LimitRejection {
+    /// Значение для заголовка Retry-After в секундах
+    pub fn retry_after_secs(&self) -> u64 {
+        match self {
+            LimitRejection::Rate { retry_after } => retry_after.as_secs_f64().ceil().max(1.0) as u64,
+            LimitRejection::Concurrency => 1,
+        }
+    }
+
+    /// Имя причины для метрик
+    pub fn reason(&self) -> &'static str {
+        match self {
+            LimitRejection::Rate { .. } => "rate",
+            LimitRejection::Concurrency => "concurrency",
+        }
+    }
+}
+
+/// Разрешение на выполнение выгрузки, слот параллельности освобождается при уничтожении
+#[derive(Debug)]
+pub struct LimitPermit {
+    _concurrency: Option<OwnedSemaphorePermit>,
+    /// Откуда взят токен частоты
+    bucket: Option<Arc<TokenBucket>>,
+}
+
+impl LimitPermit {
+    /// Отказываемся от разрешения, не начав выгрузку, например, если ее отклонил следующий ограничитель.
+    /// Токен частоты возвращается, так что отклоненный запрос не расходует лимит.
+    pub fn refund(self) {
+        if let Some(bucket) = &self.bucket {
+            bucket.put_back();
+        }
+    }
+}
+
+/// Ограничитель частоты и параллельности выгрузок
+#[derive(Debug, Default)]
+pub struct Limiter {
+    bucket: Option<Arc<TokenBucket>>,
+    concurrency: Option<Arc<Semaphore>>,
+}
+
+impl Limiter {
+    pub fn new(config: Option<&LimitsConfig>) -> Limiter {
+        let config = match config {
+            Some(config) => config,
+            None => return Limiter::default(),
+        };
+
+        Limiter {
+            bucket: config
+                .requests_per_second
+                .map(|rate| Arc::new(TokenBucket::new(rate, config.burst))),
+            concurrency: config.max_concurrent_uploads.map(|max| Arc::new(Semaphore::new(max))),
+        }
+    }
+
+    pub fn try_acquire(&self) -> Result<LimitPermit, LimitRejection> {
+        // Сначала слот параллельности, чтобы не тратить токен частоты зря
+        let concurrency = match &self.concurrency {
+            Some(semaphore) => Some(semaphore.clone().try_acquire_owned().map_err(|_| LimitRejection::Concurrency)?),
+            None => None,
+        };
+
+        if let Some(bucket) = &self.bucket {
+            bucket
+                .try_take(Instant::now())
+                .map_err(|retry_after| LimitRejection::Rate { retry_after })?;
+        }
+
+        Ok(LimitPermit {
+            _concurrency: concurrency,
+            bucket: self.bucket.clone(),
+        })
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let bucket = TokenBucket::new(2.0, Some(3));
+        let now = Instant::now();
+
+        // Пачка запросов до размера burst
+        for _ in 0..3 {
+            assert!(bucket.try_take(now).is_ok());
+        }
+        let wait = bucket.try_take(now).unwrap_err();
+        assert!(wait <= Duration::from_millis(500));
+
+        // Через полсекунды появляется ровно один токен
+        let later = now + Duration::from_millis(500);
+        assert!(bucket.try_take(later).is_ok());
+        assert!(bucket.try_take(later).is_err());
+    }
+
+    #[test]
+    fn test_concurrency_limit() {
+        let limiter = Limiter::new(Some(&LimitsConfig {
+            requests_per_second: None,
+            burst: None,
+            max_concurrent_uploads: Some(1),
+        }));
+
+        let permit = limiter.try_acquire().unwrap();
+        assert!(matches!(limiter.try_acquire(), Err(LimitRejection::Concurrency)));
+        drop(permit);
+        assert!(limiter.try_acquire().is_ok());
+    }
+
+    #[test]
+    fn test_permit_refund() {
+        let limiter = Limiter::new(Some(&LimitsConfig {
+            requests_per_second: Some(0.001),
+            burst: Some(1),
+            max_concurrent_uploads: None,
+        }));
+
+        // Возвращенный токен можно взять снова, использованный - нет
+        limiter.try_acquire().unwrap().refund();
+        drop(limiter.try_acquire().unwrap());
+        assert!(matches!(limiter.try_acquire(), Err(LimitRejection::Rate { .. })));
+    }
+}
@@ -0,0 +1,110 @@
+use crate::app_config::RetryConfig;
+use rand::Rng;
+use std::time::Duration;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Политика повторов с экспоненциальной задержкой, случайным разбросом и общим временем на все попытки
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: f64,
+    deadline: Option<Duration>,
+}
+
+impl RetryPolicy {
+    /// Без настроек делается только одна попытка
+    pub fn new(config: Option<&RetryConfig>) -> RetryPolicy {
+        match config {
+            Some(config) => RetryPolicy {
+                max_attempts: config.max_attempts.max(1),
+                base_delay: Duration::from_millis(config.base_delay_ms),
+                max_delay: Duration::from_millis(config.max_delay_ms),
+                jitter: config.jitter,
+                deadline: config.deadline_secs.map(Duration::from_secs),
+            },
+            None => RetryPolicy {
+                max_attempts: 1,
+                base_delay: Duration::ZERO,
+                max_delay: Duration::ZERO,
+                jitter: 0.0,
+                deadline: None,
+            },
+        }
+    }
+
+    /// Есть ли вообще повторы
+    pub fn is_enabled(&self) -> bool {
+        self.max_attempts > 1
+    }
+
+    /// Сколько времени осталось до общего дедлайна
+    pub fn time_left(&self, elapsed: Duration) -> Option<Duration> {
+        self.deadline.map(|deadline| deadline.saturating_sub(elapsed))
+    }
+
+    /// Задержка перед следующей попыткой после неудачной попытки номер attempt (начиная с 1).
+    /// None, если попытки или время закончились.
+    /// Подсказка сервера из Retry-After увеличивает задержку, но не больше max_delay.
+    pub fn next_delay(&self, attempt: u32, elapsed: Duration, retry_after: Option<Duration>) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+
+        let exp = self.base_delay.saturating_mul(1 << (attempt - 1).min(16)).min(self.max_delay);
+        let jittered = exp.mul_f64(1.0 - self.jitter * rand::thread_rng().gen::<f64>());
+        let delay = match retry_after {
+            Some(retry_after) => jittered.max(retry_after.min(self.max_delay)),
+            None => jittered,
+        };
+
+        // Следующая попытка должна начаться до дедлайна
+        match self.time_left(elapsed) {
+            Some(left) if delay >= left => None,
+            _ => Some(delay),
+        }
+    }
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_config() -> RetryConfig {
+        RetryConfig {
+            max_attempts: 4,
+            base_delay_ms: 100,
+            max_delay_ms: 300,
+            jitter: 0.5,
+            deadline_secs: Some(10),
+            memory_buffer_bytes: 0,
+            spool_dir: None,
+        }
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let policy = RetryPolicy::new(None);
+        assert!(!policy.is_enabled());
+        assert_eq!(policy.next_delay(1, Duration::ZERO, None), None);
+
+        let policy = RetryPolicy::new(Some(&test_config()));
+        assert!(policy.is_enabled());
+        let first = policy.next_delay(1, Duration::ZERO, None).unwrap();
+        assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+        let third = policy.next_delay(3, Duration::ZERO, None).unwrap();
+        assert!(third >= Duration::from_millis(150) && third <= Duration::from_millis(300));
+        assert_eq!(policy.next_delay(4, Duration::ZERO, None), None);
+
+        // Retry-After ограничен максимальной задержкой
+        let delay = policy.next_delay(1, Duration::ZERO, Some(Duration::from_secs(60))).unwrap();
+        assert_eq!(delay, Duration::from_millis(300));
+
+        // Дедлайн
+        assert_eq!(policy.next_delay(1, Duration::from_secs(10), None), None);
+    }
+}
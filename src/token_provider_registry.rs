@@ -0,0 +1,92 @@
+use crate::{
+    app_config::{GoogleCredentialsConfig, GoogleStorageConfig},
+    auth_token_provider::{AuthTokenProvider, TokenProviderStatus},
+    oauth2::Credentials,
+    types::HttpClient,
+};
+use eyre::WrapErr;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, Weak},
+};
+use tracing::debug;
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Загружаем источник токенов по настройкам, audience нужен для self-signed JWT
+pub fn load_credentials(google_config: &GoogleStorageConfig, audience: &str) -> Result<Credentials, eyre::Error> {
+    let credentials = match google_config.resolved_credentials()? {
+        GoogleCredentialsConfig::File { path } => {
+            Credentials::from_file(&path).wrap_err_with(|| format!("Credentials file {} load failed", path.display()))?
+        }
+        GoogleCredentialsConfig::MetadataServer { endpoint, service_account } => {
+            Credentials::metadata_server(endpoint.as_deref(), service_account.as_deref())
+        }
+        GoogleCredentialsConfig::ApplicationDefault => Credentials::application_default()?,
+    };
+
+    if google_config.self_signed_jwt {
+        return credentials.into_self_signed(audience);
+    }
+
+    Ok(match &google_config.impersonate_service_account {
+        Some(account) => credentials.impersonate(account),
+        None => credentials,
+    })
+}
+
+/// Аккаунт и scope провайдера
+type ProviderKey = (String, &'static str);
+
+/// Общие провайдеры токенов для проектов с одинаковыми сервисными аккаунтами.
+/// Храним слабые ссылки, провайдер живет пока им пользуется хотя бы один проект.
+/// На один аккаунт бывает несколько провайдеров с разными ключами, например, во время ротации.
+#[derive(Debug, Default)]
+pub struct TokenProviderRegistry {
+    providers: Mutex<HashMap<ProviderKey, Vec<Weak<AuthTokenProvider>>>>,
+}
+
+impl TokenProviderRegistry {
+    /// Провайдер для аккаунта из настроек и нужного scope,
+    /// audience используется вместо scope в режиме self-signed JWT
+    pub fn get_or_create(
+        &self,
+        http_client: &HttpClient,
+        google_config: &GoogleStorageConfig,
+        scopes: &'static str,
+        audience: &str,
+    ) -> Result<Arc<AuthTokenProvider>, eyre::Error> {
+        // Прочитаем креденшиалы для гугла
+        let credentials = load_credentials(google_config, audience)?;
+        debug!("Google credentials: {}", credentials.identity());
+
+        let mut providers = self.providers.lock().expect("Token providers lock poisoned");
+        providers.retain(|_, list| {
+            list.retain(|provider| provider.strong_count() > 0);
+            !list.is_empty()
+        });
+
+        let list = providers.entry((credentials.identity(), scopes)).or_default();
+
+        // После ротации ключа нужен новый провайдер, старый доработает с уже выданными проектами
+        let existing = list
+            .iter()
+            .filter_map(Weak::upgrade)
+            .find(|provider| provider.credentials() == &credentials);
+        if let Some(provider) = existing {
+            return Ok(provider);
+        }
+
+        let provider = Arc::new(AuthTokenProvider::new(http_client.clone(), credentials, scopes));
+        list.push(Arc::downgrade(&provider));
+        Ok(provider)
+    }
+
+    /// Состояние всех используемых провайдеров
+    pub fn statuses(&self) -> Vec<TokenProviderStatus> {
+        let providers = self.providers.lock().expect("Token providers lock poisoned");
+        let mut statuses: Vec<TokenProviderStatus> = providers.values().flatten().filter_map(Weak::upgrade).map(|v| v.status()).collect();
+        statuses.sort_by(|a, b| (&a.account, a.scope).cmp(&(&b.account, b.scope)));
+        statuses
+    }
+}
@@ -1,16 +1,45 @@
-use crate::project::Project;
+use crate::{
+    app_config::LimitsConfig, async_uploads::AsyncUploads, landing_pages::LandingPages, notification_outbox::NotificationOutbox,
+    project::Project, projects_state::ProjectsFactory, projects_state::ProjectsState, rate_limit::Limiter, upload_queue::UploadQueue,
+};
+use arc_swap::ArcSwap;
 use hyper::{
     body::Body as BodyStruct,
     client::connect::{dns::GaiResolver, HttpConnector},
     Client,
 };
 use hyper_rustls::HttpsConnector;
-use std::collections::HashMap;
+use std::{path::PathBuf, sync::Arc};
 
 //////////////////////////////////////////////////////////////////////////////////////////////////////////////////
 
 pub type HttpClient = Client<HttpsConnector<HttpConnector<GaiResolver>>, BodyStruct>;
 
+/// Токен доступа к проекту со своими ограничениями
+pub struct ApiToken {
+    pub label: String,
+    pub project: Arc<Project>,
+    pub limiter: Arc<Limiter>,
+    /// Настройки, из которых создан ограничитель
+    pub limits: Option<LimitsConfig>,
+}
+
 pub struct App {
-    pub projects: HashMap<String, Project>,
+    /// Текущие проекты и токены, подменяются атомарно.
+    /// Выгрузки в процессе продолжают работать со старыми объектами.
+    pub state: ArcSwap<ProjectsState>,
+    /// Создание проектов при изменении конфига
+    pub factory: ProjectsFactory,
+    /// Путь к файлу конфига для сохранения изменений
+    pub config_path: PathBuf,
+    /// Изменения состояния выполняем строго по-очереди
+    pub state_update_lock: tokio::sync::Mutex<()>,
+    /// Очередь выгрузок на диске, если настроена
+    pub upload_queue: Option<Arc<UploadQueue>>,
+    /// Выгрузки, выполняемые после ответа клиенту
+    pub async_uploads: Arc<AsyncUploads>,
+    /// Уведомления с повторами в фоне
+    pub notification_outbox: Arc<NotificationOutbox>,
+    /// Страницы выгрузок, если настроены
+    pub landing_pages: Option<Arc<LandingPages>>,
 }
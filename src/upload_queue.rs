@@ -0,0 +1,382 @@
+use crate::{
+    app_config::SpoolConfig,
+    async_uploads::JobStatus,
+    error::{ErrorKind, ErrorWithStatusAndDesc},
+    notification_outbox::{all_sent, NotificationOutbox, NotificationReport},
+    persistent_queue::{PersistentQueue, QueueEntry},
+    project::{Project, UploadData, UploadParams},
+    types::App,
+};
+use eyre::WrapErr;
+use futures::StreamExt;
+use hyper::{
+    body::{Body as BodyStruct, Bytes},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+use tracing::{error, info, warn, Instrument};
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Состояние задачи выгрузки
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Uploading,
+    Done,
+    Failed,
+}
+
+/// Задача выгрузки, сохраняется на диск рядом с данными
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UploadJob {
+    pub id: String,
+    pub project: String,
+    pub params: UploadParams,
+    pub state: JobState,
+    /// Unix timestamp создания
+    pub created_at: i64,
+    /// Unix timestamp последнего изменения
+    pub updated_at: i64,
+    pub attempts: u32,
+    /// Unix timestamp следующей попытки
+    pub next_attempt_at: i64,
+    /// Объем данных в очереди
+    pub spooled_bytes: u64,
+    /// Объем выгруженных в Storage данных
+    pub uploaded_bytes: Option<u64>,
+    pub link: Option<String>,
+    /// Страница выгрузки
+    #[serde(default)]
+    pub page_link: Option<String>,
+    pub slack_sent: bool,
+    /// Состояние уведомлений по получателям
+    #[serde(default)]
+    pub notification_status: NotificationReport,
+    pub error: Option<String>,
+}
+
+impl QueueEntry for UploadJob {
+    fn id(&self) -> &str {
+        &self.id
+    }
+
+    fn is_pending(&self) -> bool {
+        self.state == JobState::Queued
+    }
+
+    fn is_finished(&self) -> bool {
+        matches!(self.state, JobState::Done | JobState::Failed)
+    }
+
+    fn created_at(&self) -> i64 {
+        self.created_at
+    }
+
+    fn updated_at(&self) -> i64 {
+        self.updated_at
+    }
+
+    fn set_updated_at(&mut self, updated_at: i64) {
+        self.updated_at = updated_at;
+    }
+
+    fn next_attempt_at(&self) -> i64 {
+        self.next_attempt_at
+    }
+
+    /// Прерванную выгрузку начинаем заново
+    fn restore(&mut self) {
+        if self.state == JobState::Uploading {
+            self.state = JobState::Queued;
+        }
+    }
+}
+
+/// Ошибки, после которых повтор имеет смысл: недоступность Google, токенов и временные ограничения.
+/// Исчерпанная квота освобождается не скоро, такие задачи не повторяем.
+pub fn is_transient_error(err: &ErrorWithStatusAndDesc) -> bool {
+    if err.kind == ErrorKind::QuotaExceeded {
+        return false;
+    }
+    err.status == StatusCode::UNAUTHORIZED
+        || err.status == StatusCode::REQUEST_TIMEOUT
+        || err.status == StatusCode::TOO_MANY_REQUESTS
+        || err.status.is_server_error()
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+/// Очередь выгрузок на диске.
+/// Данные и состояние каждой задачи лежат в отдельных файлах, поэтому очередь переживает перезапуски.
+pub struct UploadQueue {
+    config: SpoolConfig,
+    jobs: PersistentQueue<UploadJob>,
+    /// Счетчик отправленных байт, задачи выполняются по одной
+    progress: Arc<AtomicU64>,
+}
+
+impl UploadQueue {
+    /// Загружаем задачи, оставшиеся с прошлого запуска
+    pub fn open(config: SpoolConfig) -> Result<UploadQueue, eyre::Error> {
+        let jobs = PersistentQueue::<UploadJob>::open("Upload queue", (&config).into())?;
+
+        // Данные без описания задачи никому не нужны
+        for entry in std::fs::read_dir(&config.dir).wrap_err("Spool dir read failed")? {
+            let path = entry.wrap_err("Spool dir read failed")?.path();
+            let orphan = path.extension().and_then(|v| v.to_str()) == Some("data")
+                && path
+                    .file_stem()
+                    .and_then(|v| v.to_str())
+                    .map(|id| jobs.get(id).is_none())
+                    .unwrap_or(true);
+            if orphan {
+                std::fs::remove_file(&path).ok();
+            }
+        }
+
+        Ok(UploadQueue {
+            config,
+            jobs,
+            progress: Default::default(),
+        })
+    }
+
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.config.dir.join(format!("{}.data", id))
+    }
+
+    /// Сохраняем данные на диск и ставим задачу в очередь
+    pub async fn enqueue(&self, project: &str, params: UploadParams, mut body: BodyStruct) -> Result<UploadJob, eyre::Error> {
+        let id = uuid::Uuid::new_v4().to_simple().to_string();
+
+        // Данные пишем во временный файл, чтобы при падении не осталось обрезанных данных
+        let data_path = self.data_path(&id);
+        let tmp_path = data_path.with_extension("data.tmp");
+        let write_result = async {
+            let mut file = tokio::fs::File::create(&tmp_path).await.wrap_err("Spool file create failed")?;
+            let mut spooled_bytes = 0;
+            while let Some(chunk) = body.next().await {
+                let chunk = chunk.wrap_err("Body data receive")?;
+                file.write_all(&chunk).await.wrap_err("Spool file write failed")?;
+                spooled_bytes += chunk.len() as u64;
+            }
+            file.sync_all().await.wrap_err("Spool file sync failed")?;
+            tokio::fs::rename(&tmp_path, &data_path)
+                .await
+                .wrap_err("Spool file rename failed")?;
+            Ok::<_, eyre::Error>(spooled_bytes)
+        }
+        .await;
+        let spooled_bytes = match write_result {
+            Ok(spooled_bytes) => spooled_bytes,
+            Err(err) => {
+                tokio::fs::remove_file(&tmp_path).await.ok();
+                return Err(err);
+            }
+        };
+
+        let now = chrono::Utc::now().timestamp();
+        let job = UploadJob {
+            id,
+            project: project.to_owned(),
+            params,
+            state: JobState::Queued,
+            created_at: now,
+            updated_at: now,
+            attempts: 0,
+            next_attempt_at: now,
+            spooled_bytes,
+            uploaded_bytes: None,
+            link: None,
+            page_link: None,
+            slack_sent: false,
+            notification_status: Default::default(),
+            error: None,
+        };
+        if let Err(err) = self.jobs.add(job.clone()).await {
+            tokio::fs::remove_file(&data_path).await.ok();
+            return Err(err);
+        }
+
+        Ok(job)
+    }
+
+    /// Текущее состояние задачи
+    pub fn get(&self, id: &str) -> Option<JobStatus> {
+        self.jobs.with_entries(|jobs| {
+            jobs.get(id).map(|job| {
+                let mut status = JobStatus::from(job);
+                if job.state == JobState::Uploading {
+                    status.bytes_transferred = self.progress.load(Ordering::Relaxed);
+                }
+                status
+            })
+        })
+    }
+
+    /// Фоновая обработка очереди
+    pub async fn run(self: Arc<Self>, app: Arc<App>) {
+        self.jobs
+            .run(|job| {
+                let span = tracing::info_span!(
+                    "upload_job",
+                    job_id = %job.id,
+                    request_id = %job.params.request_id,
+                    project = %job.project
+                );
+                self.process(&app, job).instrument(span)
+            })
+            .await;
+    }
+
+    async fn process(&self, app: &App, mut job: UploadJob) {
+        job.state = JobState::Uploading;
+        job.attempts += 1;
+        self.jobs.update(&mut job).await;
+
+        // Проект могли удалить или выключить, пока задача ждала
+        let project = app.state.load().projects.get(&job.project).cloned();
+        let result = match project {
+            Some(project) => self.process_attempt(&project, &app.notification_outbox, &mut job).await,
+            None => Err(ErrorWithStatusAndDesc::new_with_status_desc(
+                StatusCode::NOT_FOUND,
+                format!("Project {} is not available", job.project).into(),
+            )),
+        };
+
+        match result {
+            Ok(()) => {
+                info!("Upload job finished");
+                job.state = JobState::Done;
+                job.error = None;
+            }
+            Err(err) => {
+                let attempts_left = self.config.max_attempts.map(|max| job.attempts < max).unwrap_or(true);
+                job.error = Some(err.to_string());
+                if is_transient_error(&err) && attempts_left {
+                    let delay = self.jobs.retry_delay_secs(job.attempts);
+                    warn!("Upload job attempt {} failed, retry in {} sec: {}", job.attempts, delay, err);
+                    job.state = JobState::Queued;
+                    job.next_attempt_at = chrono::Utc::now().timestamp() + delay;
+                } else {
+                    error!("Upload job failed: {}", err);
+                    job.state = JobState::Failed;
+                }
+            }
+        }
+
+        // Данные больше не нужны
+        if job.is_finished() {
+            tokio::fs::remove_file(self.data_path(&job.id)).await.ok();
+        }
+        self.jobs.update(&mut job).await;
+    }
+
+    async fn process_attempt(
+        &self,
+        project: &Project,
+        outbox: &NotificationOutbox,
+        job: &mut UploadJob,
+    ) -> Result<(), ErrorWithStatusAndDesc> {
+        // После успешной выгрузки повторяем только уведомление
+        let link = match job.link.clone() {
+            Some(link) => link,
+            None => {
+                project.check_params(&job.params)?;
+
+                let body = open_data_body(&self.data_path(&job.id)).await.map_err(|err| {
+                    ErrorWithStatusAndDesc::from_error_with_status_desc(err, StatusCode::GONE, "Spooled data is not available".into())
+                })?;
+                let upload_result = project
+                    .store(&job.params, UploadData::Stream(body), Some(self.progress.clone()), outbox)
+                    .in_current_span()
+                    .await?;
+
+                job.uploaded_bytes = Some(upload_result.uploaded_bytes);
+                job.link = Some(upload_result.download_link.clone());
+                job.page_link = upload_result.page_link.clone();
+                self.jobs.update(job).await;
+
+                upload_result.download_link
+            }
+        };
+        let link = job.page_link.clone().unwrap_or(link);
+
+        // Неудачные уведомления повторяет очередь уведомлений
+        if job.notification_status.is_empty() {
+            // Небольшой файл отправляем вместе со ссылкой, данные еще лежат в очереди
+            let wants_attachment = project.attachment_limit().map(|limit| job.spooled_bytes < limit).unwrap_or(false);
+            let attachment = if wants_attachment {
+                tokio::fs::read(self.data_path(&job.id)).await.ok().map(Bytes::from)
+            } else {
+                None
+            };
+            job.notification_status = outbox
+                .send(project, &job.params, &link, attachment.as_ref())
+                .in_current_span()
+                .await;
+            job.slack_sent = all_sent(&job.notification_status);
+        }
+
+        Ok(())
+    }
+}
+
+async fn open_data_body(path: &Path) -> Result<BodyStruct, eyre::Error> {
+    let file = tokio::fs::File::open(path).await.wrap_err("Spool file open failed")?;
+    Ok(BodyStruct::wrap_stream(ReaderStream::new(file)))
+}
+
+/////////////////////////////////////////////////////////////////////////////////////////////////////////
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_queue_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = SpoolConfig {
+            dir: dir.path().to_owned(),
+            retry_delay_secs: 1,
+            max_retry_delay_secs: 10,
+            max_attempts: None,
+            keep_finished_hours: 1,
+        };
+
+        let queue = UploadQueue::open(config.clone()).unwrap();
+        let mut job = queue
+            .enqueue("project", UploadParams::for_test("test.txt"), BodyStruct::from("TEST"))
+            .await
+            .unwrap();
+        assert_eq!(job.spooled_bytes, 4);
+        job.state = JobState::Uploading;
+        queue.jobs.update(&mut job).await;
+        std::fs::write(dir.path().join("orphan.data"), "DATA").unwrap();
+        drop(queue);
+
+        // Прерванная выгрузка снова в очереди, данные без задачи удалены
+        let queue = UploadQueue::open(config).unwrap();
+        assert_eq!(queue.jobs.get(&job.id).unwrap().state, JobState::Queued);
+        assert_eq!(std::fs::read(queue.data_path(&job.id)).unwrap(), b"TEST");
+        assert!(!dir.path().join("orphan.data").exists());
+    }
+
+    #[test]
+    fn test_quota_error_is_not_transient() {
+        let err = ErrorWithStatusAndDesc::new_with_status_desc(StatusCode::TOO_MANY_REQUESTS, "Too many uploads".into());
+        assert!(is_transient_error(&err));
+        assert!(!is_transient_error(&err.with_kind(ErrorKind::QuotaExceeded)));
+    }
+}